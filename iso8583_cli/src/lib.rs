@@ -0,0 +1,273 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers of the `iso8583` binary: reading and writing message dumps and converting
+//! messages to a field listing or JSON and back.
+//!
+//! Fields are keyed by their index in the spec. Values of binary (`b`) fields are hex,
+//! all others are text. The bitmap is computed and never part of a document.
+
+use std::fs;
+
+use base64::Engine;
+use iso8583::iso_diff::{printable, Change, FieldDiff};
+use iso8583::iso_field::{FieldCharType, FieldSizeType, IsoField};
+use iso8583::iso_framing::{FrameFormat, LengthHeader};
+use iso8583::iso_msg::{IsoMsg, IsoSpecs};
+use iso8583::yaml_specs::YamlSpec;
+use serde_json::{Map, Value};
+
+/// Owned message parsed against a `&'static` spec
+pub type Message = IsoMsg<'static, 'static>;
+
+/// Encoding of a message dump
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    Hex,
+    Raw,
+    Base64,
+}
+
+impl Encoding {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<Encoding> {
+        match s {
+            "hex" => Some(Encoding::Hex),
+            "raw" => Some(Encoding::Raw),
+            "base64" => Some(Encoding::Base64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Encoding::Hex => "hex",
+            Encoding::Raw => "raw",
+            Encoding::Base64 => "base64",
+        }
+    }
+
+    /// Bytes of a dump, whitespace is ignored for hex and base64
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let text: Vec<u8> = data.iter().cloned().filter(|b| !b.is_ascii_whitespace()).collect();
+        match *self {
+            Encoding::Hex => hex::decode(&text).map_err(|e| format!("Invalid hex: {}", e)),
+            Encoding::Raw => Ok(data.to_vec()),
+            Encoding::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(&text)
+                .map_err(|e| format!("Invalid base64: {}", e)),
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            Encoding::Hex => hex::encode_upper(data).into_bytes(),
+            Encoding::Raw => data.to_vec(),
+            Encoding::Base64 => base64::engine::general_purpose::STANDARD.encode(data).into_bytes(),
+        }
+    }
+}
+
+pub fn load_spec(path: &str) -> Result<&'static YamlSpec, String> {
+    let yaml = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let spec = YamlSpec::new(&yaml)?;
+    Ok(Box::leak(Box::new(spec)))
+}
+
+fn is_bitmap(iso_field: &IsoField) -> bool {
+    iso_field.char_type == FieldCharType::Iso8583_bmp || iso_field.char_type == FieldCharType::Iso8583_bmps
+}
+
+/// Message from its wire bytes, after removing the length header if given
+pub fn read_message(spec: &'static YamlSpec, data: &[u8], header: Option<LengthHeader>) -> Result<Message, String> {
+    let payload = match header {
+        Some(h) => match FrameFormat::new(h, false, 0).decode(data)? {
+            Some((frame, _)) => frame.body,
+            None => return Err("Message is shorter than its length header".to_string()),
+        },
+        None => data.to_vec(),
+    };
+    IsoMsg::check_payload(spec, &payload)?;
+    Ok(IsoMsg::new_owned(spec, payload))
+}
+
+/// Wire bytes of a message, with a length header if given
+pub fn write_message(msg: &Message, header: Option<LengthHeader>) -> Result<Vec<u8>, String> {
+    match header {
+        Some(h) => Ok(msg.to_frame(&FrameFormat::new(h, false, 0), &[])?),
+        None => Ok(msg.to_vec()),
+    }
+}
+
+fn display_value(iso_field: &IsoField, value: &[u8]) -> String {
+    if iso_field.char_type == FieldCharType::Iso8583_b {
+        hex::encode_upper(value)
+    } else {
+        String::from_utf8_lossy(value).into_owned()
+    }
+}
+
+/// Index, field and value of every field set, bitmaps included
+pub fn fields(msg: &Message) -> Vec<(usize, &'static IsoField, String)> {
+    msg.get_spec()
+        .get_handle()
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| msg.has_field(index))
+        .filter_map(|(index, iso_field)| {
+            msg.get_field_value(index)
+                .ok()
+                .map(|value| (index, iso_field, display_value(iso_field, &value)))
+        })
+        .collect()
+}
+
+/// One line per field: index, label and value
+pub fn to_listing(msg: &Message) -> String {
+    let mut listing = String::new();
+    for (index, iso_field, value) in fields(msg) {
+        let size = format!("{}{}", iso_field.char_type.as_str(), iso_field.length);
+        listing.push_str(&format!("{:03} {:<8} {:<45} [{}]\n", index, size, iso_field.label, value));
+    }
+    listing
+}
+
+/// JSON object of field index to value, bitmaps excluded
+pub fn to_json(msg: &Message) -> Value {
+    let mut map = Map::new();
+    for (index, iso_field, value) in fields(msg) {
+        if !is_bitmap(iso_field) {
+            map.insert(index.to_string(), Value::String(value));
+        }
+    }
+    Value::Object(map)
+}
+
+/// JSON array of differences with `path`, `change` (`added`, `removed` or `changed`),
+/// `expected` and `actual`. Values are text if printable, otherwise `0x` and hex
+pub fn diff_to_json(diffs: &[FieldDiff]) -> Value {
+    let text = |v: &[u8]| Value::String(printable(v));
+    let items = diffs
+        .iter()
+        .map(|d| {
+            let (change, expected, actual) = match d.change {
+                Change::Added(ref v) => ("added", Value::Null, text(v)),
+                Change::Removed(ref v) => ("removed", text(v), Value::Null),
+                Change::Changed(ref a, ref b) => ("changed", text(a), text(b)),
+            };
+            let mut map = Map::new();
+            map.insert("path".to_string(), Value::String(d.path.clone()));
+            map.insert("change".to_string(), Value::String(change.to_string()));
+            map.insert("expected".to_string(), expected);
+            map.insert("actual".to_string(), actual);
+            Value::Object(map)
+        })
+        .collect();
+    Value::Array(items)
+}
+
+/// Message from a JSON or YAML mapping of field index to value.
+/// Values must be strings so leading zeros are kept
+pub fn from_document(spec: &'static YamlSpec, document: &str) -> Result<Message, String> {
+    let doc: serde_yaml::Value = serde_yaml::from_str(document).map_err(|e| format!("Invalid document: {}", e))?;
+    let mapping = match doc {
+        serde_yaml::Value::Mapping(m) => m,
+        _ => return Err("Document must be a mapping of field index to value".to_string()),
+    };
+    let handle = spec.get_handle();
+    let mut msg = IsoMsg::empty(spec);
+    for (key, value) in mapping.iter() {
+        let index = match *key {
+            serde_yaml::Value::Number(ref n) => n.as_u64().map(|n| n as usize),
+            serde_yaml::Value::String(ref s) => s.parse::<usize>().ok(),
+            _ => None,
+        };
+        let index = match index {
+            Some(i) if i < handle.len() => i,
+            _ => return Err(format!("Invalid field index {:?}", key)),
+        };
+        let value = match *value {
+            serde_yaml::Value::String(ref s) => s,
+            _ => return Err(format!("Value of field {} must be a string", index)),
+        };
+        let iso_field = &handle[index];
+        if is_bitmap(iso_field) {
+            continue;
+        }
+        let bytes = if iso_field.char_type == FieldCharType::Iso8583_b {
+            hex::decode(value).map_err(|_| format!("Value of field {} must be hex", index))?
+        } else {
+            value.as_bytes().to_vec()
+        };
+        if iso_field.size_type == FieldSizeType::Fixed && bytes.len() != iso_field.length {
+            return Err(format!("Field {} must be {} long", index, iso_field.length));
+        }
+        if bytes.len() > iso_field.length {
+            return Err(format!("Field {} is longer than {}", index, iso_field.length));
+        }
+        msg.set_field(index, &bytes).map_err(|e| format!("Field {}: {}", index, e))?;
+    }
+    Ok(msg)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub fn spec() -> &'static YamlSpec {
+        let s = String::from(include_str!("../../spec1993.yml"));
+        Box::leak(Box::new(YamlSpec::new(&s).unwrap()))
+    }
+
+    const DOCUMENT: &str = r#"{"0": "0200", "2": "4111111111111111", "11": "000001", "64": "0102030405060708"}"#;
+
+    #[test]
+    fn encoding_test() {
+        assert_eq!(Encoding::Hex.decode(b"30 31\n32"), Ok(b"012".to_vec()));
+        assert_eq!(Encoding::Base64.decode(b"MDEy\n"), Ok(b"012".to_vec()));
+        assert_eq!(Encoding::Base64.encode(b"012"), b"MDEy".to_vec());
+        assert!(Encoding::Hex.decode(b"3G").is_err());
+        assert_eq!(Encoding::from_str("raw"), Some(Encoding::Raw));
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let spec = spec();
+        let msg = from_document(spec, DOCUMENT).unwrap();
+        let wire = write_message(&msg, Some(LengthHeader::Binary2)).unwrap();
+        let decoded = read_message(spec, &wire, Some(LengthHeader::Binary2)).unwrap();
+        assert_eq!(to_json(&decoded), serde_json::from_str::<Value>(DOCUMENT).unwrap());
+        let json = to_json(&decoded);
+        let keys: Vec<&String> = json.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["0", "2", "11", "64"]);
+
+        let listing = to_listing(&decoded);
+        assert!(listing.contains("Primary Account Number"));
+        assert!(listing.contains("[0102030405060708]"));
+
+        let yaml = from_document(spec, "0: \"0200\"\n11: \"000001\"\n").unwrap();
+        assert_eq!(yaml.get_field_value(11), Ok(b"000001".to_vec()));
+        assert!(from_document(spec, "11: 1").is_err());
+        assert!(from_document(spec, "500: \"1\"").is_err());
+        assert_eq!(from_document(spec, r#"{"11": "1234567"}"#).err(), Some("Field 11 must be 6 long".to_string()));
+        assert_eq!(from_document(spec, r#"{"2": "41111111111111111111"}"#).err(), Some("Field 2 is longer than 19".to_string()));
+        assert_eq!(write_message(&msg, None).unwrap(), msg.to_vec());
+        assert!(read_message(spec, b"0200", None).is_err());
+    }
+
+    #[test]
+    fn diff_to_json_test() {
+        let diffs = vec![
+            FieldDiff { path: "4".to_string(), change: Change::Changed(b"1".to_vec(), b"2".to_vec()) },
+            FieldDiff { path: "64".to_string(), change: Change::Added(vec![0, 1]) },
+        ];
+        let expected = r#"[{"path": "4", "change": "changed", "expected": "1", "actual": "2"},
+            {"path": "64", "change": "added", "expected": null, "actual": "0x0001"}]"#;
+        assert_eq!(diff_to_json(&diffs), serde_json::from_str::<Value>(expected).unwrap());
+    }
+}
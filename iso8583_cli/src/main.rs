@@ -0,0 +1,292 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+use std::time::Duration;
+
+use iso8583::iso_diff::{diff_messages, DiffOptions};
+use iso8583::iso_framing::{FrameFormat, LengthHeader};
+use iso8583_cli::{diff_to_json, from_document, load_spec, read_message, to_json, to_listing, write_message, Encoding};
+use iso8583_net::client::{Client, ClientConfig};
+use iso8583_net::load::{run_load, LoadConfig, Template};
+use iso8583_net::script::{run_script, Script, Target};
+use iso8583_net::simulator::Simulator;
+
+const USAGE: &str = "\
+Usage:
+    iso8583 decode --spec <spec.yml> [--format hex|raw|base64] [--header binary2|binary4|ascii4] [--json] [<file>]
+    iso8583 encode --spec <spec.yml> [--format hex|raw|base64] [--header binary2|binary4|ascii4] [<file>]
+    iso8583 diff --spec <spec.yml> [--format ...] [--header ...] [--ignore <paths>] [--json] <expected> <actual>
+    iso8583 run --spec <spec.yml> --host <addr:port> [--header ...] [--timeout <seconds>] <script.yml>
+    iso8583 simulate --spec <spec.yml> --listen <addr:port> [--header ...] [<rules.yml>]
+    iso8583 load --spec <spec.yml> --host <addr:port> [--header ...] [--timeout <seconds>]
+                 [--rate <per second>] [--concurrency <n>] [--count <n>] [--duration <seconds>] <template.yml>
+
+decode reads a message dump and prints its fields, encode reads a JSON or YAML
+mapping of field index to value and writes the message. Input is read from <file>
+or stdin, the default format is hex. diff compares two dumps field by field and
+exits with 1 if they differ, --ignore is a comma separated list of field paths
+(default 7,11,12). run sends the cases of a certification script to a host, the
+default header is binary2, and exits with 1 if a case fails. simulate answers
+requests as an issuer according to the rules, approving all without rules. load
+sends requests from a template and reports latencies and response codes, 1000
+requests with concurrency 1 by default.";
+
+struct Options {
+    command: String,
+    spec: String,
+    format: Encoding,
+    header: Option<LengthHeader>,
+    json: bool,
+    ignore: Option<Vec<String>>,
+    host: Option<String>,
+    listen: Option<String>,
+    load: LoadConfig,
+    timeout: Option<Duration>,
+    files: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let command = match args.first() {
+        Some(c) if c == "decode" || c == "encode" || c == "diff" || c == "run" || c == "simulate" || c == "load" => c.clone(),
+        Some(c) => return Err(format!("Unknown command {}", c)),
+        None => return Err("Missing command".to_string()),
+    };
+    let mut options = Options {
+        command,
+        spec: String::new(),
+        format: Encoding::Hex,
+        header: None,
+        json: false,
+        ignore: None,
+        host: None,
+        listen: None,
+        load: LoadConfig::default(),
+        timeout: None,
+        files: Vec::new(),
+    };
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().cloned().ok_or(format!("Missing value of {}", name));
+        match arg.as_str() {
+            "--spec" => options.spec = value(arg)?,
+            "--format" => {
+                let v = value(arg)?;
+                options.format = Encoding::from_str(&v).ok_or(format!("Unknown format {}", v))?;
+            }
+            "--header" => {
+                let v = value(arg)?;
+                options.header = Some(LengthHeader::from_str(&v).ok_or(format!("Unknown header {}", v))?);
+            }
+            "--ignore" => {
+                let v = value(arg)?;
+                options.ignore = Some(v.split(',').filter(|p| !p.is_empty()).map(|p| p.to_string()).collect());
+            }
+            "--host" => options.host = Some(value(arg)?),
+            "--listen" => options.listen = Some(value(arg)?),
+            "--rate" => options.load.rate = Some(parse_number(arg, &value(arg)?)?),
+            "--concurrency" => options.load.concurrency = parse_number(arg, &value(arg)?)?,
+            "--count" => options.load.requests = Some(parse_number(arg, &value(arg)?)?),
+            "--duration" => {
+                options.load.duration = Some(Duration::from_secs_f64(parse_number(arg, &value(arg)?)?));
+                options.load.requests = None;
+            }
+            "--timeout" => options.timeout = Some(Duration::from_secs_f64(parse_number(arg, &value(arg)?)?)),
+            "--json" => options.json = true,
+            a if a.starts_with("--") => return Err(format!("Unknown option {}", a)),
+            a => options.files.push(a.to_string()),
+        }
+    }
+    if options.spec.is_empty() {
+        return Err("Missing --spec".to_string());
+    }
+    if (options.command == "run" || options.command == "load") && options.host.is_none() {
+        return Err("Missing --host".to_string());
+    }
+    if options.command == "simulate" && options.listen.is_none() {
+        return Err("Missing --listen".to_string());
+    }
+    if options.command == "diff" && options.files.len() < 2 {
+        return Err("Missing messages to compare".to_string());
+    }
+    if options.command == "run" && options.files.is_empty() {
+        return Err("Missing script".to_string());
+    }
+    if options.command == "load" && options.files.is_empty() {
+        return Err("Missing template".to_string());
+    }
+    let max_files = if options.command == "diff" { 2 } else { 1 };
+    if options.files.len() > max_files {
+        return Err(format!("Unknown argument {}", options.files[max_files]));
+    }
+    Ok(options)
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value of {}: {}", name, value))
+}
+
+fn read_input(file: Option<&String>) -> Result<Vec<u8>, String> {
+    match file {
+        Some(path) => fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e)),
+        None => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data).map_err(|e| format!("Failed to read stdin: {}", e))?;
+            Ok(data)
+        }
+    }
+}
+
+/// Ok(true) if the messages compared equal
+fn run_diff(options: &Options) -> Result<bool, String> {
+    let spec = load_spec(&options.spec)?;
+    let mut msgs = Vec::new();
+    for file in &options.files {
+        let input = read_input(Some(file))?;
+        let msg = read_message(spec, &options.format.decode(&input)?, options.header)
+            .map_err(|e| format!("{}: {}", file, e))?;
+        msgs.push(msg);
+    }
+    let mut diff_options = DiffOptions::new().with_volatile();
+    if let Some(ref ignore) = options.ignore {
+        diff_options.ignore = ignore.clone();
+    }
+    let diffs = diff_messages(&msgs[0], &msgs[1], &diff_options)?;
+    let output = if options.json {
+        format!("{}\n", serde_json::to_string_pretty(&diff_to_json(&diffs)).unwrap())
+    } else {
+        diffs.iter().map(|d| format!("{}\n", d)).collect()
+    };
+    io::stdout()
+        .write_all(output.as_bytes())
+        .map_err(|e| format!("Failed to write output: {}", e))?;
+    Ok(diffs.is_empty())
+}
+
+fn runtime() -> Result<tokio::runtime::Runtime, String> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("Failed to start runtime: {}", e))
+}
+
+/// Framing of network commands, binary2 by default
+fn network_format(options: &Options) -> FrameFormat {
+    FrameFormat::new(options.header.unwrap_or(LengthHeader::Binary2), false, 0)
+}
+
+fn client_config(options: &Options) -> ClientConfig {
+    let mut config = ClientConfig::new(network_format(options));
+    if let Some(timeout) = options.timeout {
+        config.timeout = timeout;
+    }
+    config
+}
+
+/// Ok(true) if every case passed
+fn run_cases(options: &Options) -> Result<bool, String> {
+    let spec = load_spec(&options.spec)?;
+    let script = Script::load(&options.files[0])?;
+    let host = options.host.clone().unwrap_or_default();
+    let report = runtime()?.block_on(async {
+        let client = Client::connect(host.as_str(), spec, client_config(options))
+            .await
+            .map_err(|e| format!("Failed to connect to {}: {}", host, e))?;
+        Ok::<_, String>(run_script(&script, spec, Target::Host(&client)).await)
+    })?;
+    println!("{}", report);
+    Ok(report.failed() == 0)
+}
+
+fn run_simulator(options: &Options) -> Result<(), String> {
+    let spec = load_spec(&options.spec)?;
+    let simulator = match options.files.first() {
+        Some(path) => Simulator::load(path)?,
+        None => Simulator::default(),
+    };
+    let listen = options.listen.clone().unwrap_or_default();
+    runtime()?.block_on(async {
+        let host = simulator
+            .bind(listen.as_str(), spec, network_format(options))
+            .await
+            .map_err(|e| format!("Failed to listen on {}: {}", listen, e))?;
+        println!("Listening on {}", host.local_addr());
+        std::future::pending::<()>().await;
+        Ok(())
+    })
+}
+
+fn run_load_test(options: &Options) -> Result<(), String> {
+    let spec = load_spec(&options.spec)?;
+    let template = Template::load(&options.files[0])?;
+    let host = options.host.clone().unwrap_or_default();
+    let report = runtime()?.block_on(async {
+        let client = Client::connect(host.as_str(), spec, client_config(options))
+            .await
+            .map_err(|e| format!("Failed to connect to {}: {}", host, e))?;
+        run_load(&client, &template, options.load).await
+    })?;
+    println!("{}", report);
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let spec = load_spec(&options.spec)?;
+    let input = read_input(options.files.first())?;
+    let output = if options.command == "decode" {
+        let msg = read_message(spec, &options.format.decode(&input)?, options.header)?;
+        if options.json {
+            format!("{}\n", serde_json::to_string_pretty(&to_json(&msg)).unwrap()).into_bytes()
+        } else {
+            to_listing(&msg).into_bytes()
+        }
+    } else {
+        let document = String::from_utf8(input).map_err(|_| "Document is not valid UTF-8".to_string())?;
+        let msg = from_document(spec, &document)?;
+        let mut output = options.format.encode(&write_message(&msg, options.header)?);
+        if options.format != Encoding::Raw {
+            output.push(b'\n');
+        }
+        output
+    };
+    io::stdout().write_all(&output).map_err(|e| format!("Failed to write output: {}", e))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() || args[0] == "--help" || args[0] == "-h" {
+        println!("{}", USAGE);
+        return;
+    }
+    let res = parse_args(&args).and_then(|options| {
+        if options.command == "diff" {
+            run_diff(&options)
+        } else if options.command == "run" {
+            run_cases(&options)
+        } else if options.command == "simulate" {
+            run_simulator(&options).map(|_| true)
+        } else if options.command == "load" {
+            run_load_test(&options).map(|_| true)
+        } else {
+            run(&options).map(|_| true)
+        }
+    });
+    match res {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("iso8583: {}", e);
+            if e.starts_with("Missing") || e.starts_with("Unknown") {
+                eprintln!("{}", USAGE);
+            }
+            process::exit(2);
+        }
+    }
+}
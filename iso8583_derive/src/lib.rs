@@ -0,0 +1,169 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `#[derive(Iso8583)]` implementation.
+//!
+//! Each struct member annotated with `#[iso(de = N)]` is mapped to data element `N`.
+//! `Option<T>` members are optional, any other type is mandatory and `from_iso_msg`
+//! fails when the data element is absent. Members without `#[iso]` are ignored by
+//! `to_iso_msg` and set to `Default::default()` by `from_iso_msg`.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, GenericArgument, PathArguments, Type};
+
+#[proc_macro_derive(Iso8583, attributes(iso))]
+pub fn derive_iso8583(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// A struct member mapped to a data element
+struct IsoMember {
+    ident: syn::Ident,
+    de: Option<usize>,
+    optional: bool,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let fields = match input.data {
+        Data::Struct(ref s) => match s.fields {
+            Fields::Named(ref f) => &f.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "#[derive(Iso8583)] requires a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "#[derive(Iso8583)] can only be used on structs",
+            ))
+        }
+    };
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "#[derive(Iso8583)] does not support generic structs",
+        ));
+    }
+
+    let mut members = Vec::with_capacity(fields.len());
+    for field in fields.iter() {
+        members.push(IsoMember {
+            ident: field.ident.clone().unwrap(),
+            de: parse_de(&field.attrs)?,
+            optional: option_inner(&field.ty).is_some(),
+        });
+    }
+
+    let set_fields = members.iter().filter(|m| m.de.is_some()).map(|m| {
+        let ident = &m.ident;
+        let de = m.de.unwrap();
+        if m.optional {
+            quote! {
+                if let ::std::option::Option::Some(ref v) = self.#ident {
+                    ::iso8583::iso_struct::set_value(&mut iso_msg, #de, v)?;
+                }
+            }
+        } else {
+            quote! {
+                ::iso8583::iso_struct::set_value(&mut iso_msg, #de, &self.#ident)?;
+            }
+        }
+    });
+
+    let get_fields = members.iter().map(|m| {
+        let ident = &m.ident;
+        match m.de {
+            None => quote! { #ident: ::std::default::Default::default() },
+            Some(de) if m.optional => quote! {
+                #ident: ::iso8583::iso_struct::get_value(iso_msg, #de)?
+            },
+            Some(de) => {
+                let member = ident.to_string();
+                quote! {
+                    #ident: match ::iso8583::iso_struct::get_value(iso_msg, #de)? {
+                        ::std::option::Option::Some(v) => v,
+                        ::std::option::Option::None => {
+                            return ::std::result::Result::Err(
+                                ::iso8583::iso_struct::missing_field(#de, #member),
+                            )
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl ::iso8583::iso_struct::Iso8583 for #name {
+            fn to_iso_msg<'b>(
+                &self,
+                iso_spec: &'b dyn ::iso8583::iso_msg::IsoSpecs,
+            ) -> ::std::result::Result<::iso8583::iso_msg::IsoMsg<'static, 'b>, ::std::string::String> {
+                let mut iso_msg = ::iso8583::iso_msg::IsoMsg::empty(iso_spec);
+                #(#set_fields)*
+                ::std::result::Result::Ok(iso_msg)
+            }
+
+            fn from_iso_msg(
+                iso_msg: &::iso8583::iso_msg::IsoMsg,
+            ) -> ::std::result::Result<Self, ::std::string::String> {
+                ::std::result::Result::Ok(#name {
+                    #(#get_fields,)*
+                })
+            }
+        }
+    })
+}
+
+/// Parse `#[iso(de = N)]`
+fn parse_de(attrs: &[syn::Attribute]) -> syn::Result<Option<usize>> {
+    let mut de = None;
+    for attr in attrs.iter().filter(|a| a.path().is_ident("iso")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("de") {
+                let lit: syn::LitInt = meta.value()?.parse()?;
+                de = Some(lit.base10_parse::<usize>()?);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported iso attribute, expected `de = N`"))
+            }
+        })?;
+    }
+    Ok(de)
+}
+
+/// Returns `T` if `ty` is `Option<T>`
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let path = match *ty {
+        Type::Path(ref p) if p.qself.is_none() => &p.path,
+        _ => return None,
+    };
+    let segment = path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match segment.arguments {
+        PathArguments::AngleBracketed(ref args) => match args.args.first() {
+            Some(GenericArgument::Type(ref t)) => Some(t),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,407 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Async client multiplexing requests over one connection.
+//!
+//! Responses are matched on the response MTI and DE 11 (STAN), plus DE 37 (RRN)
+//! and DE 41 (terminal id) when the request carries them. Messages from the host
+//! that match no request are passed to the unsolicited receiver.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{SinkExt, StreamExt};
+use iso8583::iso_codec::IsoCodec;
+use iso8583::iso_framing::FrameFormat;
+use iso8583::iso_msg::IsoSpecs;
+use log::{trace, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::Framed;
+
+use crate::{response_mti, Message, DE_MTI, DE_RRN, DE_STAN, DE_TERMINAL_ID};
+
+/// Number of unsolicited messages kept until read, later ones are dropped
+const UNSOLICITED_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClientConfig {
+    pub format: FrameFormat,
+    /// Time to wait for a response
+    pub timeout: Duration,
+}
+
+impl ClientConfig {
+    pub fn new(format: FrameFormat) -> ClientConfig {
+        ClientConfig {
+            format,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientError {
+    /// No matching response within the timeout
+    Timeout,
+    /// The connection is closed
+    Closed,
+    /// The request can't be sent or matched
+    InvalidMessage(&'static str),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Timeout => write!(f, "Timed out waiting for response"),
+            ClientError::Closed => write!(f, "Connection closed"),
+            ClientError::InvalidMessage(e) => write!(f, "Invalid message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Fields a response must carry to match a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchKey {
+    pub mti: Vec<u8>,
+    pub stan: Vec<u8>,
+    pub rrn: Option<Vec<u8>>,
+    pub terminal_id: Option<Vec<u8>>,
+}
+
+impl MatchKey {
+    /// Key of the response expected for `request`
+    pub fn for_request(request: &Message) -> Result<MatchKey, &'static str> {
+        let mti = response_mti(&request.get_field_value(DE_MTI)?)?;
+        MatchKey::with_mti(request, mti)
+    }
+
+    pub fn for_response(response: &Message) -> Result<MatchKey, &'static str> {
+        let mti = response.get_field_value(DE_MTI)?;
+        MatchKey::with_mti(response, mti)
+    }
+
+    fn with_mti(msg: &Message, mti: Vec<u8>) -> Result<MatchKey, &'static str> {
+        if !msg.has_field(DE_STAN) {
+            return Err("Message has no STAN");
+        }
+        let optional = |index| if msg.has_field(index) {
+            msg.get_field_value(index).ok()
+        } else {
+            None
+        };
+        Ok(MatchKey {
+            mti,
+            stan: msg.get_field_value(DE_STAN)?,
+            rrn: optional(DE_RRN),
+            terminal_id: optional(DE_TERMINAL_ID),
+        })
+    }
+
+    /// true if `response` answers the request this key was built for
+    pub fn matches(&self, response: &MatchKey) -> bool {
+        self.mti == response.mti && self.stan == response.stan &&
+            (self.rrn.is_none() || self.rrn == response.rrn) &&
+            (self.terminal_id.is_none() || self.terminal_id == response.terminal_id)
+    }
+}
+
+struct Pending {
+    id: u64,
+    key: MatchKey,
+    tx: oneshot::Sender<Message>,
+}
+
+/// In-flight requests by (response MTI, STAN)
+type PendingMap = Arc<Mutex<HashMap<(Vec<u8>, Vec<u8>), Vec<Pending>>>>;
+
+pub struct Client {
+    spec: &'static (dyn IsoSpecs + Sync),
+    tx: mpsc::UnboundedSender<Message>,
+    pending: PendingMap,
+    unsolicited: Mutex<Option<mpsc::Receiver<Message>>>,
+    stan: AtomicU32,
+    next_id: AtomicU64,
+    timeout: Duration,
+    last_activity: Arc<Mutex<Instant>>,
+}
+
+impl Client {
+    pub async fn connect<A: ToSocketAddrs>(
+        addr: A,
+        spec: &'static (dyn IsoSpecs + Sync),
+        config: ClientConfig,
+    ) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Client::new(stream, spec, config))
+    }
+
+    /// Client over an established stream, must be called within a tokio runtime
+    pub fn new<S>(stream: S, spec: &'static (dyn IsoSpecs + Sync), config: ClientConfig) -> Client
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let codec = IsoCodec::new(spec, config.format);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (unsolicited_tx, unsolicited_rx) = mpsc::channel(UNSOLICITED_CAPACITY);
+        let pending = PendingMap::default();
+        let last_activity = Arc::new(Mutex::new(Instant::now()));
+        tokio::spawn(run_connection(
+            Framed::new(stream, codec),
+            rx,
+            pending.clone(),
+            unsolicited_tx,
+            last_activity.clone(),
+        ));
+        Client {
+            spec,
+            tx,
+            pending,
+            unsolicited: Mutex::new(Some(unsolicited_rx)),
+            stan: AtomicU32::new(0),
+            next_id: AtomicU64::new(0),
+            timeout: config.timeout,
+            last_activity,
+        }
+    }
+
+    pub fn get_spec(&self) -> &'static (dyn IsoSpecs + Sync) {
+        self.spec
+    }
+
+    /// Next STAN, from `000001` to `999999`
+    pub fn next_stan(&self) -> String {
+        let n = self.stan.fetch_add(1, Ordering::Relaxed) % 999_999 + 1;
+        format!("{:06}", n)
+    }
+
+    /// Receiver of host messages matching no request, can be taken once
+    pub fn take_unsolicited(&self) -> Option<mpsc::Receiver<Message>> {
+        self.unsolicited.lock().unwrap().take()
+    }
+
+    /// Time since a message was last sent or received
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+
+    /// Send `msg` without waiting for a response e.g. the response to a host request
+    pub fn post(&self, msg: Message) -> Result<(), ClientError> {
+        self.tx.send(msg).map_err(|_| ClientError::Closed)
+    }
+
+    /// Send `request` and wait for the matching response.
+    /// DE 11 is assigned from `next_stan` if not set
+    pub async fn send(&self, mut request: Message) -> Result<Message, ClientError> {
+        if !request.has_field(DE_STAN) {
+            let stan = self.next_stan();
+            request.set_field(DE_STAN, stan.as_bytes()).map_err(
+                ClientError::InvalidMessage,
+            )?;
+        }
+        let key = MatchKey::for_request(&request).map_err(
+            ClientError::InvalidMessage,
+        )?;
+        let slot = (key.mti.clone(), key.stan.clone());
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(slot.clone())
+            .or_default()
+            .push(Pending { id, key, tx });
+
+        if self.tx.send(request).is_err() {
+            self.cancel(&slot, id);
+            return Err(ClientError::Closed);
+        }
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(ClientError::Closed),
+            Err(_) => {
+                self.cancel(&slot, id);
+                Err(ClientError::Timeout)
+            }
+        }
+    }
+
+    fn cancel(&self, slot: &(Vec<u8>, Vec<u8>), id: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(list) = pending.get_mut(slot) {
+            list.retain(|p| p.id != id);
+            if list.is_empty() {
+                pending.remove(slot);
+            }
+        }
+    }
+}
+
+async fn run_connection<S>(
+    mut framed: Framed<S, IsoCodec<'static>>,
+    mut rx: mpsc::UnboundedReceiver<Message>,
+    pending: PendingMap,
+    unsolicited: mpsc::Sender<Message>,
+    last_activity: Arc<Mutex<Instant>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        tokio::select! {
+            outgoing = rx.recv() => match outgoing {
+                Some(msg) => {
+                    *last_activity.lock().unwrap() = Instant::now();
+                    if let Err(e) = framed.send(msg).await {
+                        warn!("Failed to send message: {}", e);
+                        break;
+                    }
+                }
+                None => break,
+            },
+            incoming = framed.next() => match incoming {
+                Some(Ok(msg)) => {
+                    *last_activity.lock().unwrap() = Instant::now();
+                    dispatch(&pending, &unsolicited, msg);
+                }
+                Some(Err(e)) => {
+                    warn!("Failed to read message: {}", e);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+    // dropping the senders fails the waiting requests with `Closed`
+    pending.lock().unwrap().clear();
+}
+
+fn dispatch(pending: &PendingMap, unsolicited: &mpsc::Sender<Message>, msg: Message) {
+    if let Ok(key) = MatchKey::for_response(&msg) {
+        let mut map = pending.lock().unwrap();
+        let slot = (key.mti.clone(), key.stan.clone());
+        if let Some(list) = map.get_mut(&slot) {
+            if let Some(pos) = list.iter().position(|p| p.key.matches(&key)) {
+                let p = list.remove(pos);
+                if list.is_empty() {
+                    map.remove(&slot);
+                }
+                drop(map);
+                let _ = p.tx.send(msg);
+                return;
+            }
+        }
+    }
+    trace!("Unmatched message from host");
+    if unsolicited.try_send(msg).is_err() {
+        warn!("Dropped unsolicited message");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_response;
+    use crate::mock::MockHost;
+    use crate::tests::spec;
+    use iso8583::iso_framing::LengthHeader;
+    use iso8583::iso_msg::IsoMsg;
+    use tokio::net::TcpListener;
+
+    fn request(mti: &[u8], terminal_id: &[u8]) -> Message {
+        let mut msg = IsoMsg::empty(spec());
+        msg.set_field(DE_MTI, mti).unwrap();
+        msg.set_field(DE_TERMINAL_ID, terminal_id).unwrap();
+        msg
+    }
+
+    fn format() -> FrameFormat {
+        FrameFormat::new(LengthHeader::Binary2, false, 0)
+    }
+
+    #[tokio::test]
+    async fn client_out_of_order_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(stream, IsoCodec::new(spec(), format()));
+            let first = framed.next().await.unwrap().unwrap();
+            let second = framed.next().await.unwrap().unwrap();
+            let mut unsolicited = request(b"0800", b"HOST0001");
+            unsolicited.set_field(DE_STAN, b"999999").unwrap();
+            framed.send(unsolicited).await.unwrap();
+            framed.send(build_response(&second, b"00").unwrap()).await.unwrap();
+            framed.send(build_response(&first, b"05").unwrap()).await.unwrap();
+            let _ = framed.next().await;
+        });
+
+        let client = Client::connect(addr, spec(), ClientConfig::new(format())).await.unwrap();
+        let (a, b) = tokio::join!(
+            client.send(request(b"0100", b"TERM0001")),
+            client.send(request(b"0200", b"TERM0002"))
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.get_field_value(DE_MTI), Ok(b"0110".to_vec()));
+        assert_eq!(a.get_field_value(DE_STAN), Ok(b"000001".to_vec()));
+        assert_eq!(a.get_field_value(39), Ok(b"05".to_vec()));
+        assert_eq!(b.get_field_value(DE_TERMINAL_ID), Ok(b"TERM0002".to_vec()));
+        assert_eq!(b.get_field_value(39), Ok(b"00".to_vec()));
+
+        let mut unsolicited = client.take_unsolicited().unwrap();
+        let msg = unsolicited.recv().await.unwrap();
+        assert_eq!(msg.get_field_value(DE_MTI), Ok(b"0800".to_vec()));
+        assert!(client.take_unsolicited().is_none());
+    }
+
+    #[tokio::test]
+    async fn client_timeout_test() {
+        let host = MockHost::bind(spec(), format(), |req: &Message| {
+            if req.get_field_value(DE_TERMINAL_ID) == Ok(b"SILENT01".to_vec()) {
+                None
+            } else {
+                build_response(req, b"00").ok()
+            }
+        }).await
+            .unwrap();
+        let mut config = ClientConfig::new(format());
+        config.timeout = Duration::from_millis(100);
+        let client = Client::connect(host.local_addr(), spec(), config).await.unwrap();
+
+        let res = client.send(request(b"0100", b"SILENT01")).await;
+        assert_eq!(res.err(), Some(ClientError::Timeout));
+        assert!(client.pending.lock().unwrap().is_empty());
+
+        let res = client.send(request(b"0100", b"TERM0001")).await.unwrap();
+        assert_eq!(res.get_field_value(DE_STAN), Ok(b"000002".to_vec()));
+
+        let res = client.send(request(b"0110", b"TERM0001")).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_closed_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(stream, IsoCodec::new(spec(), format()));
+            let _ = framed.next().await;
+        });
+
+        let client = Client::connect(addr, spec(), ClientConfig::new(format())).await.unwrap();
+        let res = client.send(request(b"0100", b"TERM0001")).await;
+        assert_eq!(res.err(), Some(ClientError::Closed));
+    }
+}
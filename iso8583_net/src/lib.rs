@@ -0,0 +1,105 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Async networking on tokio for the `iso8583` crate, built on `iso_codec::IsoCodec`.
+
+pub mod client;
+pub mod load;
+pub mod mock;
+pub mod netmgmt;
+pub mod reversal;
+pub mod script;
+pub mod server;
+pub mod simulator;
+
+use iso8583::iso_field::FieldCharType;
+use iso8583::iso_msg::IsoMsg;
+
+/// Owned message parsed against a `&'static` spec, as yielded by the codec
+pub type Message = IsoMsg<'static, 'static>;
+
+pub const DE_MTI: usize = 0;
+pub const DE_STAN: usize = 11;
+pub const DE_RRN: usize = 37;
+pub const DE_ACTION_CODE: usize = 39;
+pub const DE_TERMINAL_ID: usize = 41;
+
+/// MTI of the response to a request or advice e.g. `0100` -> `0110`, `0421` -> `0430`
+pub fn response_mti(mti: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if mti.len() != 4 || !mti.iter().all(|b| b.is_ascii_digit()) {
+        return Err("Invalid MTI");
+    }
+    if mti[2] != b'0' && mti[2] != b'2' {
+        return Err("MTI is not a request or advice");
+    }
+    let mut res = mti.to_vec();
+    res[2] += 1;
+    res[3] -= (res[3] - b'0') % 2;
+    Ok(res)
+}
+
+/// Fields of a request never echoed in its response: track data (35, 45), PIN block (52),
+/// security control (53), ICC data (55), key data (96) and the request MACs (64, 128)
+pub const NOT_ECHOED_FIELDS: [usize; 8] = [35, 45, 52, 53, 55, 64, 96, 128];
+
+/// Response to `request` echoing its fields but `NOT_ECHOED_FIELDS`, with DE 39 set to `action_code`
+pub fn build_response(request: &Message, action_code: &[u8]) -> Result<Message, &'static str> {
+    let spec = request.get_spec();
+    let mti = response_mti(&request.get_field_value(DE_MTI)?)?;
+    let mut response = IsoMsg::empty(spec);
+    response.set_field(DE_MTI, &mti)?;
+    for (index, iso_field) in spec.get_handle().iter().enumerate().skip(1) {
+        if iso_field.char_type == FieldCharType::Iso8583_bmp ||
+            iso_field.char_type == FieldCharType::Iso8583_bmps
+        {
+            continue;
+        }
+        if request.has_field(index) && !NOT_ECHOED_FIELDS.contains(&index) {
+            response.set_field(index, &request.get_field_value(index)?)?;
+        }
+    }
+    response.set_field(DE_ACTION_CODE, action_code)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use iso8583::yaml_specs::YamlSpec;
+
+    pub fn spec() -> &'static YamlSpec {
+        let s = String::from(include_str!("../../spec1993.yml"));
+        Box::leak(Box::new(YamlSpec::new(&s).unwrap()))
+    }
+
+    #[test]
+    fn response_mti_test() {
+        assert_eq!(response_mti(b"0100"), Ok(b"0110".to_vec()));
+        assert_eq!(response_mti(b"0421"), Ok(b"0430".to_vec()));
+        assert_eq!(response_mti(b"0800"), Ok(b"0810".to_vec()));
+        assert!(response_mti(b"0110").is_err());
+        assert!(response_mti(b"01A0").is_err());
+    }
+
+    #[test]
+    fn build_response_test() {
+        let mut request = IsoMsg::empty(spec());
+        request.set_field(DE_MTI, b"0200").unwrap();
+        request.set_field(DE_STAN, b"000042").unwrap();
+        request.set_field(35, b"4111111111111111=2512").unwrap();
+        request.set_field(52, b"0123456789ABCDEF").unwrap();
+        request.set_field(128, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let response = build_response(&request, b"00").unwrap();
+        for index in NOT_ECHOED_FIELDS.iter() {
+            assert!(!response.has_field(*index));
+        }
+        assert_eq!(response.get_field_value(DE_MTI), Ok(b"0210".to_vec()));
+        assert_eq!(response.get_field_value(DE_STAN), Ok(b"000042".to_vec()));
+        assert_eq!(response.get_field_value(DE_ACTION_CODE), Ok(b"00".to_vec()));
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Load generator: sends templated requests at a given rate and concurrency and
+//! reports latency percentiles, timeouts and the distribution of response codes.
+//!
+//! Template values may contain `{stan}` (the client's next STAN), `{rrn}` (12 random digits),
+//! `{amount}` (12 digits between `amount.min` and `amount.max`) and `{pan}` (one of `pans`,
+//! the same in every field of a request).
+//!
+//! ```yaml
+//! request:
+//!   0: "0100"
+//!   2: "{pan}"
+//!   4: "{amount}"
+//!   11: "{stan}"
+//!   37: "{rrn}"
+//!   41: "TERM0001"
+//! pans: ["4111111111111111", "5500005555555559"]
+//! amount: {min: 100, max: 50000}
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use iso8583::iso_msg::{IsoMsg, IsoSpecs};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_yaml::Value;
+use tokio::time::MissedTickBehavior;
+
+use crate::client::{Client, ClientError};
+use crate::script::{get, parse_fields, set_field_text};
+use crate::{Message, DE_ACTION_CODE};
+
+const PLACEHOLDERS: [&str; 4] = ["stan", "rrn", "amount", "pan"];
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    /// Field path and value, in order
+    pub fields: Vec<(String, String)>,
+    pub pans: Vec<String>,
+    /// Inclusive range of `{amount}` in minor units
+    pub amount: (u64, u64),
+}
+
+fn random_digits<R: Rng>(rng: &mut R, len: usize) -> String {
+    (0..len).map(|_| char::from(b'0' + rng.gen_range(0..10u8))).collect()
+}
+
+impl Template {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(text: &str) -> Result<Template, String> {
+        let doc: Value = serde_yaml::from_str(text).map_err(|e| format!("Invalid template: {}", e))?;
+        let fields = parse_fields(get(&doc, "request"), "Template", |v| match *v {
+            Value::String(ref s) => Ok(s.clone()),
+            _ => Err("value must be a string".to_string()),
+        })?;
+        if fields.is_empty() {
+            return Err("Template has no request".to_string());
+        }
+        let pans = match get(&doc, "pans") {
+            Some(Value::Sequence(s)) => s
+                .iter()
+                .map(|p| p.as_str().map(|p| p.to_string()).ok_or("pans must be strings".to_string()))
+                .collect::<Result<Vec<String>, String>>()?,
+            None => Vec::new(),
+            _ => return Err("pans must be a sequence".to_string()),
+        };
+        let bound = |name: &str, default: u64| match get(&doc, "amount").and_then(|a| get(a, name)) {
+            Some(v) => v.as_u64().ok_or(format!("amount.{} must be a number", name)),
+            None => Ok(default),
+        };
+        let amount = (bound("min", 1)?, bound("max", 100_000)?);
+        if amount.0 > amount.1 || amount.1 > 999_999_999_999 {
+            return Err("Invalid amount range".to_string());
+        }
+
+        for (path, value) in &fields {
+            let mut rest = &value[..];
+            while let Some(start) = rest.find('{') {
+                let end = rest[start..].find('}').map(|e| start + e).ok_or(format!("{}: unclosed {{", path))?;
+                let name = &rest[start + 1..end];
+                if !PLACEHOLDERS.contains(&name) {
+                    return Err(format!("{}: unknown placeholder {{{}}}", path, name));
+                }
+                if name == "pan" && pans.is_empty() {
+                    return Err(format!("{}: {{pan}} needs a list of pans", path));
+                }
+                rest = &rest[end + 1..];
+            }
+        }
+        Ok(Template { fields, pans, amount })
+    }
+
+    pub fn load(path: &str) -> Result<Template, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Template::from_str(&text)
+    }
+
+    /// Request with `{stan}` replaced by `stan` and the other placeholders by random values
+    pub fn render(&self, spec: &'static (dyn IsoSpecs + Sync), stan: &str) -> Result<Message, String> {
+        let mut rng = rand::thread_rng();
+        let pan = self.pans.choose(&mut rng);
+        let mut msg = IsoMsg::empty(spec);
+        for (path, value) in &self.fields {
+            let mut value = value.replace("{stan}", stan);
+            if value.contains("{rrn}") {
+                value = value.replace("{rrn}", &random_digits(&mut rng, 12));
+            }
+            if value.contains("{amount}") {
+                let amount = rng.gen_range(self.amount.0..=self.amount.1);
+                value = value.replace("{amount}", &format!("{:012}", amount));
+            }
+            if let Some(pan) = pan {
+                value = value.replace("{pan}", pan);
+            }
+            set_field_text(&mut msg, path, &value)?;
+        }
+        Ok(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoadConfig {
+    /// Requests in flight at once
+    pub concurrency: usize,
+    /// Requests per second across all workers, unlimited if `None`
+    pub rate: Option<f64>,
+    /// Number of requests to send, unlimited if `None`
+    pub requests: Option<usize>,
+    /// Stop sending after this time, unlimited if `None`
+    pub duration: Option<Duration>,
+}
+
+impl Default for LoadConfig {
+    fn default() -> LoadConfig {
+        LoadConfig {
+            concurrency: 1,
+            rate: None,
+            requests: Some(1000),
+            duration: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoadReport {
+    pub sent: usize,
+    pub elapsed: Duration,
+    /// Rate of `LoadConfig`, to compare with `throughput`
+    pub target_rate: Option<f64>,
+    /// Latencies of the responses, sorted
+    pub latencies: Vec<Duration>,
+    pub timeouts: usize,
+    /// Closed connection or invalid requests
+    pub errors: usize,
+    /// Response count by DE 39, `none` if absent
+    pub response_codes: BTreeMap<String, usize>,
+}
+
+impl LoadReport {
+    /// Latency at percentile `p` (0 to 100) of the responses
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.max(1).min(self.latencies.len()) - 1])
+    }
+
+    /// Requests sent per second, the rate achieved
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.sent as f64 / secs } else { 0.0 }
+    }
+
+    fn record(&mut self, res: Result<Message, ClientError>, latency: Duration) {
+        self.sent += 1;
+        match res {
+            Ok(response) => {
+                let code = response
+                    .get_field_value(DE_ACTION_CODE)
+                    .map(|c| String::from_utf8_lossy(&c).into_owned())
+                    .unwrap_or_else(|_| "none".to_string());
+                *self.response_codes.entry(code).or_insert(0) += 1;
+                self.latencies.push(latency);
+            }
+            Err(ClientError::Timeout) => self.timeouts += 1,
+            Err(_) => self.errors += 1,
+        }
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let target = self.target_rate.map(|r| format!(" of {:.1}/s requested", r)).unwrap_or_default();
+        writeln!(
+            f,
+            "Sent {} in {:.2} s ({:.1}/s{}), {} responses, {} timeouts, {} errors",
+            self.sent,
+            self.elapsed.as_secs_f64(),
+            self.throughput(),
+            target,
+            self.latencies.len(),
+            self.timeouts,
+            self.errors
+        )?;
+        if !self.latencies.is_empty() {
+            let ms = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0).unwrap_or(0.0);
+            writeln!(
+                f,
+                "Latency ms: min {:.2} p50 {:.2} p90 {:.2} p99 {:.2} max {:.2}",
+                ms(self.percentile(0.0)),
+                ms(self.percentile(50.0)),
+                ms(self.percentile(90.0)),
+                ms(self.percentile(99.0)),
+                ms(self.percentile(100.0))
+            )?;
+        }
+        let codes: Vec<String> = self.response_codes.iter().map(|(c, n)| format!("{} {}", c, n)).collect();
+        write!(f, "Response codes: {}", codes.join(", "))
+    }
+}
+
+/// Send requests rendered from `template` until the request count or duration of
+/// `config` is reached, then wait for the responses in flight
+pub async fn run_load(client: &Client, template: &Template, config: LoadConfig) -> Result<LoadReport, String> {
+    let spec = client.get_spec();
+    // fail on an invalid template before sending anything
+    template.render(spec, "000001")?;
+    let start = Instant::now();
+    let deadline = config.duration.map(|d| start + d);
+    let remaining = AtomicUsize::new(config.requests.unwrap_or(usize::MAX));
+    let rate = config.rate.filter(|r| *r > 0.0);
+    // a slow host lowers the rate rather than causing a burst to catch up
+    let interval = rate.map(|r| {
+        let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / r));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        tokio::sync::Mutex::new(interval)
+    });
+    let report = Mutex::new(LoadReport {
+        target_rate: rate,
+        ..LoadReport::default()
+    });
+
+    let worker = || async {
+        loop {
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) ||
+                remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_err()
+            {
+                break;
+            }
+            if let Some(ref interval) = interval {
+                interval.lock().await.tick().await;
+            }
+            let request = match template.render(spec, &client.next_stan()) {
+                Ok(r) => r,
+                Err(_) => {
+                    report.lock().unwrap().errors += 1;
+                    continue;
+                }
+            };
+            let sent = Instant::now();
+            let res = client.send(request).await;
+            report.lock().unwrap().record(res, sent.elapsed());
+        }
+    };
+    futures::future::join_all((0..config.concurrency.max(1)).map(|_| worker())).await;
+
+    let mut report = report.into_inner().unwrap();
+    report.elapsed = start.elapsed();
+    report.latencies.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientConfig;
+    use crate::simulator::Simulator;
+    use crate::tests::spec;
+    use iso8583::iso_framing::{FrameFormat, LengthHeader};
+
+    const TEMPLATE: &str = r#"
+request:
+  0: "0100"
+  2: "{pan}"
+  4: "{amount}"
+  11: "{stan}"
+  37: "{rrn}"
+  41: "TERM0001"
+pans: ["4111111111111111", "4000001234567899"]
+amount: {min: 5100, max: 5199}
+"#;
+
+    #[test]
+    fn render_test() {
+        let template = Template::from_str(TEMPLATE).unwrap();
+        for _ in 0..20 {
+            let msg = template.render(spec(), "000042").unwrap();
+            let pan = msg.get_field_value(2).unwrap();
+            assert!(template.pans.iter().any(|p| p.as_bytes() == &pan[..]));
+            assert_eq!(msg.get_field_value(11), Ok(b"000042".to_vec()));
+            let amount: u64 = String::from_utf8(msg.get_field_value(4).unwrap()).unwrap().parse().unwrap();
+            assert!((5100..=5199).contains(&amount));
+            assert_eq!(msg.get_field_value(11).unwrap().len(), 6);
+            assert_eq!(msg.get_field_value(37).unwrap().len(), 12);
+        }
+        // one PAN per request
+        let template = Template::from_str("request: {2: \"{pan}\", 35: \"{pan}=2512\"}\npans: [\"4111111111111111\", \"5500005555555559\"]").unwrap();
+        for _ in 0..20 {
+            let msg = template.render(spec(), "000001").unwrap();
+            let mut track = msg.get_field_value(2).unwrap();
+            track.extend_from_slice(b"=2512");
+            assert_eq!(msg.get_field_value(35), Ok(track));
+        }
+        assert!(Template::from_str("request: {11: \"{seq}\"}").is_err());
+        assert!(Template::from_str("request: {2: \"{pan}\"}").is_err());
+        assert!(Template::from_str("request: {4: \"{amount}\"}\namount: {min: 5, max: 1}").is_err());
+    }
+
+    #[test]
+    fn percentile_test() {
+        let report = LoadReport {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..LoadReport::default()
+        };
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+        assert_eq!(LoadReport::default().percentile(50.0), None);
+    }
+
+    #[tokio::test]
+    async fn run_load_test() {
+        let format = FrameFormat::new(LengthHeader::Binary2, false, 0);
+        let sim = Simulator::from_str("rules:\n  - {match: {2: \"400000*\"}, action_code: \"05\"}\n").unwrap();
+        let host = sim.bind("127.0.0.1:0", spec(), format).await.unwrap();
+        let client = Client::connect(host.local_addr(), spec(), ClientConfig::new(format)).await.unwrap();
+        let template = Template::from_str(TEMPLATE).unwrap();
+        let config = LoadConfig {
+            concurrency: 4,
+            requests: Some(200),
+            ..LoadConfig::default()
+        };
+        let report = run_load(&client, &template, config).await.unwrap();
+        assert_eq!(report.sent, 200);
+        assert_eq!(report.latencies.len() + report.timeouts + report.errors, 200);
+        assert_eq!(report.response_codes.values().sum::<usize>(), report.latencies.len());
+        assert!(report.response_codes.keys().all(|c| c == "00" || c == "05"));
+        assert!(report.to_string().contains("Latency ms: min"));
+
+        let config = LoadConfig {
+            concurrency: 2,
+            rate: Some(200.0),
+            requests: Some(20),
+            ..LoadConfig::default()
+        };
+        let report = run_load(&client, &template, config).await.unwrap();
+        assert_eq!(report.sent, 20);
+        assert!(report.throughput() < 400.0, "{}", report);
+        assert!(report.to_string().contains("of 200.0/s requested"));
+    }
+}
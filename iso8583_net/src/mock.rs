@@ -0,0 +1,71 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! In-process host for testing clients, answering each request with a handler.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use iso8583::iso_codec::IsoCodec;
+use iso8583::iso_framing::FrameFormat;
+use iso8583::iso_msg::IsoSpecs;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::codec::Framed;
+
+use crate::Message;
+
+/// Host listening on a local port, stops accepting when dropped
+pub struct MockHost {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl MockHost {
+    /// `handler` returns the response to send, `None` to not respond
+    pub async fn bind<F>(
+        spec: &'static (dyn IsoSpecs + Sync),
+        format: FrameFormat,
+        handler: F,
+    ) -> io::Result<MockHost>
+    where
+        F: Fn(&Message) -> Option<Message> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let handler = Arc::new(handler);
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut framed = Framed::new(stream, IsoCodec::new(spec, format));
+                    while let Some(Ok(request)) = framed.next().await {
+                        if let Some(response) = (*handler)(&request) {
+                            if framed.send(response).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        Ok(MockHost { addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MockHost {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
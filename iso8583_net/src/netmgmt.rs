@@ -0,0 +1,417 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Network management (`0800`/`0810`): sign-on, sign-off, echo test and key change.
+//!
+//! The network management code is carried in DE 70 and key data in DE 96.
+//! `NetworkManager` tracks whether a client connection is signed on, answers
+//! `0800`s from the host and sends an echo test when the connection is idle.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use iso8583::iso_msg::{IsoMsg, IsoSpecs};
+use log::warn;
+use tokio::sync::mpsc;
+
+use crate::client::{Client, ClientError};
+use crate::{response_mti, Message, DE_ACTION_CODE, DE_MTI, DE_RRN, DE_STAN, DE_TERMINAL_ID};
+
+pub const DE_TRANSMISSION_DATE_TIME: usize = 7;
+pub const DE_NETWORK_CODE: usize = 70;
+pub const DE_KEY_DATA: usize = 96;
+
+pub const MTI_NETWORK_REQUEST: &[u8] = b"0800";
+
+/// Network management information code (DE 70)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetworkCode {
+    SignOn,
+    SignOff,
+    KeyChange,
+    EchoTest,
+}
+
+#[allow(clippy::should_implement_trait)]
+impl NetworkCode {
+    pub fn from_str(s: &str) -> Option<NetworkCode> {
+        match s {
+            "001" => Some(NetworkCode::SignOn),
+            "002" => Some(NetworkCode::SignOff),
+            "101" => Some(NetworkCode::KeyChange),
+            "301" => Some(NetworkCode::EchoTest),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NetworkCode::SignOn => "001",
+            NetworkCode::SignOff => "002",
+            NetworkCode::KeyChange => "101",
+            NetworkCode::EchoTest => "301",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    SignedOff,
+    SignedOn,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkError {
+    Client(ClientError),
+    /// The host responded with this action code
+    Declined(Vec<u8>),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::Client(e) => e.fmt(f),
+            NetworkError::Declined(code) => {
+                write!(f, "Declined with action code {}", String::from_utf8_lossy(code))
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+impl From<ClientError> for NetworkError {
+    fn from(e: ClientError) -> NetworkError {
+        NetworkError::Client(e)
+    }
+}
+
+/// true for the approval action codes `00`, `000` and `800` (network management)
+pub fn is_approved(action_code: &[u8]) -> bool {
+    action_code == b"00" || action_code == b"000" || action_code == b"800"
+}
+
+/// DE 7 value `MMDDhhmmss` in UTC
+pub fn transmission_date_time(now: SystemTime) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let rem = secs % 86400;
+    // civil date from days since epoch
+    let z = (secs / 86400) as i64 + 719_468;
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    format!(
+        "{:02}{:02}{:02}{:02}{:02}",
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// `0800` with DE 7 and DE 70 set, DE 11 is assigned by the client
+pub fn build_network_request(
+    spec: &'static (dyn IsoSpecs + Sync),
+    code: NetworkCode,
+) -> Result<Message, &'static str> {
+    let mut msg = IsoMsg::empty(spec);
+    msg.set_field(DE_MTI, MTI_NETWORK_REQUEST)?;
+    let date_time = transmission_date_time(SystemTime::now());
+    msg.set_field(DE_TRANSMISSION_DATE_TIME, date_time.as_bytes())?;
+    msg.set_field(DE_NETWORK_CODE, code.as_str().as_bytes())?;
+    Ok(msg)
+}
+
+/// Key change `0800` carrying `key_data` in DE 96
+pub fn build_key_change(spec: &'static (dyn IsoSpecs + Sync), key_data: &[u8]) -> Result<Message, &'static str> {
+    let mut msg = build_network_request(spec, NetworkCode::KeyChange)?;
+    msg.set_field(DE_KEY_DATA, key_data)?;
+    Ok(msg)
+}
+
+/// Fields of an `0800` echoed in its `0810`. DE 37 and DE 41 are kept when present for the
+/// client to match the response, key data (DE 53, 96) is not
+const NETWORK_RESPONSE_FIELDS: [usize; 5] = [
+    DE_TRANSMISSION_DATE_TIME,
+    DE_STAN,
+    DE_RRN,
+    DE_TERMINAL_ID,
+    DE_NETWORK_CODE,
+];
+
+/// Approved `0810` for a `0800`, `None` for other messages.
+/// Usable as the `0800` handler of a `server::Server`
+pub fn respond_network_request(request: &Message) -> Option<Message> {
+    match request.get_field_value(DE_MTI) {
+        Ok(ref mti) if mti.as_slice() == MTI_NETWORK_REQUEST => build_network_response(request).ok(),
+        _ => None,
+    }
+}
+
+fn build_network_response(request: &Message) -> Result<Message, &'static str> {
+    let mut response = IsoMsg::empty(request.get_spec());
+    response.set_field(DE_MTI, &response_mti(MTI_NETWORK_REQUEST)?)?;
+    for index in NETWORK_RESPONSE_FIELDS.iter() {
+        if request.has_field(*index) {
+            response.set_field(*index, &request.get_field_value(*index)?)?;
+        }
+    }
+    response.set_field(DE_ACTION_CODE, b"00")?;
+    Ok(response)
+}
+
+fn get_network_code(msg: &Message) -> Option<NetworkCode> {
+    let code = msg.get_field_value(DE_NETWORK_CODE).ok()?;
+    NetworkCode::from_str(std::str::from_utf8(&code).ok()?)
+}
+
+/// Network management of a client connection
+pub struct NetworkManager {
+    client: Arc<Client>,
+    state: Mutex<ConnectionState>,
+    key_data: Mutex<Option<Vec<u8>>>,
+}
+
+impl NetworkManager {
+    pub fn new(client: Arc<Client>) -> NetworkManager {
+        NetworkManager {
+            client,
+            state: Mutex::new(ConnectionState::SignedOff),
+            key_data: Mutex::new(None),
+        }
+    }
+
+    pub fn get_state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Key data of the last key change sent by the host
+    pub fn get_key_data(&self) -> Option<Vec<u8>> {
+        self.key_data.lock().unwrap().clone()
+    }
+
+    async fn send(&self, request: Message) -> Result<Message, NetworkError> {
+        let response = self.client.send(request).await?;
+        let action_code = response.get_field_value(DE_ACTION_CODE).unwrap_or_default();
+        if !is_approved(&action_code) {
+            return Err(NetworkError::Declined(action_code));
+        }
+        Ok(response)
+    }
+
+    fn request(&self, code: NetworkCode) -> Result<Message, NetworkError> {
+        build_network_request(self.client.get_spec(), code)
+            .map_err(|e| NetworkError::Client(ClientError::InvalidMessage(e)))
+    }
+
+    pub async fn sign_on(&self) -> Result<(), NetworkError> {
+        self.send(self.request(NetworkCode::SignOn)?).await?;
+        self.set_state(ConnectionState::SignedOn);
+        Ok(())
+    }
+
+    /// Signed off once sent, even if the host doesn't approve
+    pub async fn sign_off(&self) -> Result<(), NetworkError> {
+        let res = self.send(self.request(NetworkCode::SignOff)?).await;
+        self.set_state(ConnectionState::SignedOff);
+        res.map(|_| ())
+    }
+
+    pub async fn echo_test(&self) -> Result<(), NetworkError> {
+        self.send(self.request(NetworkCode::EchoTest)?).await.map(|_| ())
+    }
+
+    /// Send `key_data` to the host, returns the response which may carry a key in DE 96
+    pub async fn key_change(&self, key_data: &[u8]) -> Result<Message, NetworkError> {
+        let request = build_key_change(self.client.get_spec(), key_data)
+            .map_err(|e| NetworkError::Client(ClientError::InvalidMessage(e)))?;
+        self.send(request).await
+    }
+
+    /// Answer a `0800` from the host and apply its sign-on, sign-off or key change.
+    /// Other messages are returned to the caller
+    pub fn handle_unsolicited(&self, msg: Message) -> Option<Message> {
+        let response = match respond_network_request(&msg) {
+            Some(r) => r,
+            None => return Some(msg),
+        };
+        match get_network_code(&msg) {
+            Some(NetworkCode::SignOn) => self.set_state(ConnectionState::SignedOn),
+            Some(NetworkCode::SignOff) => self.set_state(ConnectionState::SignedOff),
+            Some(NetworkCode::KeyChange) => {
+                if let Ok(key) = msg.get_field_value(DE_KEY_DATA) {
+                    *self.key_data.lock().unwrap() = Some(key);
+                }
+            }
+            _ => {}
+        }
+        if self.client.post(response).is_err() {
+            self.set_state(ConnectionState::SignedOff);
+        }
+        None
+    }
+
+    /// Answer host `0800`s from `unsolicited` and send an echo test after
+    /// `heartbeat` of inactivity. Returns, signed off, when the connection closes
+    pub async fn run(&self, mut unsolicited: mpsc::Receiver<Message>, heartbeat: Duration) {
+        loop {
+            let idle = self.client.idle_time();
+            let wait = if idle < heartbeat { heartbeat - idle } else { Duration::from_millis(0) };
+            tokio::select! {
+                msg = unsolicited.recv() => match msg {
+                    Some(msg) => {
+                        if self.handle_unsolicited(msg).is_some() {
+                            warn!("Dropped unsolicited message");
+                        }
+                    }
+                    None => break,
+                },
+                _ = tokio::time::sleep(wait) => {
+                    if self.client.idle_time() < heartbeat {
+                        continue;
+                    }
+                    match self.echo_test().await {
+                        Ok(()) => {}
+                        Err(NetworkError::Client(ClientError::Closed)) => break,
+                        Err(e) => warn!("Echo test failed: {}", e),
+                    }
+                }
+            }
+        }
+        self.set_state(ConnectionState::SignedOff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientConfig;
+    use crate::mock::MockHost;
+    use crate::server::{Server, ServerConfig};
+    use crate::tests::spec;
+    use crate::build_response;
+    use iso8583::iso_framing::{FrameFormat, LengthHeader};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    fn format() -> FrameFormat {
+        FrameFormat::new(LengthHeader::Binary2, false, 0)
+    }
+
+    #[test]
+    fn build_network_request_test() {
+        assert_eq!(transmission_date_time(UNIX_EPOCH + Duration::from_secs(1_000_000_000)), "0909014640");
+        assert_eq!(transmission_date_time(UNIX_EPOCH + Duration::from_secs(951_825_600)), "0229120000");
+
+        let msg = build_key_change(spec(), b"\x01\x02\x03").unwrap();
+        assert_eq!(msg.get_field_value(DE_MTI), Ok(b"0800".to_vec()));
+        assert_eq!(msg.get_field_value(DE_NETWORK_CODE), Ok(b"101".to_vec()));
+        assert_eq!(msg.get_field_value(DE_KEY_DATA), Ok(b"\x01\x02\x03".to_vec()));
+        assert_eq!(get_network_code(&msg), Some(NetworkCode::KeyChange));
+        assert_eq!(NetworkCode::from_str("301"), Some(NetworkCode::EchoTest));
+
+        let mut msg = msg;
+        msg.set_field(DE_STAN, b"000123").unwrap();
+        msg.set_field(53, b"0000000000000001").unwrap();
+        let response = respond_network_request(&msg).unwrap();
+        assert_eq!(response.get_field_value(DE_MTI), Ok(b"0810".to_vec()));
+        assert_eq!(response.get_field_value(DE_STAN), Ok(b"000123".to_vec()));
+        assert_eq!(response.get_field_value(DE_NETWORK_CODE), Ok(b"101".to_vec()));
+        assert_eq!(response.get_field_value(DE_ACTION_CODE), Ok(b"00".to_vec()));
+        assert!(response.has_field(DE_TRANSMISSION_DATE_TIME));
+        assert!(!response.has_field(DE_KEY_DATA));
+        assert!(!response.has_field(53));
+        assert!(respond_network_request(&response).is_none());
+    }
+
+    #[tokio::test]
+    async fn network_manager_test() {
+        let echoes = Arc::new(AtomicUsize::new(0));
+        let counter = echoes.clone();
+        let host = MockHost::bind(spec(), format(), move |req: &Message| {
+            match get_network_code(req) {
+                Some(NetworkCode::EchoTest) => {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+                Some(NetworkCode::KeyChange) => return build_response(req, b"30").ok(),
+                _ => {}
+            }
+            respond_network_request(req)
+        }).await
+            .unwrap();
+        let client = Client::connect(host.local_addr(), spec(), ClientConfig::new(format()))
+            .await
+            .unwrap();
+        let unsolicited = client.take_unsolicited().unwrap();
+        let manager = Arc::new(NetworkManager::new(Arc::new(client)));
+
+        assert_eq!(manager.get_state(), ConnectionState::SignedOff);
+        manager.sign_on().await.unwrap();
+        assert_eq!(manager.get_state(), ConnectionState::SignedOn);
+        assert_eq!(manager.key_change(b"KEY").await.err(), Some(NetworkError::Declined(b"30".to_vec())));
+
+        let runner = manager.clone();
+        let task = tokio::spawn(async move { runner.run(unsolicited, Duration::from_millis(40)).await });
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(echoes.load(Ordering::SeqCst) >= 2);
+
+        manager.sign_off().await.unwrap();
+        assert_eq!(manager.get_state(), ConnectionState::SignedOff);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn network_manager_host_request_test() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut server = Server::new(spec(), ServerConfig::new(format()));
+        server.add_handler(b"0810", move |resp: Message| {
+            let _ = tx.send(resp);
+            async { None }
+        });
+        let handle = server.handle();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.run(listener));
+
+        let client = Client::connect(addr, spec(), ClientConfig::new(format())).await.unwrap();
+        let unsolicited = client.take_unsolicited().unwrap();
+        let manager = Arc::new(NetworkManager::new(Arc::new(client)));
+        let runner = manager.clone();
+        let task = tokio::spawn(async move { runner.run(unsolicited, Duration::from_secs(60)).await });
+
+        while handle.connections().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let mut request = build_key_change(spec(), b"NEWKEY").unwrap();
+        request.set_field(DE_STAN, b"000777").unwrap();
+        handle.connections()[0].send(request).unwrap();
+        let response = rx.recv().await.unwrap();
+        assert_eq!(response.get_field_value(DE_STAN), Ok(b"000777".to_vec()));
+        assert_eq!(response.get_field_value(DE_ACTION_CODE), Ok(b"00".to_vec()));
+        assert_eq!(manager.get_key_data(), Some(b"NEWKEY".to_vec()));
+
+        let mut request = build_network_request(spec(), NetworkCode::SignOn).unwrap();
+        request.set_field(DE_STAN, b"000778").unwrap();
+        handle.connections()[0].send(request).unwrap();
+        rx.recv().await.unwrap();
+        assert_eq!(manager.get_state(), ConnectionState::SignedOn);
+
+        handle.shutdown();
+        task.await.unwrap();
+        assert_eq!(manager.get_state(), ConnectionState::SignedOff);
+    }
+}
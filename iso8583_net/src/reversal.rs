@@ -0,0 +1,256 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Automatic reversal of requests that time out, repeated until the host acknowledges.
+//!
+//! By default `Reverser::send` returns once the reversal is acknowledged or the retry
+//! policy gives up. With `set_wait(false)` it returns the timeout as soon as the reversal
+//! is stored and repeats it in a background task.
+
+use std::sync::{Arc, Mutex};
+
+use iso8583::iso_msg::IsoMsg;
+use iso8583::iso_reversal::{build_reversal_excluding, reversal_mti, OriginalDataFormat, PendingReversal,
+                            ReversalStore, RetryPolicy, DEFAULT_EXCLUDED_FIELDS};
+use log::warn;
+
+use crate::client::{Client, ClientError};
+use crate::{Message, DE_MTI, DE_STAN};
+
+/// Sends requests through a client and reverses them on timeout
+pub struct Reverser<S> {
+    store: Mutex<S>,
+    policy: RetryPolicy,
+    format: OriginalDataFormat,
+    advice: bool,
+    excluded_fields: Vec<usize>,
+    wait: bool,
+}
+
+fn store_error(e: String) -> ClientError {
+    warn!("Reversal store failed: {}", e);
+    ClientError::InvalidMessage("Reversal store failed")
+}
+
+impl<S: ReversalStore + Send + 'static> Reverser<S> {
+    /// `advice` selects `0420`/`0421` reversals instead of `0400`/`0401`
+    pub fn new(store: S, policy: RetryPolicy, format: OriginalDataFormat, advice: bool) -> Reverser<S> {
+        Reverser {
+            store: Mutex::new(store),
+            policy,
+            format,
+            advice,
+            excluded_fields: DEFAULT_EXCLUDED_FIELDS.to_vec(),
+            wait: true,
+        }
+    }
+
+    /// Fields of the original not copied into the reversal, `DEFAULT_EXCLUDED_FIELDS` unless set
+    pub fn set_excluded_fields(&mut self, excluded_fields: &[usize]) {
+        self.excluded_fields = excluded_fields.to_vec();
+    }
+
+    /// false to return from `send` once the reversal is stored, repeating it in the background
+    pub fn set_wait(&mut self, wait: bool) {
+        self.wait = wait;
+    }
+
+    pub fn outstanding(&self) -> Vec<PendingReversal> {
+        self.store.lock().unwrap().outstanding()
+    }
+
+    /// Send `request`, if it times out send its reversal before returning `Timeout`
+    pub async fn send(self: &Arc<Self>, client: &Arc<Client>, mut request: Message) -> Result<Message, ClientError> {
+        if !request.has_field(DE_STAN) {
+            request
+                .set_field(DE_STAN, client.next_stan().as_bytes())
+                .map_err(ClientError::InvalidMessage)?;
+        }
+        let original = request.clone();
+        match client.send(request).await {
+            Err(ClientError::Timeout) => {
+                let key = self.store_reversal(&original)?;
+                if self.wait {
+                    if let Err(e) = self.retry(client, &key).await {
+                        warn!("Reversal not acknowledged: {}", e);
+                    }
+                } else {
+                    let reverser = self.clone();
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = reverser.retry(&client, &key).await {
+                            warn!("Reversal not acknowledged: {}", e);
+                        }
+                    });
+                }
+                Err(ClientError::Timeout)
+            }
+            res => res,
+        }
+    }
+
+    /// Store the reversal of `original` and send it until acknowledged
+    pub async fn reverse(&self, client: &Client, original: &Message) -> Result<Message, ClientError> {
+        let key = self.store_reversal(original)?;
+        self.retry(client, &key).await
+    }
+
+    /// Build and store the reversal of `original`, returns its store key
+    fn store_reversal(&self, original: &Message) -> Result<String, ClientError> {
+        let mti = original.get_field_value(DE_MTI).map_err(ClientError::InvalidMessage)?;
+        let mti = reversal_mti(&mti, self.advice).map_err(ClientError::InvalidMessage)?;
+        let reversal = build_reversal_excluding(original, &mti, self.format, &self.excluded_fields)
+            .map_err(ClientError::InvalidMessage)?;
+        let pending = PendingReversal::new(&reversal).map_err(ClientError::InvalidMessage)?;
+        let key = pending.key.clone();
+        self.store.lock().unwrap().insert(pending).map_err(store_error)?;
+        Ok(key)
+    }
+
+    /// Send the stored reversal `key`, as a repeat after the first attempt, until the
+    /// host responds. Returns `Timeout` once the policy gives up, the reversal stays stored
+    pub async fn retry(&self, client: &Client, key: &str) -> Result<Message, ClientError> {
+        loop {
+            let payload = {
+                let mut store = self.store.lock().unwrap();
+                let pending = match store.get(key) {
+                    Some(p) => p,
+                    None => return Err(ClientError::InvalidMessage("No such reversal")),
+                };
+                let payload = match self.policy.next_payload(&pending) {
+                    Some(p) => p,
+                    None => return Err(ClientError::Timeout),
+                };
+                store.record_attempt(key).map_err(store_error)?;
+                payload
+            };
+            IsoMsg::check_payload(client.get_spec(), &payload).map_err(ClientError::InvalidMessage)?;
+            match client.send(IsoMsg::new_owned(client.get_spec(), payload)).await {
+                Ok(response) => {
+                    self.store.lock().unwrap().acknowledge(key);
+                    return Ok(response);
+                }
+                Err(ClientError::Timeout) => tokio::time::sleep(self.policy.interval).await,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Retry every stored reversal e.g. after reconnecting
+    pub async fn retry_outstanding(&self, client: &Client) -> Result<(), ClientError> {
+        for pending in self.outstanding() {
+            match self.retry(client, &pending.key).await {
+                Err(ClientError::Closed) => return Err(ClientError::Closed),
+                Err(e) => warn!("Reversal {} not acknowledged: {}", pending.key, e),
+                Ok(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_response;
+    use crate::client::ClientConfig;
+    use crate::mock::MockHost;
+    use crate::tests::spec;
+    use iso8583::iso_framing::{FrameFormat, LengthHeader};
+    use iso8583::iso_reversal::InMemoryReversalStore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn format() -> FrameFormat {
+        FrameFormat::new(LengthHeader::Binary2, false, 0)
+    }
+
+    fn request() -> Message {
+        let mut msg = IsoMsg::empty(spec());
+        msg.set_field(DE_MTI, b"0200").unwrap();
+        msg.set_field(4, b"000000001000").unwrap();
+        msg.set_field(41, b"TERM0001").unwrap();
+        msg
+    }
+
+    async fn client(respond_to: &'static [u8], counts: Arc<[AtomicUsize; 3]>) -> (MockHost, Arc<Client>) {
+        let host = MockHost::bind(spec(), format(), move |req: &Message| {
+            let mti = req.get_field_value(DE_MTI).unwrap();
+            let i = [&b"0200"[..], b"0420", b"0421"].iter().position(|m| *m == mti.as_slice())?;
+            counts[i].fetch_add(1, Ordering::SeqCst);
+            assert_eq!(req.has_field(90), i > 0);
+            if mti.as_slice() == respond_to {
+                build_response(req, b"00").ok()
+            } else {
+                None
+            }
+        }).await
+            .unwrap();
+        let mut config = ClientConfig::new(format());
+        config.timeout = Duration::from_millis(50);
+        let client = Client::connect(host.local_addr(), spec(), config).await.unwrap();
+        (host, Arc::new(client))
+    }
+
+    #[tokio::test]
+    async fn reverse_on_timeout_test() {
+        let counts = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)]);
+        let (_host, client) = client(b"0421", counts.clone()).await;
+        let policy = RetryPolicy::new(3, Duration::from_millis(10));
+        let reverser = Arc::new(Reverser::new(InMemoryReversalStore::new(), policy, OriginalDataFormat::De90, true));
+
+        let res = reverser.send(&client, request()).await;
+        assert_eq!(res.err(), Some(ClientError::Timeout));
+        assert_eq!(counts[0].load(Ordering::SeqCst), 1);
+        assert_eq!(counts[1].load(Ordering::SeqCst), 1);
+        assert_eq!(counts[2].load(Ordering::SeqCst), 1);
+        assert!(reverser.outstanding().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reversal_gives_up_test() {
+        let counts = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)]);
+        let (_host, client) = client(b"", counts.clone()).await;
+        let policy = RetryPolicy::new(2, Duration::from_millis(10));
+        let reverser = Arc::new(Reverser::new(InMemoryReversalStore::new(), policy, OriginalDataFormat::De90, true));
+
+        assert!(reverser.send(&client, request()).await.is_err());
+        let outstanding = reverser.outstanding();
+        assert_eq!(outstanding.len(), 1);
+        assert_eq!(outstanding[0].attempts, 2);
+        assert_eq!(counts[2].load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reverse_in_background_test() {
+        let counts = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)]);
+        let (_host, client) = client(b"0421", counts.clone()).await;
+        let policy = RetryPolicy::new(3, Duration::from_millis(10));
+        let mut reverser = Reverser::new(InMemoryReversalStore::new(), policy, OriginalDataFormat::De90, true);
+        reverser.set_wait(false);
+        reverser.set_excluded_fields(&[4]);
+        let reverser = Arc::new(reverser);
+
+        let res = reverser.send(&client, request()).await;
+        assert_eq!(res.err(), Some(ClientError::Timeout));
+        let outstanding = reverser.outstanding();
+        assert_eq!(outstanding.len(), 1);
+        let reversal = IsoMsg::new(spec(), &outstanding[0].payload);
+        assert!(!reversal.has_field(4));
+
+        for _ in 0..50 {
+            if reverser.outstanding().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(reverser.outstanding().is_empty());
+        assert_eq!(counts[2].load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Certification scripts: requests and the fields expected in their responses, run
+//! against a host or an in-process handler.
+//!
+//! Scripts are YAML or JSON. Fields are given by index or by path as in
+//! `get_field_path`, binary (`b`) fields in hex. An expected value is matched
+//! exactly, as a glob if it contains `*` or `?`, or as a regex between slashes.
+//! `null` (`~`) expects the field to be absent.
+//!
+//! ```yaml
+//! name: Authorizations
+//! cases:
+//!   - name: Approved purchase
+//!     timeout_ms: 5000
+//!     request:
+//!       0: "0100"
+//!       2: "4111111111111111"
+//!       4: "000000001000"
+//!     expect:
+//!       0: "0110"
+//!       39: "00"
+//!       38: "*"
+//!       37: '/^\d{12}$/'
+//!       44: ~
+//! ```
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use iso8583::iso_field::{FieldCharType, FieldSizeType};
+use iso8583::iso_msg::{IsoMsg, IsoSpecs};
+use regex::Regex;
+use serde_yaml::Value;
+
+use crate::client::Client;
+use crate::server::Handler;
+use crate::Message;
+
+/// Expected value of a response field
+#[derive(Debug, Clone)]
+pub enum Expectation {
+    Absent,
+    Any,
+    Exact(String),
+    /// Source text and the regex it compiles to
+    Pattern(String, Regex),
+}
+
+impl Expectation {
+    pub(crate) fn parse(value: &Value) -> Result<Expectation, String> {
+        let s = match *value {
+            Value::Null => return Ok(Expectation::Absent),
+            Value::String(ref s) => s,
+            _ => return Err(format!("Expected value must be a string or null, got {:?}", value)),
+        };
+        if s == "*" {
+            return Ok(Expectation::Any);
+        }
+        let pattern = if s.len() >= 2 && s.starts_with('/') && s.ends_with('/') {
+            s[1..s.len() - 1].to_string()
+        } else if s.contains('*') || s.contains('?') {
+            let glob: String = s
+                .chars()
+                .map(|c| match c {
+                    '*' => ".*".to_string(),
+                    '?' => ".".to_string(),
+                    c => regex::escape(&c.to_string()),
+                })
+                .collect();
+            format!("^{}$", glob)
+        } else {
+            return Ok(Expectation::Exact(s.clone()));
+        };
+        let re = Regex::new(&pattern).map_err(|e| format!("Invalid pattern {}: {}", s, e))?;
+        Ok(Expectation::Pattern(s.clone(), re))
+    }
+
+    pub fn matches(&self, actual: Option<&str>) -> bool {
+        self.check("", actual).is_none()
+    }
+
+    /// Failure description, `None` if `actual` matches
+    fn check(&self, path: &str, actual: Option<&str>) -> Option<String> {
+        match (self, actual) {
+            (Expectation::Absent, None) | (Expectation::Any, Some(_)) => None,
+            (Expectation::Absent, Some(v)) => Some(format!("{}: expected absent, got \"{}\"", path, v)),
+            (Expectation::Exact(e), Some(v)) if e == v => None,
+            (Expectation::Exact(e), Some(v)) => Some(format!("{}: expected \"{}\", got \"{}\"", path, e, v)),
+            (Expectation::Pattern(_, re), Some(v)) if re.is_match(v) => None,
+            (Expectation::Pattern(p, _), Some(v)) => Some(format!("{}: expected {}, got \"{}\"", path, p, v)),
+            (Expectation::Exact(e), None) | (Expectation::Pattern(e, _), None) => {
+                Some(format!("{}: expected \"{}\", field absent", path, e))
+            }
+            (Expectation::Any, None) => Some(format!("{}: expected a value, field absent", path)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub name: String,
+    /// Overrides the client timeout
+    pub timeout: Option<Duration>,
+    /// Field path and value, in order
+    pub request: Vec<(String, String)>,
+    pub expect: Vec<(String, Expectation)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Script {
+    pub name: String,
+    pub cases: Vec<Case>,
+}
+
+pub(crate) fn get<'v>(value: &'v Value, key: &str) -> Option<&'v Value> {
+    match *value {
+        Value::Mapping(ref m) => m.get(&Value::String(key.to_string())),
+        _ => None,
+    }
+}
+
+fn key_to_path(key: &Value) -> Result<String, String> {
+    match *key {
+        Value::Number(ref n) => Ok(n.to_string()),
+        Value::String(ref s) => Ok(s.clone()),
+        _ => Err(format!("Invalid field path {:?}", key)),
+    }
+}
+
+pub(crate) fn parse_fields<T, F>(value: Option<&Value>, context: &str, parse: F) -> Result<Vec<(String, T)>, String>
+where
+    F: Fn(&Value) -> Result<T, String>,
+{
+    let mapping = match value {
+        Some(Value::Mapping(m)) => m,
+        None | Some(Value::Null) => return Ok(Vec::new()),
+        _ => return Err(format!("{}: fields must be a mapping", context)),
+    };
+    mapping
+        .iter()
+        .map(|(k, v)| {
+            let path = key_to_path(k)?;
+            let value = parse(v).map_err(|e| format!("{}, field {}: {}", context, path, e))?;
+            Ok((path, value))
+        })
+        .collect()
+}
+
+impl Script {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(text: &str) -> Result<Script, String> {
+        let doc: Value = serde_yaml::from_str(text).map_err(|e| format!("Invalid script: {}", e))?;
+        let name = match get(&doc, "name") {
+            Some(Value::String(s)) => s.clone(),
+            _ => String::new(),
+        };
+        let cases = match get(&doc, "cases") {
+            Some(Value::Sequence(s)) => s,
+            _ => return Err("Script has no cases".to_string()),
+        };
+        let mut res = Vec::with_capacity(cases.len());
+        for (i, c) in cases.iter().enumerate() {
+            let name = match get(c, "name") {
+                Some(Value::String(s)) => s.clone(),
+                _ => format!("#{}", i + 1),
+            };
+            let timeout = match get(c, "timeout_ms") {
+                Some(Value::Number(n)) => n.as_u64().map(Duration::from_millis),
+                _ => None,
+            };
+            let context = format!("Case {}", name);
+            let request = parse_fields(get(c, "request"), &context, |v| match *v {
+                Value::String(ref s) => Ok(s.clone()),
+                _ => Err("value must be a string".to_string()),
+            })?;
+            if request.is_empty() {
+                return Err(format!("Case {} has no request", name));
+            }
+            let expect = parse_fields(get(c, "expect"), &context, Expectation::parse)?;
+            res.push(Case { name, timeout, request, expect });
+        }
+        Ok(Script { name, cases: res })
+    }
+
+    pub fn load(path: &str) -> Result<Script, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Script::from_str(&text)
+    }
+}
+
+/// Where the requests of a script are sent
+pub enum Target<'a> {
+    Host(&'a Client),
+    Handler(&'a dyn Handler),
+}
+
+#[derive(Debug, Clone)]
+pub struct CaseResult {
+    pub name: String,
+    pub elapsed: Duration,
+    /// Empty if the case passed
+    pub failures: Vec<String>,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub name: String,
+    pub results: Vec<CaseResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.passed()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.name.is_empty() {
+            writeln!(f, "{}", self.name)?;
+        }
+        for r in &self.results {
+            let status = if r.passed() { "PASS" } else { "FAIL" };
+            writeln!(f, "{}  {} ({} ms)", status, r.name, r.elapsed.as_millis())?;
+            for failure in &r.failures {
+                writeln!(f, "      {}", failure)?;
+            }
+        }
+        write!(f, "{} passed, {} failed", self.passed(), self.failed())
+    }
+}
+
+fn is_binary(spec: &dyn IsoSpecs, path: &str) -> bool {
+    !path.contains('.') &&
+        path.parse::<usize>()
+            .ok()
+            .and_then(|i| spec.get_handle().get(i))
+            .map(|f| f.char_type == FieldCharType::Iso8583_b)
+            .unwrap_or(false)
+}
+
+/// Set the field at `path`, binary fields are given in hex
+pub(crate) fn set_field_text(msg: &mut Message, path: &str, value: &str) -> Result<(), String> {
+    let bytes = if is_binary(&msg.get_spec(), path) {
+        hex::decode(value).map_err(|_| format!("{}: value must be hex", path))?
+    } else {
+        value.as_bytes().to_vec()
+    };
+    if let Some(iso_field) = path.parse::<usize>().ok().and_then(|i| msg.get_spec().get_handle().get(i)) {
+        if iso_field.size_type == FieldSizeType::Fixed && bytes.len() != iso_field.length {
+            return Err(format!("{}: value must be {} long", path, iso_field.length));
+        }
+    }
+    msg.set_field_path(path, &bytes).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn build_request(spec: &'static (dyn IsoSpecs + Sync), fields: &[(String, String)]) -> Result<Message, String> {
+    let mut msg = IsoMsg::empty(spec);
+    for (path, value) in fields {
+        set_field_text(&mut msg, path, value)?;
+    }
+    Ok(msg)
+}
+
+/// Value of the field at `path`, binary fields in hex
+pub(crate) fn field_text(msg: &Message, path: &str) -> Option<String> {
+    let value = msg.get_field_path_value(path).ok()?;
+    if is_binary(&msg.get_spec(), path) {
+        Some(hex::encode_upper(value))
+    } else {
+        Some(String::from_utf8_lossy(&value).into_owned())
+    }
+}
+
+async fn exchange(target: &Target<'_>, request: Message, timeout: Option<Duration>) -> Result<Message, String> {
+    let response = async {
+        match target {
+            Target::Host(client) => client.send(request).await.map_err(|e| e.to_string()),
+            Target::Handler(handler) => handler.handle(request).await.ok_or_else(|| "No response".to_string()),
+        }
+    };
+    match timeout {
+        Some(t) => tokio::time::timeout(t, response).await.unwrap_or_else(|_| Err("Timeout".to_string())),
+        None => response.await,
+    }
+}
+
+pub async fn run_case(case: &Case, spec: &'static (dyn IsoSpecs + Sync), target: &Target<'_>) -> CaseResult {
+    let start = Instant::now();
+    let failures = match build_request(spec, &case.request) {
+        Err(e) => vec![format!("Invalid request: {}", e)],
+        Ok(request) => match exchange(target, request, case.timeout).await {
+            Err(e) => vec![format!("No response: {}", e)],
+            Ok(response) => case.expect
+                .iter()
+                .filter_map(|(path, e)| e.check(path, field_text(&response, path).as_deref()))
+                .collect(),
+        },
+    };
+    CaseResult {
+        name: case.name.clone(),
+        elapsed: start.elapsed(),
+        failures,
+    }
+}
+
+/// Run the cases in order
+pub async fn run_script(script: &Script, spec: &'static (dyn IsoSpecs + Sync), target: Target<'_>) -> Report {
+    let mut results = Vec::with_capacity(script.cases.len());
+    for case in &script.cases {
+        results.push(run_case(case, spec, &target).await);
+    }
+    Report {
+        name: script.name.clone(),
+        results,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_response;
+    use crate::client::ClientConfig;
+    use crate::mock::MockHost;
+    use crate::tests::spec;
+    use iso8583::iso_framing::{FrameFormat, LengthHeader};
+
+    const SCRIPT: &str = r#"
+name: Authorizations
+cases:
+  - name: Approved
+    request:
+      0: "0100"
+      2: "4111111111111111"
+      4: "000000001000"
+      11: "000001"
+    expect:
+      0: "0110"
+      39: "00"
+      38: "*"
+      2: "4111*"
+      11: '/^\d{6}$/'
+      44: ~
+  - name: Insufficient funds
+    request:
+      0: "0100"
+      4: "000000001051"
+    expect:
+      39: "00"
+      38: ~
+      41: "*"
+"#;
+
+    fn respond(req: &Message) -> Option<Message> {
+        let amount = req.get_field_value(4).ok()?;
+        let code: &[u8] = if amount.ends_with(b"51") { b"51" } else { b"00" };
+        let mut response = build_response(req, code).ok()?;
+        response.set_field(38, b"ABC123").ok()?;
+        Some(response)
+    }
+
+    fn check_report(report: &Report) {
+        assert_eq!(report.passed(), 1);
+        assert_eq!(report.failed(), 1);
+        assert!(report.results[0].passed(), "{}", report);
+        assert_eq!(
+            report.results[1].failures,
+            [
+                "39: expected \"00\", got \"51\"",
+                "38: expected absent, got \"ABC123\"",
+                "41: expected a value, field absent",
+            ]
+        );
+        assert!(report.to_string().ends_with("1 passed, 1 failed"));
+    }
+
+    #[tokio::test]
+    async fn handler_script_test() {
+        let script = Script::from_str(SCRIPT).unwrap();
+        assert_eq!(script.cases.len(), 2);
+        let handler = |req: Message| async move { respond(&req) };
+        let report = run_script(&script, spec(), Target::Handler(&handler)).await;
+        check_report(&report);
+    }
+
+    #[tokio::test]
+    async fn host_script_test() {
+        let format = FrameFormat::new(LengthHeader::Binary2, false, 0);
+        let host = MockHost::bind(spec(), format, respond).await.unwrap();
+        let client = Client::connect(host.local_addr(), spec(), ClientConfig::new(format)).await.unwrap();
+        let report = run_script(&Script::from_str(SCRIPT).unwrap(), spec(), Target::Host(&client)).await;
+        check_report(&report);
+    }
+
+    #[tokio::test]
+    async fn invalid_request_test() {
+        let script = Script::from_str(
+            "cases:\n  - request:\n      0: \"0100\"\n      11: \"1234567\"\n  - request:\n      2: \"41111111111111111111\"\n",
+        )
+        .unwrap();
+        let handler = |req: Message| async move { respond(&req) };
+        let report = run_script(&script, spec(), Target::Handler(&handler)).await;
+        assert_eq!(report.failed(), 2);
+        assert_eq!(report.results[0].failures, ["Invalid request: 11: value must be 6 long"]);
+        assert_eq!(report.results[1].failures, ["Invalid request: 2: Field value exceeds max length"]);
+    }
+
+    #[test]
+    fn invalid_script_test() {
+        assert!(Script::from_str("name: x").is_err());
+        assert!(Script::from_str("cases:\n  - name: a\n").is_err());
+        assert!(Script::from_str("cases:\n  - request:\n      0: 100\n").is_err());
+        assert!(Script::from_str("cases:\n  - request:\n      0: \"0100\"\n    expect:\n      39: '/[/'\n").is_err());
+    }
+}
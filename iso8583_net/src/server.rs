@@ -0,0 +1,402 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Async host accepting framed connections and dispatching requests to handlers by MTI.
+//!
+//! Each request runs in its own task so a slow handler doesn't hold up the
+//! connection. `ServerHandle` sends unsolicited messages (e.g. a host `0800`) to
+//! connected peers and shuts the server down: it stops accepting and reading,
+//! then waits for in-flight handlers to write their responses.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use iso8583::iso_codec::IsoCodec;
+use iso8583::iso_framing::FrameFormat;
+use iso8583::iso_msg::IsoSpecs;
+use log::{trace, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio_util::codec::Framed;
+
+use crate::{Message, DE_MTI};
+
+/// Pause after a failed accept e.g. when out of file descriptors, before trying again
+pub const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Option<Message>> + Send>>;
+
+/// What the connection does once a request is handled
+#[derive(Clone)]
+pub enum Outcome {
+    Respond(Message),
+    NoResponse,
+    /// Close the connection without waiting for other requests in flight
+    Close,
+}
+
+pub type OutcomeFuture = Pin<Box<dyn Future<Output = Outcome> + Send>>;
+
+/// Handles a request, resolving to the response or `None` to not respond
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: Message) -> HandlerFuture;
+
+    /// Called by the server, override to close the connection
+    fn handle_outcome(&self, request: Message) -> OutcomeFuture {
+        let response = self.handle(request);
+        Box::pin(async move {
+            match response.await {
+                Some(msg) => Outcome::Respond(msg),
+                None => Outcome::NoResponse,
+            }
+        })
+    }
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<Message>> + Send + 'static,
+{
+    fn handle(&self, request: Message) -> HandlerFuture {
+        Box::pin(self(request))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ServerConfig {
+    pub format: FrameFormat,
+}
+
+impl ServerConfig {
+    pub fn new(format: FrameFormat) -> ServerConfig {
+        ServerConfig { format }
+    }
+}
+
+/// Sends messages to a connected peer
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    id: u64,
+    peer: SocketAddr,
+    tx: mpsc::UnboundedSender<Message>,
+}
+
+impl ConnectionHandle {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Queue an unsolicited message, its response is dispatched to the handler of its MTI
+    pub fn send(&self, msg: Message) -> Result<(), &'static str> {
+        self.tx.send(msg).map_err(|_| "Connection closed")
+    }
+}
+
+type Connections = Arc<Mutex<HashMap<u64, ConnectionHandle>>>;
+
+/// Controls a running server
+#[derive(Clone)]
+pub struct ServerHandle {
+    shutdown: Arc<watch::Sender<bool>>,
+    connections: Connections,
+}
+
+impl ServerHandle {
+    pub fn connections(&self) -> Vec<ConnectionHandle> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Stop accepting and reading, `Server::run` returns once pending responses are written
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+    }
+}
+
+struct Router {
+    handlers: HashMap<Vec<u8>, Arc<dyn Handler>>,
+    default_handler: Option<Arc<dyn Handler>>,
+}
+
+impl Router {
+    fn get_handler(&self, request: &Message) -> Option<Arc<dyn Handler>> {
+        let mti = request.get_field_value(DE_MTI).ok()?;
+        self.handlers
+            .get(&mti)
+            .or(self.default_handler.as_ref())
+            .cloned()
+    }
+}
+
+pub struct Server {
+    spec: &'static (dyn IsoSpecs + Sync),
+    config: ServerConfig,
+    router: Router,
+    handle: ServerHandle,
+    next_id: AtomicU64,
+}
+
+impl Server {
+    pub fn new(spec: &'static (dyn IsoSpecs + Sync), config: ServerConfig) -> Server {
+        let (shutdown, _) = watch::channel(false);
+        Server {
+            spec,
+            config,
+            router: Router {
+                handlers: HashMap::new(),
+                default_handler: None,
+            },
+            handle: ServerHandle {
+                shutdown: Arc::new(shutdown),
+                connections: Connections::default(),
+            },
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Handle requests with MTI `mti` e.g. `b"0100"`
+    pub fn add_handler<H: Handler>(&mut self, mti: &[u8], handler: H) {
+        self.router.handlers.insert(mti.to_vec(), Arc::new(handler));
+    }
+
+    /// Handle requests with no handler for their MTI, these are dropped otherwise
+    pub fn set_default_handler<H: Handler>(&mut self, handler: H) {
+        self.router.default_handler = Some(Arc::new(handler));
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Accept connections until shutdown
+    pub async fn run(self, listener: TcpListener) -> io::Result<()> {
+        let router = Arc::new(self.router);
+        let mut shutdown = self.handle.shutdown.subscribe();
+        let mut tasks = JoinSet::new();
+        while !*shutdown.borrow() {
+            tokio::select! {
+                _ = shutdown.changed() => {}
+                // reap finished connections
+                Some(_) = tasks.join_next() => {}
+                res = listener.accept() => {
+                    let (stream, peer) = match res {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!("Failed to accept connection: {}", e);
+                            tokio::select! {
+                                _ = shutdown.changed() => {}
+                                _ = tokio::time::sleep(ACCEPT_ERROR_DELAY) => {}
+                            }
+                            continue;
+                        }
+                    };
+                    let codec = IsoCodec::new(self.spec, self.config.format);
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    let conn = ConnectionHandle {
+                        id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                        peer,
+                        tx,
+                    };
+                    self.handle.connections.lock().unwrap().insert(conn.id, conn.clone());
+                    tasks.spawn(run_connection(
+                        Framed::new(stream, codec),
+                        conn,
+                        rx,
+                        router.clone(),
+                        self.handle.connections.clone(),
+                        shutdown.clone(),
+                    ));
+                }
+            }
+        }
+        drop(listener);
+        while tasks.join_next().await.is_some() {}
+        Ok(())
+    }
+}
+
+async fn run_connection(
+    framed: Framed<TcpStream, IsoCodec<'static>>,
+    conn: ConnectionHandle,
+    mut rx: mpsc::UnboundedReceiver<Message>,
+    router: Arc<Router>,
+    connections: Connections,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (mut sink, mut stream) = framed.split();
+    // `None` closes the connection
+    let (response_tx, mut response_rx) = mpsc::unbounded_channel::<Option<Message>>();
+    let mut closed = false;
+    while !*shutdown.borrow() {
+        tokio::select! {
+            _ = shutdown.changed() => {}
+            incoming = stream.next() => match incoming {
+                Some(Ok(request)) => {
+                    let handler = match router.get_handler(&request) {
+                        Some(h) => h,
+                        None => {
+                            warn!("No handler for request from {}", conn.peer);
+                            continue;
+                        }
+                    };
+                    let tx = response_tx.clone();
+                    tokio::spawn(async move {
+                        match handler.handle_outcome(request).await {
+                            Outcome::Respond(response) => {
+                                let _ = tx.send(Some(response));
+                            }
+                            Outcome::NoResponse => {}
+                            Outcome::Close => {
+                                let _ = tx.send(None);
+                            }
+                        }
+                    });
+                }
+                Some(Err(e)) => {
+                    warn!("Failed to read message from {}: {}", conn.peer, e);
+                    break;
+                }
+                None => break,
+            },
+            Some(outgoing) = response_rx.recv() => match outgoing {
+                Some(outgoing) => {
+                    if let Err(e) = sink.send(outgoing).await {
+                        warn!("Failed to send message to {}: {}", conn.peer, e);
+                        break;
+                    }
+                }
+                None => {
+                    closed = true;
+                    break;
+                }
+            },
+            Some(outgoing) = rx.recv() => {
+                if let Err(e) = sink.send(outgoing).await {
+                    warn!("Failed to send message to {}: {}", conn.peer, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    // write the responses of in-flight handlers, `response_rx` ends once they are done
+    connections.lock().unwrap().remove(&conn.id);
+    drop(rx);
+    drop(response_tx);
+    if !closed {
+        while let Some(Some(outgoing)) = response_rx.recv().await {
+            if sink.send(outgoing).await.is_err() {
+                break;
+            }
+        }
+    }
+    trace!("Connection closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_response;
+    use crate::client::{Client, ClientConfig};
+    use crate::tests::spec;
+    use crate::{DE_ACTION_CODE, DE_STAN, DE_TERMINAL_ID};
+    use iso8583::iso_framing::LengthHeader;
+    use iso8583::iso_msg::IsoMsg;
+    use tokio::sync::oneshot;
+
+    fn format() -> FrameFormat {
+        FrameFormat::new(LengthHeader::Ascii4, false, 0)
+    }
+
+    fn request(mti: &[u8]) -> Message {
+        let mut msg = IsoMsg::empty(spec());
+        msg.set_field(DE_MTI, mti).unwrap();
+        msg.set_field(DE_TERMINAL_ID, b"TERM0001").unwrap();
+        msg
+    }
+
+    #[tokio::test]
+    async fn server_dispatch_test() {
+        let mut server = Server::new(spec(), ServerConfig::new(format()));
+        server.add_handler(b"0100", |req: Message| async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            build_response(&req, b"00").ok()
+        });
+        server.add_handler(b"0200", |req: Message| async move {
+            build_response(&req, b"51").ok()
+        });
+        let handle = server.handle();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(server.run(listener));
+
+        let config = ClientConfig::new(format());
+        let a = Client::connect(addr, spec(), config).await.unwrap();
+        let b = Client::connect(addr, spec(), config).await.unwrap();
+        let (r1, r2, r3) = tokio::join!(
+            a.send(request(b"0100")),
+            a.send(request(b"0200")),
+            b.send(request(b"0100"))
+        );
+        assert_eq!(r1.unwrap().get_field_value(DE_ACTION_CODE), Ok(b"00".to_vec()));
+        assert_eq!(r2.unwrap().get_field_value(DE_ACTION_CODE), Ok(b"51".to_vec()));
+        assert_eq!(r3.unwrap().get_field_value(DE_MTI), Ok(b"0110".to_vec()));
+        assert_eq!(handle.connections().len(), 2);
+
+        let mut unsolicited = a.take_unsolicited().unwrap();
+        let mut echo = request(b"0800");
+        echo.set_field(DE_STAN, b"900001").unwrap();
+        for conn in handle.connections() {
+            conn.send(build_response(&echo, b"00").unwrap()).unwrap();
+        }
+        let msg = unsolicited.recv().await.unwrap();
+        assert_eq!(msg.get_field_value(DE_MTI), Ok(b"0810".to_vec()));
+
+        handle.shutdown();
+        task.await.unwrap().unwrap();
+        assert!(a.send(request(b"0200")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn server_graceful_shutdown_test() {
+        let (started_tx, started_rx) = oneshot::channel();
+        let started_tx = Mutex::new(Some(started_tx));
+        let mut server = Server::new(spec(), ServerConfig::new(format()));
+        server.set_default_handler(move |req: Message| {
+            if let Some(tx) = started_tx.lock().unwrap().take() {
+                let _ = tx.send(());
+            }
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                build_response(&req, b"00").ok()
+            }
+        });
+        let handle = server.handle();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(server.run(listener));
+
+        let client = Client::connect(addr, spec(), ClientConfig::new(format())).await.unwrap();
+        let pending = tokio::spawn(async move { client.send(request(b"0200")).await });
+        started_rx.await.unwrap();
+        handle.shutdown();
+
+        let response = pending.await.unwrap().unwrap();
+        assert_eq!(response.get_field_value(DE_MTI), Ok(b"0210".to_vec()));
+        task.await.unwrap().unwrap();
+        assert!(handle.connections().is_empty());
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Issuer simulator: answers requests according to rules, to exercise approvals,
+//! declines, timeouts and reversals without a real host.
+//!
+//! The first rule whose conditions match the request applies, conditions use the
+//! expected value syntax of `script`. A rule may set the action code, set response
+//! fields, delay the reply, not respond or drop the connection.
+//!
+//! ```yaml
+//! action_code: "00"
+//! set:
+//!   38: "123456"
+//! rules:
+//!   - name: Insufficient funds
+//!     match: {4: "*51"}
+//!     action_code: "51"
+//!   - name: Blocked BIN
+//!     match: {0: "0100", 2: "400000*"}
+//!     action_code: "05"
+//!   - name: Slow
+//!     match: {4: "*98"}
+//!     delay_ms: 5000
+//!   - name: Timeout
+//!     match: {4: "*99"}
+//!     action: no_response
+//!   - name: Disconnect
+//!     match: {41: "DROP*"}
+//!     action: drop
+//! ```
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use iso8583::iso_framing::FrameFormat;
+use iso8583::iso_msg::IsoSpecs;
+use log::{debug, warn};
+use serde_yaml::Value;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::task::JoinHandle;
+
+use crate::script::{field_text, get, parse_fields, set_field_text, Expectation};
+use crate::server::{Handler, HandlerFuture, Outcome, OutcomeFuture, Server, ServerConfig};
+use crate::{build_response, Message};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    Respond,
+    /// Let the client time out
+    NoResponse,
+    /// Close the connection
+    Drop,
+}
+
+impl Action {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<Action> {
+        match s {
+            "respond" => Some(Action::Respond),
+            "no_response" => Some(Action::NoResponse),
+            "drop" => Some(Action::Drop),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Action::Respond => "respond",
+            Action::NoResponse => "no_response",
+            Action::Drop => "drop",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    /// Field path and expected value, all must match
+    pub conditions: Vec<(String, Expectation)>,
+    pub action: Action,
+    /// Overrides the default action code
+    pub action_code: Option<String>,
+    /// Response fields, after the defaults
+    pub fields: Vec<(String, String)>,
+    pub delay: Option<Duration>,
+}
+
+impl Rule {
+    pub fn matches(&self, request: &Message) -> bool {
+        self.conditions
+            .iter()
+            .all(|(path, e)| e.matches(field_text(request, path).as_deref()))
+    }
+}
+
+/// What the simulator does with a request
+pub struct Reply {
+    /// Name of the rule applied, `None` for the defaults
+    pub rule: Option<String>,
+    pub action: Action,
+    pub delay: Option<Duration>,
+    /// `None` if the request cannot be answered e.g. it is a response
+    pub response: Option<Message>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    /// Action code of requests no rule matches
+    pub action_code: String,
+    /// Fields set in every response
+    pub fields: Vec<(String, String)>,
+    pub rules: Vec<Rule>,
+}
+
+fn parse_string(value: &Value) -> Result<String, String> {
+    match *value {
+        Value::String(ref s) => Ok(s.clone()),
+        _ => Err("value must be a string".to_string()),
+    }
+}
+
+impl Default for Simulator {
+    /// Approves every request
+    fn default() -> Simulator {
+        Simulator {
+            action_code: "00".to_string(),
+            fields: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Simulator {
+    /// Rules from YAML or JSON
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(text: &str) -> Result<Simulator, String> {
+        let doc: Value = serde_yaml::from_str(text).map_err(|e| format!("Invalid rules: {}", e))?;
+        let mut sim = Simulator::default();
+        if let Some(v) = get(&doc, "action_code") {
+            sim.action_code = parse_string(v).map_err(|e| format!("action_code: {}", e))?;
+        }
+        sim.fields = parse_fields(get(&doc, "set"), "Defaults", parse_string)?;
+        let rules = match get(&doc, "rules") {
+            Some(Value::Sequence(s)) => &s[..],
+            None | Some(Value::Null) => &[],
+            _ => return Err("rules must be a sequence".to_string()),
+        };
+        for (i, r) in rules.iter().enumerate() {
+            let name = match get(r, "name") {
+                Some(Value::String(s)) => s.clone(),
+                _ => format!("#{}", i + 1),
+            };
+            let context = format!("Rule {}", name);
+            let action = match get(r, "action") {
+                None => Action::Respond,
+                Some(Value::String(s)) => {
+                    Action::from_str(s).ok_or(format!("{}: unknown action {}", context, s))?
+                }
+                Some(v) => return Err(format!("{}: invalid action {:?}", context, v)),
+            };
+            let action_code = match get(r, "action_code") {
+                Some(v) => Some(parse_string(v).map_err(|e| format!("{}, action_code: {}", context, e))?),
+                None => None,
+            };
+            let delay = match get(r, "delay_ms") {
+                Some(Value::Number(n)) => n.as_u64().map(Duration::from_millis),
+                _ => None,
+            };
+            sim.rules.push(Rule {
+                conditions: parse_fields(get(r, "match"), &context, Expectation::parse)?,
+                fields: parse_fields(get(r, "set"), &context, parse_string)?,
+                name,
+                action,
+                action_code,
+                delay,
+            });
+        }
+        Ok(sim)
+    }
+
+    pub fn load(path: &str) -> Result<Simulator, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Simulator::from_str(&text)
+    }
+
+    /// First rule matching `request`
+    pub fn find_rule(&self, request: &Message) -> Option<&Rule> {
+        self.rules.iter().find(|r| r.matches(request))
+    }
+
+    pub fn reply(&self, request: &Message) -> Reply {
+        let rule = self.find_rule(request);
+        let action = rule.map(|r| r.action).unwrap_or(Action::Respond);
+        let response = if action == Action::Respond {
+            match self.build(request, rule) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    warn!("Failed to build response: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Reply {
+            rule: rule.map(|r| r.name.clone()),
+            action,
+            delay: rule.and_then(|r| r.delay),
+            response,
+        }
+    }
+
+    fn build(&self, request: &Message, rule: Option<&Rule>) -> Result<Message, String> {
+        let action_code = rule.and_then(|r| r.action_code.as_ref()).unwrap_or(&self.action_code);
+        let mut response = build_response(request, action_code.as_bytes())?;
+        let rule_fields = rule.map(|r| &r.fields[..]).unwrap_or(&[]);
+        for (path, value) in self.fields.iter().chain(rule_fields) {
+            set_field_text(&mut response, path, value)?;
+        }
+        Ok(response)
+    }
+
+    /// Listen on `addr` with a `Server`, connections are closed when the returned host is dropped
+    pub async fn bind<A: ToSocketAddrs>(
+        self,
+        addr: A,
+        spec: &'static (dyn IsoSpecs + Sync),
+        format: FrameFormat,
+    ) -> io::Result<SimulatorHost> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let mut server = Server::new(spec, ServerConfig::new(format));
+        server.set_default_handler(self);
+        let task = tokio::spawn(async move {
+            if let Err(e) = server.run(listener).await {
+                warn!("Simulator stopped: {}", e);
+            }
+        });
+        Ok(SimulatorHost { addr, task })
+    }
+}
+
+impl Handler for Simulator {
+    fn handle(&self, request: Message) -> HandlerFuture {
+        let outcome = self.handle_outcome(request);
+        Box::pin(async move {
+            match outcome.await {
+                Outcome::Respond(response) => Some(response),
+                _ => None,
+            }
+        })
+    }
+
+    fn handle_outcome(&self, request: Message) -> OutcomeFuture {
+        let reply = self.reply(&request);
+        debug!("Rule {}: {}", reply.rule.as_deref().unwrap_or("default"), reply.action.as_str());
+        Box::pin(async move {
+            if let Some(delay) = reply.delay {
+                tokio::time::sleep(delay).await;
+            }
+            match (reply.action, reply.response) {
+                (Action::Respond, Some(response)) => Outcome::Respond(response),
+                (Action::Drop, _) => Outcome::Close,
+                _ => Outcome::NoResponse,
+            }
+        })
+    }
+}
+
+/// Simulator listening on a port
+pub struct SimulatorHost {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl SimulatorHost {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for SimulatorHost {
+    /// Stops the server, which aborts its connections
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientConfig, ClientError};
+    use crate::tests::spec;
+    use crate::DE_ACTION_CODE;
+    use iso8583::iso_framing::LengthHeader;
+    use iso8583::iso_msg::IsoMsg;
+
+    const RULES: &str = r#"
+set:
+  38: "123456"
+rules:
+  - name: Insufficient funds
+    match: {4: "*51"}
+    action_code: "51"
+  - name: Blocked BIN
+    match: {0: "0100", 2: "400000*"}
+    action_code: "05"
+    set: {38: "000000"}
+  - name: Timeout
+    match: {4: "*99"}
+    action: no_response
+  - name: Disconnect
+    match: {4: "*97"}
+    delay_ms: 20
+    action: drop
+"#;
+
+    fn request(pan: &str, amount: &str) -> Message {
+        let mut msg = IsoMsg::empty(spec());
+        msg.set_field(0, b"0100").unwrap();
+        msg.set_field(2, pan.as_bytes()).unwrap();
+        msg.set_field(4, amount.as_bytes()).unwrap();
+        msg.set_field(11, b"000001").unwrap();
+        msg
+    }
+
+    #[test]
+    fn reply_test() {
+        let sim = Simulator::from_str(RULES).unwrap();
+        let reply = sim.reply(&request("4111111111111111", "000000001000"));
+        assert_eq!(reply.rule, None);
+        let response = reply.response.unwrap();
+        assert_eq!(response.get_field_value(DE_ACTION_CODE), Ok(b"00".to_vec()));
+        assert_eq!(response.get_field_value(38), Ok(b"123456".to_vec()));
+
+        let reply = sim.reply(&request("4111111111111111", "000000001051"));
+        assert_eq!(reply.rule.as_deref(), Some("Insufficient funds"));
+        assert_eq!(reply.response.unwrap().get_field_value(DE_ACTION_CODE), Ok(b"51".to_vec()));
+
+        let response = sim.reply(&request("4000001234567899", "000000001000")).response.unwrap();
+        assert_eq!(response.get_field_value(DE_ACTION_CODE), Ok(b"05".to_vec()));
+        assert_eq!(response.get_field_value(38), Ok(b"000000".to_vec()));
+
+        let reply = sim.reply(&request("4111111111111111", "000000001099"));
+        assert_eq!(reply.action, Action::NoResponse);
+        assert!(reply.response.is_none());
+        let reply = sim.reply(&request("4111111111111111", "000000001097"));
+        assert_eq!((reply.action, reply.delay), (Action::Drop, Some(Duration::from_millis(20))));
+
+        assert!(Simulator::from_str("rules:\n  - action: hang\n").is_err());
+        assert!(Simulator::from_str("action_code: 51\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn host_test() {
+        let format = FrameFormat::new(LengthHeader::Binary2, false, 0);
+        let sim = Simulator::from_str(RULES).unwrap();
+        let host = sim.bind("127.0.0.1:0", spec(), format).await.unwrap();
+        let mut config = ClientConfig::new(format);
+        config.timeout = Duration::from_millis(200);
+        let client = Client::connect(host.local_addr(), spec(), config).await.unwrap();
+
+        let response = client.send(request("4111111111111111", "000000001051")).await.unwrap();
+        assert_eq!(response.get_field_value(DE_ACTION_CODE), Ok(b"51".to_vec()));
+        let res = client.send(request("4111111111111111", "000000001099")).await;
+        assert_eq!(res.err(), Some(ClientError::Timeout));
+        let res = client.send(request("4111111111111111", "000000001097")).await;
+        assert_eq!(res.err(), Some(ClientError::Closed));
+
+        // dropping the host closes open connections too
+        let client = Client::connect(host.local_addr(), spec(), config).await.unwrap();
+        assert!(client.send(request("4111111111111111", "000000001000")).await.is_ok());
+        drop(host);
+        let res = client.send(request("4111111111111111", "000000001000")).await;
+        assert_eq!(res.err(), Some(ClientError::Closed));
+    }
+}
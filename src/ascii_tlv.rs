@@ -0,0 +1,250 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! ASCII tag-length-value codec for private fields (DE 48, 62, 63).
+//!
+//! The layout is declared on the field in the spec with `TlvTagWidth`,
+//! `TlvLengthWidth` and `TlvLengthEncoding` (`decimal`, `hex` or `binary`).
+
+use std::str;
+use iso_field::AsciiTlvFormat;
+use iso_field::TlvLengthEncoding;
+use iso_msg::IsoMsg;
+
+/// A tag-length-value element
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsciiTlv {
+    pub tag: String,
+    pub value: Vec<u8>,
+}
+
+impl AsciiTlv {
+    pub fn new(tag: &str, value: &[u8]) -> AsciiTlv {
+        AsciiTlv {
+            tag: String::from(tag),
+            value: value.to_vec(),
+        }
+    }
+}
+
+pub fn parse_ascii_tlv(format: &AsciiTlvFormat, data: &[u8]) -> Result<Vec<AsciiTlv>, &'static str> {
+    // every element advances by at least its tag and length
+    format.check()?;
+    let mut items = Vec::new();
+    let mut index = 0usize;
+    while index < data.len() {
+        if index + format.tag_width + format.length_width > data.len() {
+            return Err("TLV tag or length is truncated");
+        }
+        let tag = match str::from_utf8(&data[index..index + format.tag_width]) {
+            Ok(t) => t,
+            Err(_) => return Err("TLV tag is not valid utf8"),
+        };
+        index += format.tag_width;
+        let len = decode_length(format, &data[index..index + format.length_width])?;
+        index += format.length_width;
+        if index + len > data.len() {
+            return Err("TLV value exceeds data length");
+        }
+        items.push(AsciiTlv::new(tag, &data[index..index + len]));
+        index += len;
+    }
+    Ok(items)
+}
+
+pub fn encode_ascii_tlv(format: &AsciiTlvFormat, items: &[AsciiTlv]) -> Result<Vec<u8>, &'static str> {
+    format.check()?;
+    let mut out = Vec::new();
+    for item in items.iter() {
+        if item.tag.len() != format.tag_width {
+            return Err("TLV tag does not match tag width");
+        }
+        out.extend_from_slice(item.tag.as_bytes());
+        out.extend_from_slice(&encode_length(format, item.value.len())?);
+        out.extend_from_slice(&item.value);
+    }
+    Ok(out)
+}
+
+fn decode_length(format: &AsciiTlvFormat, data: &[u8]) -> Result<usize, &'static str> {
+    match format.length_encoding {
+        TlvLengthEncoding::Binary => {
+            let mut len = 0usize;
+            for b in data.iter() {
+                len = (len << 8) | *b as usize;
+            }
+            Ok(len)
+        }
+        TlvLengthEncoding::Decimal | TlvLengthEncoding::Hex => {
+            let radix = if format.length_encoding == TlvLengthEncoding::Hex { 16 } else { 10 };
+            let digits = match str::from_utf8(data) {
+                Ok(d) => d,
+                Err(_) => return Err("Invalid TLV length"),
+            };
+            match usize::from_str_radix(digits, radix) {
+                Ok(l) => Ok(l),
+                Err(_) => Err("Invalid TLV length"),
+            }
+        }
+    }
+}
+
+fn encode_length(format: &AsciiTlvFormat, len: usize) -> Result<Vec<u8>, &'static str> {
+    let w = format.length_width;
+    let v = match format.length_encoding {
+        TlvLengthEncoding::Decimal => format!("{:0w$}", len, w = w).into_bytes(),
+        TlvLengthEncoding::Hex => format!("{:0w$X}", len, w = w).into_bytes(),
+        TlvLengthEncoding::Binary => {
+            if w < 8 && len >> (8 * w) != 0 {
+                return Err("TLV value exceeds max length");
+            }
+            (0..w).rev().map(|i| if i < 8 { (len >> (8 * i)) as u8 } else { 0 }).collect()
+        }
+    };
+    if v.len() != w {
+        return Err("TLV value exceeds max length");
+    }
+    Ok(v)
+}
+
+/// ASCII TLV helpers, `index` must have a `tlv_format` in the spec
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    pub fn get_ascii_tlv(&self, index: usize) -> Result<Vec<AsciiTlv>, &'static str> {
+        let format = match self.get_spec().get_handle()[index].tlv_format {
+            Some(ref f) => f,
+            None => return Err("Field has no TLV format"),
+        };
+        let value = self.get_field_value(index)?;
+        parse_ascii_tlv(format, &value)
+    }
+
+    pub fn set_ascii_tlv(&mut self, index: usize, items: &[AsciiTlv]) -> Result<(), &'static str> {
+        let iso_field = &self.get_spec().get_handle()[index];
+        let format = match iso_field.tlv_format {
+            Some(ref f) => f,
+            None => return Err("Field has no TLV format"),
+        };
+        let value = encode_ascii_tlv(format, items)?;
+        if value.len() > iso_field.length {
+            return Err("TLV data exceeds field max length");
+        }
+        self.set_field(index, &value)
+    }
+
+    pub fn get_ascii_tlv_tag(&self, index: usize, tag: &str) -> Result<Vec<u8>, &'static str> {
+        let items = self.get_ascii_tlv(index)?;
+        match items.into_iter().find(|t| t.tag == tag) {
+            Some(t) => Ok(t.value),
+            None => Err("TLV tag not found"),
+        }
+    }
+
+    /// Replace the value of `tag`, append it if absent
+    pub fn set_ascii_tlv_tag(&mut self, index: usize, tag: &str, value: &[u8]) -> Result<(), &'static str> {
+        let mut items = self.get_existing_ascii_tlv(index)?;
+        match items.iter().position(|t| t.tag == tag) {
+            Some(pos) => items[pos].value = value.to_vec(),
+            None => items.push(AsciiTlv::new(tag, value)),
+        }
+        self.set_ascii_tlv(index, &items)
+    }
+
+    pub fn remove_ascii_tlv_tag(&mut self, index: usize, tag: &str) -> Result<(), &'static str> {
+        let mut items = self.get_existing_ascii_tlv(index)?;
+        match items.iter().position(|t| t.tag == tag) {
+            Some(pos) => {
+                items.remove(pos);
+            }
+            None => return Err("TLV tag not found"),
+        }
+        if items.is_empty() {
+            return self.remove_field(index);
+        }
+        self.set_ascii_tlv(index, &items)
+    }
+
+    fn get_existing_ascii_tlv(&self, index: usize) -> Result<Vec<AsciiTlv>, &'static str> {
+        if self.get_spec().get_handle()[index].tlv_format.is_none() {
+            return Err("Field has no TLV format");
+        }
+        if !self.has_field(index) {
+            return Ok(Vec::new());
+        }
+        match self.get_ascii_tlv(index) {
+            Ok(items) => Ok(items),
+            Err(_) => Err("Invalid TLV data"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_specs::YamlSpec;
+
+    #[test]
+    fn ascii_tlv_codec_test() {
+        let format = AsciiTlvFormat::new(2, 3, TlvLengthEncoding::Decimal);
+        let items = parse_ascii_tlv(&format, b"01003ABC42010HELLOWORLD").unwrap();
+        assert_eq!(items, vec![AsciiTlv::new("01", b"ABC"), AsciiTlv::new("42", b"HELLOWORLD")]);
+        assert_eq!(encode_ascii_tlv(&format, &items).unwrap(), b"01003ABC42010HELLOWORLD".to_vec());
+        assert!(parse_ascii_tlv(&format, b"01009ABC").is_err());
+
+        let format = AsciiTlvFormat::new(3, 2, TlvLengthEncoding::Hex);
+        let items = vec![AsciiTlv::new("T01", &[b'X'; 26])];
+        let data = encode_ascii_tlv(&format, &items).unwrap();
+        assert_eq!(&data[..5], b"T011A");
+        assert_eq!(parse_ascii_tlv(&format, &data).unwrap(), items);
+
+        let format = AsciiTlvFormat::new(1, 1, TlvLengthEncoding::Binary);
+        let items = vec![AsciiTlv::new("A", b"XY")];
+        assert_eq!(encode_ascii_tlv(&format, &items).unwrap(), vec![b'A', 2, b'X', b'Y']);
+        assert!(encode_ascii_tlv(&format, &[AsciiTlv::new("A", &[0; 256])]).is_err());
+        assert!(encode_ascii_tlv(&format, &[AsciiTlv::new("AB", b"")]).is_err());
+
+        let format = AsciiTlvFormat::new(0, 0, TlvLengthEncoding::Binary);
+        assert!(parse_ascii_tlv(&format, b"AB").is_err());
+        let format = AsciiTlvFormat::new(2, 9, TlvLengthEncoding::Binary);
+        assert!(parse_ascii_tlv(&format, &[0; 20]).is_err());
+    }
+
+    #[test]
+    fn iso_msg_ascii_tlv_test() {
+        let s = String::from(
+            "
+            0:
+                ContentType: n
+                LengthType: fixed
+                Length: 4
+            1:
+                ContentType: ans
+                LengthType: lllvar
+                Length: 999
+                TlvTagWidth: 2
+                TlvLengthWidth: 3
+                TlvLengthEncoding: decimal
+            ",
+        );
+        let spec = YamlSpec::new(&s).unwrap();
+        let payload = "0100015AB004WXYZCD0011";
+        let mut iso_msg = IsoMsg::new(&spec, payload.as_bytes());
+
+        assert_eq!(iso_msg.get_ascii_tlv_tag(1, "AB"), Ok(b"WXYZ".to_vec()));
+        assert_eq!(iso_msg.get_ascii_tlv_tag(1, "EF"), Err("TLV tag not found"));
+        assert_eq!(iso_msg.set_ascii_tlv_tag(1, "CD", b"22"), Ok(()));
+        assert_eq!(iso_msg.set_ascii_tlv_tag(1, "EF", b"N"), Ok(()));
+        assert_eq!(iso_msg.remove_ascii_tlv_tag(1, "AB"), Ok(()));
+        assert_eq!(iso_msg.get_field_value(1), Ok(b"CD00222EF001N".to_vec()));
+        assert_eq!(iso_msg.get_ascii_tlv_tag(0, "AB"), Err("Field has no TLV format"));
+
+        let zero = s.replace("TlvTagWidth: 2", "TlvTagWidth: 0").replace("TlvLengthWidth: 3", "TlvLengthWidth: 0");
+        assert!(YamlSpec::new(&zero).is_err());
+        let wide = s.replace("TlvLengthWidth: 3", "TlvLengthWidth: 9").replace("decimal", "binary");
+        assert!(YamlSpec::new(&wide).is_err());
+    }
+}
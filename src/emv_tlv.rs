@@ -0,0 +1,427 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! BER-TLV codec for EMV data (DE 55).
+//!
+//! Tags are kept as `u32` in their encoded form e.g. `0x9F26`. Binary fields (`b`)
+//! carry the TLV bytes as is, any other content type carries them hex encoded.
+
+use hex;
+use iso_field::FieldCharType;
+use iso_msg::IsoMsg;
+
+/// A BER-TLV data object. Constructed objects keep their content in `children`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tlv {
+    pub tag: u32,
+    pub value: Vec<u8>,
+    pub children: Vec<Tlv>,
+}
+
+impl Tlv {
+    pub fn new(tag: u32, value: &[u8]) -> Tlv {
+        Tlv {
+            tag,
+            value: value.to_vec(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn new_constructed(tag: u32, children: Vec<Tlv>) -> Tlv {
+        Tlv {
+            tag,
+            value: Vec::new(),
+            children,
+        }
+    }
+
+    /// bit 6 of the first tag byte
+    pub fn is_constructed_tag(tag: u32) -> bool {
+        let bytes = tag_to_bytes(tag);
+        bytes[0] & 0x20 != 0
+    }
+
+    pub fn is_constructed(&self) -> bool {
+        Tlv::is_constructed_tag(self.tag)
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        tag_name(self.tag)
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&tag_to_bytes(self.tag));
+        if self.is_constructed() {
+            let content = encode_tlv(&self.children);
+            encode_length(content.len(), out);
+            out.extend_from_slice(&content);
+        } else {
+            encode_length(self.value.len(), out);
+            out.extend_from_slice(&self.value);
+        }
+    }
+}
+
+/// Parse a list of BER-TLV objects, constructed objects are parsed recursively.
+/// `0x00`/`0xFF` padding between objects is skipped
+pub fn parse_tlv(data: &[u8]) -> Result<Vec<Tlv>, &'static str> {
+    let mut items = Vec::new();
+    let mut index = 0usize;
+    while index < data.len() {
+        if data[index] == 0x00 || data[index] == 0xFF {
+            index += 1;
+            continue;
+        }
+        let (tag, tag_len) = decode_tag(&data[index..])?;
+        index += tag_len;
+        let (len, len_len) = decode_length(&data[index..])?;
+        index += len_len;
+        if index + len > data.len() {
+            return Err("TLV value exceeds data length");
+        }
+        let value = &data[index..index + len];
+        index += len;
+
+        if Tlv::is_constructed_tag(tag) {
+            items.push(Tlv::new_constructed(tag, parse_tlv(value)?));
+        } else {
+            items.push(Tlv::new(tag, value));
+        }
+    }
+    Ok(items)
+}
+
+pub fn encode_tlv(items: &[Tlv]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for item in items.iter() {
+        item.encode(&mut out);
+    }
+    out
+}
+
+/// Find `tag` in `items`, searching constructed objects depth first
+pub fn find_tag(items: &[Tlv], tag: u32) -> Option<&Tlv> {
+    for item in items.iter() {
+        if item.tag == tag {
+            return Some(item);
+        }
+        if let Some(t) = find_tag(&item.children, tag) {
+            return Some(t);
+        }
+    }
+    None
+}
+
+/// Replace the value of `tag` (nested or not), append it at the top level if absent.
+/// The value of a constructed tag is parsed into its children
+pub fn set_tag(items: &mut Vec<Tlv>, tag: u32, value: &[u8]) -> Result<(), &'static str> {
+    let item = if Tlv::is_constructed_tag(tag) {
+        Tlv::new_constructed(tag, parse_tlv(value)?)
+    } else {
+        Tlv::new(tag, value)
+    };
+    if let Some(item) = replace_tag(items, item) {
+        items.push(item);
+    }
+    Ok(())
+}
+
+/// Returns `item` back if `item.tag` is not found
+fn replace_tag(items: &mut [Tlv], item: Tlv) -> Option<Tlv> {
+    let mut item = item;
+    for existing in items.iter_mut() {
+        if existing.tag == item.tag {
+            *existing = item;
+            return None;
+        }
+        item = replace_tag(&mut existing.children, item)?;
+    }
+    Some(item)
+}
+
+/// Remove `tag` (nested or not), returns false if not found
+pub fn remove_tag(items: &mut Vec<Tlv>, tag: u32) -> bool {
+    if let Some(pos) = items.iter().position(|t| t.tag == tag) {
+        items.remove(pos);
+        return true;
+    }
+    for item in items.iter_mut() {
+        if remove_tag(&mut item.children, tag) {
+            return true;
+        }
+    }
+    false
+}
+
+fn decode_tag(data: &[u8]) -> Result<(u32, usize), &'static str> {
+    if data.is_empty() {
+        return Err("TLV tag is truncated");
+    }
+    let mut tag = u32::from(data[0]);
+    let mut len = 1;
+    if data[0] & 0x1F == 0x1F {
+        loop {
+            if len >= data.len() {
+                return Err("TLV tag is truncated");
+            }
+            if len >= 4 {
+                return Err("TLV tag is longer than 4 bytes");
+            }
+            tag = (tag << 8) | u32::from(data[len]);
+            len += 1;
+            if data[len - 1] & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    Ok((tag, len))
+}
+
+fn decode_length(data: &[u8]) -> Result<(usize, usize), &'static str> {
+    if data.is_empty() {
+        return Err("TLV length is truncated");
+    }
+    if data[0] & 0x80 == 0 {
+        return Ok((data[0] as usize, 1));
+    }
+    let num_bytes = (data[0] & 0x7F) as usize;
+    if num_bytes == 0 || num_bytes > 4 {
+        return Err("Invalid TLV length");
+    }
+    if data.len() < num_bytes + 1 {
+        return Err("TLV length is truncated");
+    }
+    let mut len = 0usize;
+    for b in data[1..num_bytes + 1].iter() {
+        len = (len << 8) | *b as usize;
+    }
+    Ok((len, num_bytes + 1))
+}
+
+fn encode_length(len: usize, out: &mut Vec<u8>) {
+    if len < 0x80 {
+        out.push(len as u8);
+        return;
+    }
+    let bytes = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    out.push(0x80 | (4 - skip) as u8);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+fn tag_to_bytes(tag: u32) -> Vec<u8> {
+    let bytes = [(tag >> 24) as u8, (tag >> 16) as u8, (tag >> 8) as u8, tag as u8];
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    if skip == 4 {
+        return vec![0];
+    }
+    bytes[skip..].to_vec()
+}
+
+/// EMV tag dictionary
+pub fn tag_name(tag: u32) -> Option<&'static str> {
+    let name = match tag {
+        0x4F => "Application Identifier (AID)",
+        0x50 => "Application Label",
+        0x57 => "Track 2 Equivalent Data",
+        0x5A => "Application PAN",
+        0x5F20 => "Cardholder Name",
+        0x5F24 => "Application Expiration Date",
+        0x5F25 => "Application Effective Date",
+        0x5F28 => "Issuer Country Code",
+        0x5F2A => "Transaction Currency Code",
+        0x5F34 => "Application PAN Sequence Number",
+        0x6F => "File Control Information Template",
+        0x70 => "Record Template",
+        0x71 => "Issuer Script Template 1",
+        0x72 => "Issuer Script Template 2",
+        0x77 => "Response Message Template Format 2",
+        0x80 => "Response Message Template Format 1",
+        0x82 => "Application Interchange Profile",
+        0x84 => "Dedicated File Name",
+        0x8A => "Authorisation Response Code",
+        0x8E => "CVM List",
+        0x91 => "Issuer Authentication Data",
+        0x95 => "Terminal Verification Results",
+        0x9A => "Transaction Date",
+        0x9B => "Transaction Status Information",
+        0x9C => "Transaction Type",
+        0x9F02 => "Amount, Authorised",
+        0x9F03 => "Amount, Other",
+        0x9F06 => "Application Identifier (AID) - terminal",
+        0x9F07 => "Application Usage Control",
+        0x9F09 => "Application Version Number",
+        0x9F10 => "Issuer Application Data",
+        0x9F1A => "Terminal Country Code",
+        0x9F1E => "Interface Device Serial Number",
+        0x9F26 => "Application Cryptogram",
+        0x9F27 => "Cryptogram Information Data",
+        0x9F33 => "Terminal Capabilities",
+        0x9F34 => "CVM Results",
+        0x9F35 => "Terminal Type",
+        0x9F36 => "Application Transaction Counter",
+        0x9F37 => "Unpredictable Number",
+        0x9F41 => "Transaction Sequence Counter",
+        0x9F53 => "Transaction Category Code",
+        0x9F6E => "Form Factor Indicator",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// EMV helpers, `index` is the field carrying the TLV data, usually 55
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    pub fn get_tlv(&self, index: usize) -> Result<Vec<Tlv>, &'static str> {
+        let value = self.get_field_value(index)?;
+        if self.get_spec().get_handle()[index].char_type == FieldCharType::Iso8583_b {
+            return parse_tlv(&value);
+        }
+        match hex::decode(&value) {
+            Ok(data) => parse_tlv(&data),
+            Err(_) => Err("TLV field is not valid hex"),
+        }
+    }
+
+    pub fn set_tlv(&mut self, index: usize, items: &[Tlv]) -> Result<(), &'static str> {
+        let iso_field = &self.get_spec().get_handle()[index];
+        let mut value = encode_tlv(items);
+        if iso_field.char_type != FieldCharType::Iso8583_b {
+            value = hex::encode_upper(&value).into_bytes();
+        }
+        if value.len() > iso_field.length {
+            return Err("TLV data exceeds field max length");
+        }
+        self.set_field(index, &value)
+    }
+
+    pub fn get_tlv_tag(&self, index: usize, tag: u32) -> Result<Vec<u8>, &'static str> {
+        let items = self.get_tlv(index)?;
+        match find_tag(&items, tag) {
+            Some(t) => Ok(t.value.clone()),
+            None => Err("TLV tag not found"),
+        }
+    }
+
+    pub fn set_tlv_tag(&mut self, index: usize, tag: u32, value: &[u8]) -> Result<(), &'static str> {
+        let mut items = self.get_existing_tlv(index)?;
+        set_tag(&mut items, tag, value)?;
+        self.set_tlv(index, &items)
+    }
+
+    pub fn remove_tlv_tag(&mut self, index: usize, tag: u32) -> Result<(), &'static str> {
+        let mut items = self.get_existing_tlv(index)?;
+        if !remove_tag(&mut items, tag) {
+            return Err("TLV tag not found");
+        }
+        if items.is_empty() {
+            return self.remove_field(index);
+        }
+        self.set_tlv(index, &items)
+    }
+
+    fn get_existing_tlv(&self, index: usize) -> Result<Vec<Tlv>, &'static str> {
+        if !self.has_field(index) {
+            return Ok(Vec::new());
+        }
+        match self.get_tlv(index) {
+            Ok(items) => Ok(items),
+            Err(_) => Err("Invalid TLV data"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iso_field::FieldSizeType;
+    use iso_field::IsoField;
+    use iso_msg::IsoSpecs;
+
+    const EMV_DATA: &str = "9F2608C2C12B098F3DA6E39F2701809F100706011203A0B8009F3704F8F1F4A19F36020057950580000480009A031711219C01005F2A020840820258009F1A0208409F03060000000000009F02060000000031009F330360F0C8";
+
+    #[test]
+    fn parse_tlv_test() {
+        let data = hex::decode(EMV_DATA).unwrap();
+        let items = parse_tlv(&data).unwrap();
+        assert_eq!(items.len(), 14);
+        assert_eq!(items[0].tag, 0x9F26);
+        assert_eq!(items[0].name(), Some("Application Cryptogram"));
+        assert_eq!(hex::encode_upper(&items[0].value), "C2C12B098F3DA6E3");
+        assert_eq!(find_tag(&items, 0x95).unwrap().value, vec![0x80, 0x00, 0x04, 0x80, 0x00]);
+        assert_eq!(encode_tlv(&items), data);
+
+        assert!(parse_tlv(&[0x9F, 0x26, 0x08, 0x01]).is_err());
+        assert!(parse_tlv(&[0x9F]).is_err());
+    }
+
+    #[test]
+    fn constructed_tlv_test() {
+        let long_value = vec![0xAB; 200];
+        let items = vec![
+            Tlv::new_constructed(0x71, vec![Tlv::new(0x9F18, &[1, 2, 3, 4]), Tlv::new(0x86, &long_value)]),
+            Tlv::new(0x91, &[0x11; 10]),
+        ];
+        let data = encode_tlv(&items);
+        assert_eq!(&data[..4], &[0x71, 0x81, 0xD2, 0x9F]);
+        let parsed = parse_tlv(&data).unwrap();
+        assert_eq!(parsed, items);
+        assert_eq!(find_tag(&parsed, 0x86).unwrap().value.len(), 200);
+
+        let mut parsed = parsed;
+        assert_eq!(set_tag(&mut parsed, 0x9F18, &[5, 6]), Ok(()));
+        assert_eq!(find_tag(&parsed, 0x9F18).unwrap().value, vec![5, 6]);
+        // the value of a constructed tag is kept as children, encoded on output
+        let script = encode_tlv(&[Tlv::new(0x86, &[0xCD; 4])]);
+        assert_eq!(set_tag(&mut parsed, 0x72, &script), Ok(()));
+        assert_eq!(parsed[2].children, vec![Tlv::new(0x86, &[0xCD; 4])]);
+        assert_eq!(parse_tlv(&encode_tlv(&parsed)).unwrap(), parsed);
+        assert_eq!(set_tag(&mut parsed, 0x71, &script), Ok(()));
+        assert_eq!(parsed[0].children, vec![Tlv::new(0x86, &[0xCD; 4])]);
+        assert!(set_tag(&mut parsed, 0x77, &[0x9F]).is_err());
+        assert_eq!(parsed.len(), 3);
+        parsed.remove(2);
+        parsed[0] = items[0].clone();
+
+        assert!(remove_tag(&mut parsed, 0x9F18));
+        assert_eq!(parsed[0].children.len(), 1);
+        assert!(!remove_tag(&mut parsed, 0x9F18));
+    }
+
+    struct EmvSpecs {
+        handle: Vec<IsoField>,
+    }
+    impl IsoSpecs for EmvSpecs {
+        fn get_handle(&self) -> &Vec<IsoField> {
+            &self.handle
+        }
+    }
+
+    #[test]
+    fn iso_msg_tlv_test() {
+        let spec = EmvSpecs {
+            handle: vec![
+                IsoField::new("Message Type Indicator", FieldCharType::Iso8583_ns, 4, FieldSizeType::Fixed),
+                IsoField::new("IC Card System Related Data", FieldCharType::Iso8583_ans, 999, FieldSizeType::LllVar),
+            ],
+        };
+        let payload = format!("0100{:03}{}", EMV_DATA.len(), EMV_DATA);
+        let mut iso_msg = IsoMsg::new(&spec, payload.as_bytes());
+
+        assert_eq!(iso_msg.get_tlv_tag(1, 0x9F36), Ok(vec![0x00, 0x57]));
+        assert_eq!(iso_msg.set_tlv_tag(1, 0x9F36, &[0x00, 0x58]), Ok(()));
+        assert_eq!(iso_msg.set_tlv_tag(1, 0x9F34, &[0x1E, 0x03, 0x00]), Ok(()));
+        assert_eq!(iso_msg.remove_tlv_tag(1, 0x9F03), Ok(()));
+        assert_eq!(iso_msg.remove_tlv_tag(1, 0x9F03), Err("TLV tag not found"));
+
+        let items = iso_msg.get_tlv(1).unwrap();
+        assert_eq!(items.len(), 14);
+        assert_eq!(find_tag(&items, 0x9F36).unwrap().value, vec![0x00, 0x58]);
+        assert_eq!(items[13].tag, 0x9F34);
+        assert!(find_tag(&items, 0x9F03).is_none());
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Card data: PAN Luhn check, track 1 (DE 45) and track 2 (DE 35) parsing and service codes.
+
+use iso_msg::IsoMsg;
+
+pub const DE_PAN: usize = 2;
+pub const DE_EXPIRY: usize = 14;
+pub const DE_TRACK2: usize = 35;
+pub const DE_TRACK1: usize = 45;
+
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Luhn (mod 10) check digit for `digits`, the PAN without its check digit
+pub fn luhn_check_digit(digits: &str) -> Option<char> {
+    if !is_digits(digits) {
+        return None;
+    }
+    let sum: u32 = digits
+        .bytes()
+        .rev()
+        .enumerate()
+        .map(|(i, b)| {
+            let d = u32::from(b - b'0');
+            if i % 2 == 0 {
+                if d * 2 > 9 { d * 2 - 9 } else { d * 2 }
+            } else {
+                d
+            }
+        })
+        .sum();
+    Some((b'0' + ((10 - sum % 10) % 10) as u8) as char)
+}
+
+/// true if `pan` is 12 to 19 digits with a valid check digit
+pub fn luhn_check(pan: &str) -> bool {
+    if pan.len() < 12 || pan.len() > 19 || !pan.is_ascii() {
+        return false;
+    }
+    let (digits, check) = pan.split_at(pan.len() - 1);
+    luhn_check_digit(digits).map(|c| check.starts_with(c)).unwrap_or(false)
+}
+
+/// The three service code digits of ISO 7813
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ServiceCode {
+    pub interchange: u8,
+    pub authorization: u8,
+    pub services: u8,
+}
+
+impl ServiceCode {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<ServiceCode> {
+        if s.len() != 3 || !is_digits(s) {
+            return None;
+        }
+        let b = s.as_bytes();
+        Some(ServiceCode {
+            interchange: b[0] - b'0',
+            authorization: b[1] - b'0',
+            services: b[2] - b'0',
+        })
+    }
+
+    /// International interchange allowed (1 or 2)
+    pub fn is_international(&self) -> bool {
+        self.interchange == 1 || self.interchange == 2
+    }
+
+    /// Integrated circuit card (2 or 6), magstripe fallback should be declined
+    pub fn has_chip(&self) -> bool {
+        self.interchange == 2 || self.interchange == 6
+    }
+
+    /// Transactions must be authorized online (2)
+    pub fn online_authorization(&self) -> bool {
+        self.authorization == 2
+    }
+
+    /// PIN required (0, 3 or 5)
+    pub fn pin_required(&self) -> bool {
+        self.services == 0 || self.services == 3 || self.services == 5
+    }
+}
+
+/// Track 2: `PAN=YYMMSSS` followed by discretionary data
+#[derive(Debug, PartialEq, Clone)]
+pub struct Track2 {
+    pub pan: String,
+    /// YYMM
+    pub expiry: String,
+    pub service_code: String,
+    pub discretionary: String,
+}
+
+impl Track2 {
+    /// Start and end sentinels are optional, the separator is `=` or `D`
+    pub fn parse(track: &str) -> Result<Track2, &'static str> {
+        if !track.is_ascii() {
+            return Err("Track 2 is not ASCII");
+        }
+        let track = track.trim_start_matches(';').trim_end_matches('?');
+        let sep = match track.find(['=', 'D']) {
+            Some(i) => i,
+            None => return Err("Track 2 has no separator"),
+        };
+        let (pan, rest) = (&track[..sep], &track[sep + 1..]);
+        if !is_digits(pan) || pan.len() > 19 {
+            return Err("Invalid PAN in track 2");
+        }
+        if rest.len() < 7 || !is_digits(&rest[..7]) {
+            return Err("Invalid expiry or service code in track 2");
+        }
+        Ok(Track2 {
+            pan: pan.to_string(),
+            expiry: rest[..4].to_string(),
+            service_code: rest[4..7].to_string(),
+            discretionary: rest[7..].to_string(),
+        })
+    }
+
+    pub fn get_service_code(&self) -> Option<ServiceCode> {
+        ServiceCode::from_str(&self.service_code)
+    }
+}
+
+/// Track 1 format B: `BPAN^NAME^YYMMSSS` followed by discretionary data
+#[derive(Debug, PartialEq, Clone)]
+pub struct Track1 {
+    pub pan: String,
+    pub name: String,
+    /// YYMM
+    pub expiry: String,
+    pub service_code: String,
+    pub discretionary: String,
+}
+
+impl Track1 {
+    pub fn parse(track: &str) -> Result<Track1, &'static str> {
+        if !track.is_ascii() {
+            return Err("Track 1 is not ASCII");
+        }
+        let track = track.trim_start_matches('%').trim_end_matches('?');
+        if !track.starts_with('B') {
+            return Err("Track 1 is not format B");
+        }
+        let mut parts = track[1..].splitn(3, '^');
+        let (pan, name, rest) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(p), Some(n), Some(r)) => (p, n, r),
+            _ => return Err("Track 1 has no separator"),
+        };
+        if !is_digits(pan) || pan.len() > 19 {
+            return Err("Invalid PAN in track 1");
+        }
+        if name.len() < 2 || name.len() > 26 {
+            return Err("Invalid name in track 1");
+        }
+        if rest.len() < 7 || !is_digits(&rest[..7]) {
+            return Err("Invalid expiry or service code in track 1");
+        }
+        Ok(Track1 {
+            pan: pan.to_string(),
+            name: name.to_string(),
+            expiry: rest[..4].to_string(),
+            service_code: rest[4..7].to_string(),
+            discretionary: rest[7..].to_string(),
+        })
+    }
+
+    pub fn get_service_code(&self) -> Option<ServiceCode> {
+        ServiceCode::from_str(&self.service_code)
+    }
+}
+
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    fn get_field_string(&self, index: usize) -> Result<String, &'static str> {
+        String::from_utf8(self.get_field_value(index)?).map_err(|_| "Field is not valid UTF-8")
+    }
+
+    pub fn get_track2(&self) -> Result<Track2, &'static str> {
+        Track2::parse(&self.get_field_string(DE_TRACK2)?)
+    }
+
+    pub fn get_track1(&self) -> Result<Track1, &'static str> {
+        Track1::parse(&self.get_field_string(DE_TRACK1)?)
+    }
+
+    /// Check the Luhn digit of DE 2 and that DE 2 and DE 14 match the track data in
+    /// DE 35 and DE 45. Absent fields are not checked
+    pub fn check_card_data(&self) -> Result<(), &'static str> {
+        let pan = if self.has_field(DE_PAN) {
+            Some(self.get_field_string(DE_PAN)?)
+        } else {
+            None
+        };
+        let expiry = if self.has_field(DE_EXPIRY) {
+            Some(self.get_field_string(DE_EXPIRY)?)
+        } else {
+            None
+        };
+        let mut tracks = Vec::new();
+        if self.has_field(DE_TRACK2) {
+            let t = self.get_track2()?;
+            tracks.push((t.pan, t.expiry));
+        }
+        if self.has_field(DE_TRACK1) {
+            let t = self.get_track1()?;
+            tracks.push((t.pan, t.expiry));
+        }
+
+        let pan = match pan.or_else(|| tracks.first().map(|t| t.0.clone())) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if !luhn_check(&pan) {
+            return Err("PAN fails Luhn check");
+        }
+        for (track_pan, track_expiry) in &tracks {
+            if *track_pan != pan {
+                return Err("PAN does not match track data");
+            }
+            if expiry.as_ref().map(|e| e != track_expiry).unwrap_or(false) {
+                return Err("Expiry date does not match track data");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_specs::YamlSpec;
+
+    #[test]
+    fn luhn_test() {
+        assert!(luhn_check("4111111111111111"));
+        assert!(luhn_check("5500005555555559"));
+        assert!(luhn_check("378282246310005"));
+        assert!(!luhn_check("4111111111111112"));
+        assert!(!luhn_check("41111111111A1111"));
+        assert!(!luhn_check("41111"));
+        assert!(!luhn_check("411111111111111é"));
+        assert_eq!(luhn_check_digit("411111111111111"), Some('1'));
+        assert_eq!(luhn_check_digit("7992739871"), Some('3'));
+    }
+
+    #[test]
+    fn track_test() {
+        let t = Track2::parse(";4111111111111111=25121011234567890?").unwrap();
+        assert_eq!(t.pan, "4111111111111111");
+        assert_eq!(t.expiry, "2512");
+        assert_eq!(t.service_code, "101");
+        assert_eq!(t.discretionary, "1234567890");
+        assert_eq!(Track2::parse("4111111111111111D2512201").unwrap().service_code, "201");
+        assert!(Track2::parse("4111111111111111").is_err());
+        assert!(Track2::parse("4111111111111111=2512").is_err());
+        assert_eq!(Track2::parse("4111111111111111=251210é"), Err("Track 2 is not ASCII"));
+
+        let t = Track1::parse("%B4111111111111111^DOE/JOHN^2512101000123?").unwrap();
+        assert_eq!(t.pan, "4111111111111111");
+        assert_eq!(t.name, "DOE/JOHN");
+        assert_eq!(t.expiry, "2512");
+        assert_eq!(t.discretionary, "000123");
+        assert!(Track1::parse("A4111111111111111^DOE/JOHN^2512101").is_err());
+        assert_eq!(Track1::parse("B4111111111111111^DOE/JOHN^251210é"), Err("Track 1 is not ASCII"));
+
+        let code = t.get_service_code().unwrap();
+        assert!(code.is_international() && !code.has_chip() && !code.online_authorization());
+        assert!(!code.pin_required());
+        let code = ServiceCode::from_str("220").unwrap();
+        assert!(code.has_chip() && code.online_authorization() && code.pin_required());
+        assert!(ServiceCode::from_str("2A0").is_none());
+    }
+
+    #[test]
+    fn check_card_data_test() {
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut msg = IsoMsg::empty(&spec);
+        msg.set_field(0, b"0100").unwrap();
+        assert_eq!(msg.check_card_data(), Ok(()));
+
+        msg.set_field(DE_TRACK2, b"4111111111111111=25121011234567890").unwrap();
+        assert_eq!(msg.get_track2().unwrap().expiry, "2512");
+        assert_eq!(msg.check_card_data(), Ok(()));
+        msg.set_field(DE_PAN, b"4111111111111111").unwrap();
+        msg.set_field(DE_EXPIRY, b"2512").unwrap();
+        assert_eq!(msg.check_card_data(), Ok(()));
+
+        msg.set_field(DE_EXPIRY, b"2601").unwrap();
+        assert_eq!(msg.check_card_data(), Err("Expiry date does not match track data"));
+        msg.set_field(DE_EXPIRY, b"2512").unwrap();
+        msg.set_field(DE_TRACK1, b"B5500005555555559^DOE/JOHN^2512101").unwrap();
+        assert_eq!(msg.check_card_data(), Err("PAN does not match track data"));
+        msg.remove_field(DE_TRACK1).unwrap();
+        msg.set_field(DE_PAN, b"4111111111111112").unwrap();
+        assert_eq!(msg.check_card_data(), Err("PAN fails Luhn check"));
+    }
+}
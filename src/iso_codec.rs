@@ -0,0 +1,317 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `tokio_util::codec` implementation for framed streams, enabled by the `codec` feature.
+//!
+//! ```ignore
+//! let format = FrameFormat::new(LengthHeader::Binary2, false, 0);
+//! let mut framed = Framed::new(tcp_stream, IsoCodec::new(&spec, format));
+//! while let Some(msg) = framed.next().await { ... }
+//! ```
+//!
+//! `IsoCodec` writes the same TPDU before every message and drops the TPDU of the frames
+//! it reads. `IsoTpduCodec` reads and writes each message with its own TPDU, e.g. to
+//! answer a request with `swap_tpdu` of its TPDU.
+
+use std::io;
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+use iso_framing::FrameFormat;
+use iso_msg::{IsoMsg, IsoSpecs};
+use iso_stream::ParseStatus;
+
+pub use iso_framing::DEFAULT_MAX_FRAME_LENGTH;
+
+/// Decodes frames into owned `IsoMsg` parsed against a shared spec and encodes them back
+pub struct IsoCodec<'s> {
+    spec: &'s (dyn IsoSpecs + Sync),
+    format: FrameFormat,
+    tpdu: Vec<u8>,
+}
+
+impl<'s> IsoCodec<'s> {
+    pub fn new(spec: &'s (dyn IsoSpecs + Sync), format: FrameFormat) -> IsoCodec<'s> {
+        IsoCodec {
+            spec,
+            format,
+            tpdu: vec![0u8; format.tpdu_length],
+        }
+    }
+
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.format.max_length = max_frame_length;
+    }
+
+    pub fn get_max_frame_length(&self) -> usize {
+        self.format.max_length
+    }
+
+    /// TPDU written before each encoded message, zeros unless set
+    pub fn get_tpdu(&self) -> &[u8] {
+        &self.tpdu
+    }
+
+    /// TPDU written before each encoded message
+    pub fn set_tpdu(&mut self, tpdu: &[u8]) -> Result<(), &'static str> {
+        if tpdu.len() != self.format.tpdu_length {
+            return Err("TPDU does not match TPDU length");
+        }
+        self.tpdu = tpdu.to_vec();
+        Ok(())
+    }
+
+    pub fn get_spec(&self) -> &'s (dyn IsoSpecs + Sync) {
+        self.spec
+    }
+
+    pub fn get_format(&self) -> &FrameFormat {
+        &self.format
+    }
+
+    /// Next frame as its TPDU and message
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<(Vec<u8>, IsoMsg<'static, 's>)>, io::Error> {
+        let total = match self.format.frame_length(src).map_err(invalid_data)? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        if src.len() < total {
+            src.reserve(total - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(total);
+        let tpdu_start = self.format.header.size();
+        let body_start = tpdu_start + self.format.tpdu_length;
+        let tpdu = frame[tpdu_start..body_start].to_vec();
+
+        let body = frame[body_start..].to_vec();
+        match IsoMsg::measure(self.spec, &body).map_err(invalid_data)? {
+            ParseStatus::Complete(len) if len == body.len() => {}
+            ParseStatus::Complete(_) => return Err(invalid_data("Frame is longer than the message")),
+            ParseStatus::Incomplete(_) => return Err(invalid_data("Payload is truncated")),
+        }
+        Ok(Some((tpdu, IsoMsg::new_owned(self.spec, body))))
+    }
+
+    /// Reserve the length header, write the TPDU and message in place, then fill in the header
+    fn encode_frame(&self, tpdu: &[u8], item: &IsoMsg, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if tpdu.len() != self.format.tpdu_length {
+            return Err(invalid_data("TPDU does not match TPDU length"));
+        }
+        let start = dst.len();
+        let header_len = self.format.header.size();
+        dst.reserve(header_len + tpdu.len() + item.encoded_len());
+        dst.resize(start + header_len, 0);
+        dst.extend_from_slice(tpdu);
+        let body_len = item.write_to_bytes(dst);
+        match self.format.length_header(tpdu.len() + body_len) {
+            Ok(header) => {
+                dst[start..start + header_len].copy_from_slice(&header);
+                Ok(())
+            }
+            Err(e) => {
+                dst.truncate(start);
+                Err(invalid_data(e))
+            }
+        }
+    }
+}
+
+fn invalid_data(e: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl<'s> Decoder for IsoCodec<'s> {
+    type Item = IsoMsg<'static, 's>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        Ok(self.decode_frame(src)?.map(|(_, msg)| msg))
+    }
+}
+
+impl<'s, 'a, 'b> Encoder<IsoMsg<'a, 'b>> for IsoCodec<'s> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: IsoMsg<'a, 'b>, dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.encode_frame(&self.tpdu, &item, dst)
+    }
+}
+
+impl<'s, 'a, 'b, 'c> Encoder<&'c IsoMsg<'a, 'b>> for IsoCodec<'s> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &'c IsoMsg<'a, 'b>, dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.encode_frame(&self.tpdu, item, dst)
+    }
+}
+
+/// Same framing as `IsoCodec`, each message paired with the TPDU of its frame
+pub struct IsoTpduCodec<'s> {
+    codec: IsoCodec<'s>,
+}
+
+impl<'s> IsoTpduCodec<'s> {
+    pub fn new(spec: &'s (dyn IsoSpecs + Sync), format: FrameFormat) -> IsoTpduCodec<'s> {
+        IsoTpduCodec { codec: IsoCodec::new(spec, format) }
+    }
+
+    pub fn get_codec(&mut self) -> &mut IsoCodec<'s> {
+        &mut self.codec
+    }
+}
+
+impl<'s> Decoder for IsoTpduCodec<'s> {
+    type Item = (Vec<u8>, IsoMsg<'static, 's>);
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        self.codec.decode_frame(src)
+    }
+}
+
+impl<'s, 'a, 'b> Encoder<(Vec<u8>, IsoMsg<'a, 'b>)> for IsoTpduCodec<'s> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: (Vec<u8>, IsoMsg<'a, 'b>), dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.codec.encode_frame(&item.0, &item.1, dst)
+    }
+}
+
+impl<'s, 'a, 'b, 'c> Encoder<(&'c [u8], &'c IsoMsg<'a, 'b>)> for IsoTpduCodec<'s> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: (&'c [u8], &'c IsoMsg<'a, 'b>), dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.codec.encode_frame(item.0, item.1, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iso_framing::{swap_tpdu, LengthHeader};
+    use yaml_specs::YamlSpec;
+
+    fn spec() -> YamlSpec {
+        let s = String::from(
+            "
+            0:
+                ContentType: n
+                LengthType: fixed
+                Length: 4
+            1:
+                ContentType: bmps
+                LengthType: bitmap
+                Length: 16
+            2:
+                ContentType: n
+                LengthType: fixed
+                Length: 6
+            3:
+                ContentType: ans
+                LengthType: llvar
+                Length: 20
+            ",
+        );
+        YamlSpec::new(&s).unwrap()
+    }
+
+    #[test]
+    fn iso_codec_test() {
+        let spec = spec();
+        let format = FrameFormat::new(LengthHeader::Binary2, false, 5);
+        let mut codec = IsoCodec::new(&spec, format);
+        assert_eq!(codec.set_tpdu(b"\x60\x00\x01\x00\x02"), Ok(()));
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x00\x21\x60\x00\x03\x00\x0408002000000000000000005HELLO");
+        buf.extend_from_slice(b"\x00\x21\x60");
+
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        // the configured TPDU is kept
+        assert_eq!(codec.get_tpdu(), b"\x60\x00\x01\x00\x02");
+        assert_eq!(msg.get_field_value(3), Ok(b"HELLO".to_vec()));
+        assert!(!msg.has_field(2));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 3);
+
+        let mut reply = IsoMsg::empty(codec.get_spec());
+        assert_eq!(reply.set_field(0, b"0810"), Ok(()));
+        assert_eq!(reply.set_field(2, b"000001"), Ok(()));
+        let mut out = BytesMut::new();
+        codec.encode(&reply, &mut out).unwrap();
+        assert_eq!(&out[..], &b"\x00\x1F\x60\x00\x01\x00\x0208104000000000000000000001"[..]);
+        let mut owned = BytesMut::new();
+        codec.encode(reply.clone(), &mut owned).unwrap();
+        assert_eq!(owned, out);
+
+        let mut out = BytesMut::from(&b"\x00\x18"[..]);
+        assert_eq!(reply.write_to_bytes(&mut out), reply.encoded_len());
+        assert_eq!(&out[..], &b"\x00\x1808104000000000000000000001"[..]);
+    }
+
+    #[test]
+    fn iso_codec_error_test() {
+        let spec = spec();
+        let format = FrameFormat::new(LengthHeader::Ascii4, false, 0);
+        let mut codec = IsoCodec::new(&spec, format);
+        codec.set_max_frame_length(32);
+
+        let mut buf = BytesMut::from(&b"0100"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&b"002608002000000000000000009HEL"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&b"002808002000000000000000"[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"0X5HELLO");
+        assert!(codec.decode(&mut buf).is_err());
+
+        // a message over the max frame length leaves the output untouched
+        let mut reply = IsoMsg::empty(&spec);
+        assert_eq!(reply.set_field(0, b"0810"), Ok(()));
+        assert_eq!(reply.set_field(3, b"HELLO WORLD"), Ok(()));
+        let mut out = BytesMut::from(&b"head"[..]);
+        assert!(codec.encode(&reply, &mut out).is_err());
+        assert_eq!(&out[..], &b"head"[..]);
+
+        // bytes after the message
+        let mut codec = IsoCodec::new(&spec, format);
+        let mut buf = BytesMut::from(&b"002808002000000000000000005HELLO"[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        let mut buf = BytesMut::from(&b"002908002000000000000000005HELLOX"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+        assert!(codec.set_tpdu(b"\x60").is_err());
+    }
+
+    #[test]
+    fn iso_tpdu_codec_test() {
+        let spec = spec();
+        let format = FrameFormat::new(LengthHeader::Binary2, false, 5);
+        let mut codec = IsoTpduCodec::new(&spec, format);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x00\x21\x60\x00\x03\x00\x0408002000000000000000005HELLO");
+        buf.extend_from_slice(b"\x00\x21\x60\x00\x05\x00\x0608002000000000000000005WORLD");
+
+        let (tpdu, msg) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(tpdu, b"\x60\x00\x03\x00\x04".to_vec());
+        assert_eq!(msg.get_field_value(3), Ok(b"HELLO".to_vec()));
+        let (other, _) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(other, b"\x60\x00\x05\x00\x06".to_vec());
+
+        let mut reply = IsoMsg::empty(&spec);
+        assert_eq!(reply.set_field(0, b"0810"), Ok(()));
+        assert_eq!(reply.set_field(2, b"000001"), Ok(()));
+        let mut out = BytesMut::new();
+        codec.encode((&swap_tpdu(&tpdu)[..], &reply), &mut out).unwrap();
+        assert_eq!(&out[..], &b"\x00\x1F\x60\x00\x04\x00\x0308104000000000000000000001"[..]);
+        let mut owned = BytesMut::new();
+        codec.encode((swap_tpdu(&tpdu), reply), &mut owned).unwrap();
+        assert_eq!(owned, out);
+        assert!(codec.encode((vec![0x60], IsoMsg::empty(&spec)), &mut out).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Block cipher helpers for MAC and PIN processing, enabled by the `crypto` feature.
+//! Keys are plain bytes.
+
+use aes::{Aes128, Aes192, Aes256};
+use cmac::{Cmac, Mac};
+use des::{Des, TdesEde2, TdesEde3};
+use des::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use des::cipher::generic_array::GenericArray;
+
+/// Single DES (8 byte key) or triple DES (16 or 24 byte key)
+pub enum DesKey {
+    Single(Des),
+    Double(TdesEde2),
+    Triple(TdesEde3),
+}
+
+impl DesKey {
+    pub fn new(key: &[u8]) -> Result<DesKey, &'static str> {
+        match key.len() {
+            8 => Ok(DesKey::Single(Des::new_from_slice(key).unwrap())),
+            16 => Ok(DesKey::Double(TdesEde2::new_from_slice(key).unwrap())),
+            24 => Ok(DesKey::Triple(TdesEde3::new_from_slice(key).unwrap())),
+            _ => Err("DES key must be 8, 16 or 24 bytes"),
+        }
+    }
+
+    /// Encrypt an 8 byte block in place
+    pub fn encrypt_block(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            DesKey::Single(c) => c.encrypt_block(block),
+            DesKey::Double(c) => c.encrypt_block(block),
+            DesKey::Triple(c) => c.encrypt_block(block),
+        }
+    }
+
+    /// Decrypt an 8 byte block in place
+    pub fn decrypt_block(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            DesKey::Single(c) => c.decrypt_block(block),
+            DesKey::Double(c) => c.decrypt_block(block),
+            DesKey::Triple(c) => c.decrypt_block(block),
+        }
+    }
+}
+
+/// AES with a 16, 24 or 32 byte key
+pub enum AesKey {
+    Aes128(Aes128),
+    Aes192(Aes192),
+    Aes256(Aes256),
+}
+
+impl AesKey {
+    pub fn new(key: &[u8]) -> Result<AesKey, &'static str> {
+        match key.len() {
+            16 => Ok(AesKey::Aes128(Aes128::new_from_slice(key).unwrap())),
+            24 => Ok(AesKey::Aes192(Aes192::new_from_slice(key).unwrap())),
+            32 => Ok(AesKey::Aes256(Aes256::new_from_slice(key).unwrap())),
+            _ => Err("AES key must be 16, 24 or 32 bytes"),
+        }
+    }
+
+    /// Encrypt a 16 byte block in place
+    pub fn encrypt_block(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            AesKey::Aes128(c) => c.encrypt_block(block),
+            AesKey::Aes192(c) => c.encrypt_block(block),
+            AesKey::Aes256(c) => c.encrypt_block(block),
+        }
+    }
+
+    /// Decrypt a 16 byte block in place
+    pub fn decrypt_block(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            AesKey::Aes128(c) => c.decrypt_block(block),
+            AesKey::Aes192(c) => c.decrypt_block(block),
+            AesKey::Aes256(c) => c.decrypt_block(block),
+        }
+    }
+}
+
+/// AES-CMAC (NIST SP 800-38B), 16 bytes
+pub fn aes_cmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    macro_rules! cmac {
+        ($cipher:ty) => {{
+            let mut mac = <Cmac<$cipher> as Mac>::new_from_slice(key).unwrap();
+            mac.update(data);
+            Ok(mac.finalize().into_bytes().to_vec())
+        }};
+    }
+    match key.len() {
+        16 => cmac!(Aes128),
+        24 => cmac!(Aes192),
+        32 => cmac!(Aes256),
+        _ => Err("AES key must be 16, 24 or 32 bytes"),
+    }
+}
+
+/// `a ^= b`
+pub fn xor(a: &mut [u8], b: &[u8]) {
+    for (x, y) in a.iter_mut().zip(b.iter()) {
+        *x ^= *y;
+    }
+}
+
+/// Compare without an early exit
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex;
+
+    #[test]
+    fn block_cipher_test() {
+        let key = DesKey::new(&hex::decode("0123456789ABCDEF").unwrap()).unwrap();
+        let mut block = *b"Now is t";
+        key.encrypt_block(&mut block);
+        assert_eq!(hex::encode_upper(block), "3FA40E8A984D4815");
+        key.decrypt_block(&mut block);
+        assert_eq!(&block, b"Now is t");
+        assert!(DesKey::new(&[0; 12]).is_err());
+
+        // FIPS 197 appendix C.1
+        let key = AesKey::new(&hex::decode("000102030405060708090a0b0c0d0e0f").unwrap()).unwrap();
+        let mut block = hex::decode("00112233445566778899aabbccddeeff").unwrap();
+        key.encrypt_block(&mut block);
+        assert_eq!(hex::encode(&block), "69c4e0d86a7b0430d8cdb78070b4c55a");
+    }
+
+    #[test]
+    fn aes_cmac_test() {
+        // RFC 4493
+        let key = hex::decode("2b7e151628aed2a6abf7158809cf4f3c").unwrap();
+        assert_eq!(hex::encode(aes_cmac(&key, &[]).unwrap()), "bb1d6929e95937287fa37d129b756746");
+        let data = hex::decode("6bc1bee22e409f96e93d7e117393172a").unwrap();
+        assert_eq!(hex::encode(aes_cmac(&key, &data).unwrap()), "070a16b46b4d4144f79bdd9dd04a287c");
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+    }
+}
@@ -0,0 +1,365 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Field by field comparison of two messages of the same spec.
+//!
+//! Differences are reported by path: `4` for a field, `43.2` for a subfield or a field
+//! of a nested message (as in `get_field_path`), `48.AB` for an ASCII TLV tag and
+//! `55.9F26` for an EMV tag (`55.70.5A` inside a constructed tag). Fields whose
+//! structure can't be parsed are compared as a whole.
+
+use std::fmt;
+
+use ascii_tlv::parse_ascii_tlv;
+use emv_tlv::Tlv;
+use iso_field::{FieldCharType, IsoField};
+use iso_msg::IsoMsg;
+use iso_subfield::parse_subfields;
+
+/// Fields that change with every transmission
+pub const VOLATILE_FIELDS: [&str; 3] = ["7", "11", "12"];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Change {
+    /// Only in the actual message
+    Added(Vec<u8>),
+    /// Only in the expected message
+    Removed(Vec<u8>),
+    /// Expected and actual value
+    Changed(Vec<u8>, Vec<u8>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FieldDiff {
+    pub path: String,
+    pub change: Change,
+}
+
+/// `value` as text if printable, otherwise upper case hex prefixed with `0x`
+pub fn printable(value: &[u8]) -> String {
+    if value.iter().all(|b| *b >= 0x20 && *b < 0x7F) {
+        String::from_utf8_lossy(value).into_owned()
+    } else {
+        format!("0x{}", ::hex::encode_upper(value))
+    }
+}
+
+impl fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.change {
+            Change::Added(ref v) => write!(f, "+ {} [{}]", self.path, printable(v)),
+            Change::Removed(ref v) => write!(f, "- {} [{}]", self.path, printable(v)),
+            Change::Changed(ref a, ref b) => write!(f, "~ {} [{}] -> [{}]", self.path, printable(a), printable(b)),
+        }
+    }
+}
+
+pub struct DiffOptions {
+    /// Paths left out of the comparison, including everything below them
+    pub ignore: Vec<String>,
+    /// Top level fields carrying EMV BER-TLV data
+    pub emv_fields: Vec<usize>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> DiffOptions {
+        DiffOptions {
+            ignore: Vec::new(),
+            emv_fields: vec![55],
+        }
+    }
+}
+
+impl DiffOptions {
+    /// Comparing every field, same as `default()`
+    pub fn new() -> DiffOptions {
+        Default::default()
+    }
+
+    /// Also ignoring `VOLATILE_FIELDS`
+    pub fn with_volatile(mut self) -> DiffOptions {
+        self.ignore.extend(VOLATILE_FIELDS.iter().map(|s| s.to_string()));
+        self
+    }
+
+    fn is_ignored(&self, path: &str) -> bool {
+        self.ignore
+            .iter()
+            .any(|p| path == p || (path.starts_with(p.as_str()) && path[p.len()..].starts_with('.')))
+    }
+}
+
+fn is_bitmap(iso_field: &IsoField) -> bool {
+    iso_field.char_type == FieldCharType::Iso8583_bmp || iso_field.char_type == FieldCharType::Iso8583_bmps
+}
+
+fn push(out: &mut Vec<FieldDiff>, options: &DiffOptions, path: String, a: Option<Vec<u8>>, b: Option<Vec<u8>>) {
+    if options.is_ignored(&path) {
+        return;
+    }
+    let change = match (a, b) {
+        (Some(a), Some(b)) => {
+            if a == b {
+                return;
+            }
+            Change::Changed(a, b)
+        }
+        (Some(a), None) => Change::Removed(a),
+        (None, Some(b)) => Change::Added(b),
+        (None, None) => return,
+    };
+    out.push(FieldDiff { path, change });
+}
+
+/// Compare tagged values, in the order of `a` then the tags only in `b`
+fn diff_tags(out: &mut Vec<FieldDiff>, options: &DiffOptions, path: &str, a: &[(String, Vec<u8>)], b: &[(String, Vec<u8>)]) {
+    for (tag, value) in a {
+        let other = b.iter().find(|t| t.0 == *tag).map(|t| t.1.clone());
+        push(out, options, format!("{}.{}", path, tag), Some(value.clone()), other);
+    }
+    for (tag, value) in b {
+        if !a.iter().any(|t| t.0 == *tag) {
+            push(out, options, format!("{}.{}", path, tag), None, Some(value.clone()));
+        }
+    }
+}
+
+fn flatten_tlv(items: &[Tlv], prefix: &str, out: &mut Vec<(String, Vec<u8>)>) {
+    for item in items {
+        let tag = format!("{}{:X}", prefix, item.tag);
+        if item.is_constructed() {
+            flatten_tlv(&item.children, &format!("{}.", tag), out);
+        } else {
+            out.push((tag, item.value.clone()));
+        }
+    }
+}
+
+/// Differences below a field present in both messages, false if its values can't be split
+fn diff_structure(
+    out: &mut Vec<FieldDiff>,
+    options: &DiffOptions,
+    path: &str,
+    iso_field: &IsoField,
+    a: &[u8],
+    b: &[u8],
+) -> Result<bool, String> {
+    if let Some(ref nested) = iso_field.nested {
+        if IsoMsg::check_payload(nested, a).is_err() || IsoMsg::check_payload(nested, b).is_err() {
+            return Ok(false);
+        }
+        diff_fields(out, options, &format!("{}.", path), 1, &IsoMsg::new(nested, a), &IsoMsg::new(nested, b))?;
+        return Ok(true);
+    }
+    if !iso_field.subfields.is_empty() {
+        let (offsets_a, offsets_b) = match (parse_subfields(&iso_field.subfields, a), parse_subfields(&iso_field.subfields, b)) {
+            (Ok(x), Ok(y)) => (x, y),
+            _ => return Ok(false),
+        };
+        for (i, (x, y)) in offsets_a.iter().zip(offsets_b.iter()).enumerate() {
+            let x = x.map(|(offset, len)| a[offset..offset + len].to_vec());
+            let y = y.map(|(offset, len)| b[offset..offset + len].to_vec());
+            push(out, options, format!("{}.{}", path, i + 1), x, y);
+        }
+        return Ok(true);
+    }
+    if let Some(ref format) = iso_field.tlv_format {
+        return Ok(match (parse_ascii_tlv(format, a), parse_ascii_tlv(format, b)) {
+            (Ok(x), Ok(y)) => {
+                let x: Vec<(String, Vec<u8>)> = x.into_iter().map(|t| (t.tag, t.value)).collect();
+                let y: Vec<(String, Vec<u8>)> = y.into_iter().map(|t| (t.tag, t.value)).collect();
+                diff_tags(out, options, path, &x, &y);
+                true
+            }
+            _ => false,
+        });
+    }
+    Ok(false)
+}
+
+/// Value of a field if present, an error if it is present but can't be read
+fn field_value(msg: &IsoMsg, index: usize, path: &str) -> Result<Option<Vec<u8>>, String> {
+    if !msg.has_field(index) {
+        return Ok(None);
+    }
+    msg.get_field_value(index)
+        .map(Some)
+        .map_err(|e| format!("Failed to read field {}. Err: {}", path, e))
+}
+
+fn diff_fields(out: &mut Vec<FieldDiff>, options: &DiffOptions, prefix: &str, offset: usize, a: &IsoMsg, b: &IsoMsg) -> Result<(), String> {
+    for (index, iso_field) in a.get_spec().get_handle().iter().enumerate() {
+        if is_bitmap(iso_field) || (!a.has_field(index) && !b.has_field(index)) {
+            continue;
+        }
+        let path = format!("{}{}", prefix, index + offset);
+        if options.is_ignored(&path) {
+            continue;
+        }
+        let x = field_value(a, index, &path)?;
+        let y = field_value(b, index, &path)?;
+        if let (Some(x), Some(y)) = (&x, &y) {
+            if x == y {
+                continue;
+            }
+            if prefix.is_empty() && options.emv_fields.contains(&index) {
+                if let (Ok(tx), Ok(ty)) = (a.get_tlv(index), b.get_tlv(index)) {
+                    let (mut fx, mut fy) = (Vec::new(), Vec::new());
+                    flatten_tlv(&tx, "", &mut fx);
+                    flatten_tlv(&ty, "", &mut fy);
+                    diff_tags(out, options, &path, &fx, &fy);
+                    continue;
+                }
+            }
+            if diff_structure(out, options, &path, iso_field, x, y)? {
+                continue;
+            }
+        }
+        push(out, options, path, x, y);
+    }
+    Ok(())
+}
+
+/// Differences from `expected` to `actual`, both parsed with the same spec.
+/// Fails if a field present in either message can't be read
+pub fn diff_messages(expected: &IsoMsg, actual: &IsoMsg, options: &DiffOptions) -> Result<Vec<FieldDiff>, String> {
+    let mut out = Vec::new();
+    diff_fields(&mut out, options, "", 0, expected, actual)?;
+    Ok(out)
+}
+
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    /// Differences from `self` (the expected message) to `other`
+    pub fn diff(&self, other: &IsoMsg, options: &DiffOptions) -> Result<Vec<FieldDiff>, String> {
+        diff_messages(self, other, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emv_tlv::Tlv;
+    use yaml_specs::YamlSpec;
+
+    const SPEC: &str = "
+            0:
+                ContentType: n
+                LengthType: fixed
+                Length: 4
+            1:
+                ContentType: ans
+                LengthType: llvar
+                Length: 20
+            2:
+                ContentType: ans
+                LengthType: lllvar
+                Length: 999
+                SubFields:
+                    1:
+                        ContentType: n
+                        LengthType: fixed
+                        Length: 2
+                    2:
+                        ContentType: ans
+                        LengthType: llvar
+                        Length: 20
+            3:
+                ContentType: ans
+                LengthType: lllvar
+                Length: 999
+                TlvTagWidth: 2
+                TlvLengthWidth: 3
+                TlvLengthEncoding: decimal
+            4:
+                ContentType: b
+                LengthType: lllvar
+                Length: 255
+            5:
+                ContentType: ans
+                LengthType: lllvar
+                Length: 999
+                NestedFields:
+                    1:
+                        ContentType: bmps
+                        LengthType: bitmap
+                        Length: 16
+                    2:
+                        ContentType: ans
+                        LengthType: llvar
+                        Length: 32
+            ";
+
+    #[test]
+    fn diff_test() {
+        let spec = YamlSpec::new(&String::from(SPEC)).unwrap();
+        let mut expected = IsoMsg::empty(&spec);
+        expected.set_field(0, b"0100").unwrap();
+        expected.set_field(1, b"same").unwrap();
+        expected.set_field_path("2.1", b"01").unwrap();
+        expected.set_field_path("2.2", b"ABC").unwrap();
+        expected.set_ascii_tlv_tag(3, "AA", b"1").unwrap();
+        expected.set_ascii_tlv_tag(3, "BB", b"2").unwrap();
+        expected.set_tlv(4, &[Tlv::new(0x9F26, &[1, 2]), Tlv::new(0x95, &[0; 5])]).unwrap();
+        expected.set_field_path("5.2", b"nested").unwrap();
+
+        let mut actual = expected.clone();
+        assert!(expected.diff(&actual, &DiffOptions::new()).unwrap().is_empty());
+
+        actual.set_field(0, b"0110").unwrap();
+        actual.set_field_path("2.2", b"ABD").unwrap();
+        actual.remove_ascii_tlv_tag(3, "AA").unwrap();
+        actual.set_ascii_tlv_tag(3, "CC", b"3").unwrap();
+        actual.set_tlv(4, &[Tlv::new(0x9F26, &[1, 3]), Tlv::new(0x95, &[0; 5])]).unwrap();
+        actual.set_field_path("5.2", b"other").unwrap();
+
+        let mut options = DiffOptions::new();
+        options.emv_fields = vec![4];
+        let diffs = expected.diff(&actual, &options).unwrap();
+        let lines: Vec<String> = diffs.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "~ 0 [0100] -> [0110]",
+                "~ 2.2 [ABC] -> [ABD]",
+                "- 3.AA [1]",
+                "+ 3.CC [3]",
+                "~ 4.9F26 [0x0102] -> [0x0103]",
+                "~ 5.2 [nested] -> [other]",
+            ]
+        );
+
+        let mut options = DiffOptions {
+            ignore: vec!["0".to_string(), "3".to_string(), "5.2".to_string()],
+            ..DiffOptions::default()
+        };
+        options.emv_fields.clear();
+        let diffs = expected.diff(&actual, &options).unwrap();
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(diffs[1].path, "4");
+
+        actual.remove_field(1).unwrap();
+        let diffs = expected.diff(&actual, &options).unwrap();
+        assert_eq!(diffs[0], FieldDiff { path: "1".to_string(), change: Change::Removed(b"same".to_vec()) });
+    }
+
+    #[test]
+    fn volatile_fields_test() {
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut expected = IsoMsg::empty(&spec);
+        expected.set_field(0, b"0200").unwrap();
+        expected.set_field(11, b"000001").unwrap();
+        let mut actual = IsoMsg::empty(&spec);
+        actual.set_field(0, b"0200").unwrap();
+        actual.set_field(11, b"000002").unwrap();
+        actual.set_field(7, b"1019120000").unwrap();
+        assert!(expected.diff(&actual, &DiffOptions::new().with_volatile()).unwrap().is_empty());
+        assert_eq!(expected.diff(&actual, &DiffOptions::new()).unwrap().len(), 2);
+        assert_eq!(printable(b"\x00\x01"), "0x0001");
+    }
+}
@@ -0,0 +1,467 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! DUKPT per-transaction keys from a BDK and KSN.
+//! TDES DUKPT follows ANSI X9.24-1 with a 10 byte KSN, AES DUKPT follows
+//! ANSI X9.24-3 with a 12 byte KSN (initial key id and 32 bit transaction counter).
+
+use hex;
+
+use iso_crypto::{xor, AesKey, DesKey};
+use iso_field::{FieldCharType, FieldSizeType, IsoField};
+use iso_mac::MacAlgorithm;
+use iso_msg::IsoMsg;
+use iso_pin::PinBlockFormat;
+
+/// Security related control information. Networks that carry the KSN here define DE 53 as binary
+/// or as a field of at least 24 characters; the `n 16` DE 53 of spec1993 cannot hold a KSN and
+/// `set_ksn` rejects it. Others carry the KSN in a private field such as DE 48.
+pub const DE_KSN: usize = 53;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DukptAlgorithm {
+    /// ANSI X9.24-1, double length TDES keys
+    Tdes,
+    /// ANSI X9.24-3, working keys have the length of the BDK
+    Aes,
+}
+
+impl DukptAlgorithm {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<DukptAlgorithm> {
+        match s {
+            "tdes" => Some(DukptAlgorithm::Tdes),
+            "aes" => Some(DukptAlgorithm::Aes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DukptAlgorithm::Tdes => "tdes",
+            DukptAlgorithm::Aes => "aes",
+        }
+    }
+
+    pub fn ksn_len(&self) -> usize {
+        match *self {
+            DukptAlgorithm::Tdes => 10,
+            DukptAlgorithm::Aes => 12,
+        }
+    }
+
+    /// PIN block format used with the PIN key
+    pub fn pin_block_format(&self) -> PinBlockFormat {
+        match *self {
+            DukptAlgorithm::Tdes => PinBlockFormat::Format0,
+            DukptAlgorithm::Aes => PinBlockFormat::Format4,
+        }
+    }
+
+    /// MAC algorithm used with the MAC keys
+    pub fn mac_algorithm(&self) -> MacAlgorithm {
+        match *self {
+            DukptAlgorithm::Tdes => MacAlgorithm::Iso9797Alg3,
+            DukptAlgorithm::Aes => MacAlgorithm::AesCmac,
+        }
+    }
+}
+
+/// Working key derived for a transaction
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KeyUsage {
+    Pin,
+    MacRequest,
+    MacResponse,
+    DataRequest,
+    DataResponse,
+}
+
+impl KeyUsage {
+    /// TDES variant mask
+    fn variant(&self) -> &'static str {
+        match *self {
+            KeyUsage::Pin => "00000000000000FF00000000000000FF",
+            KeyUsage::MacRequest => "000000000000FF00000000000000FF00",
+            KeyUsage::MacResponse => "00000000FF00000000000000FF000000",
+            KeyUsage::DataRequest => "0000000000FF00000000000000FF0000",
+            KeyUsage::DataResponse => "000000FF00000000000000FF00000000",
+        }
+    }
+
+    /// AES key usage indicator
+    fn indicator(&self) -> u16 {
+        match *self {
+            KeyUsage::Pin => 0x1000,
+            KeyUsage::MacRequest => 0x2000,
+            KeyUsage::MacResponse => 0x2001,
+            KeyUsage::DataRequest => 0x3000,
+            KeyUsage::DataResponse => 0x3001,
+        }
+    }
+}
+
+const TDES_KEY_MASK: &str = "C0C0C0C000000000C0C0C0C000000000";
+const TDES_COUNTER_BITS: u64 = 0x1F_FFFF;
+const AES_KEY_DERIVATION: u16 = 0x8000;
+const AES_INITIAL_KEY: u16 = 0x8001;
+
+fn tdes_encrypt(key: &[u8], block: &[u8]) -> Vec<u8> {
+    let mut block = block.to_vec();
+    DesKey::new(key).unwrap().encrypt_block(&mut block);
+    block
+}
+
+fn tdes_initial_key(bdk: &[u8], ksn: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if bdk.len() != 16 {
+        return Err("TDES BDK must be 16 bytes");
+    }
+    let mut ksn = ksn[..8].to_vec();
+    ksn[7] &= 0xE0;
+    let mut bdk_mask = bdk.to_vec();
+    xor(&mut bdk_mask, &hex::decode(TDES_KEY_MASK).unwrap());
+    let mut key = tdes_encrypt(bdk, &ksn);
+    key.extend(tdes_encrypt(&bdk_mask, &ksn));
+    Ok(key)
+}
+
+/// Non-reversible key generation process
+fn tdes_next_key(key: &[u8], ksn_register: &[u8]) -> Vec<u8> {
+    let half = |key: &[u8]| {
+        let mut msg = ksn_register.to_vec();
+        xor(&mut msg, &key[8..]);
+        let mut res = tdes_encrypt(&key[..8], &msg);
+        xor(&mut res, &key[8..]);
+        res
+    };
+    let right = half(key);
+    let mut masked = key.to_vec();
+    xor(&mut masked, &hex::decode(TDES_KEY_MASK).unwrap());
+    let mut res = half(&masked);
+    res.extend(right);
+    res
+}
+
+fn tdes_working_key(initial_key: &[u8], ksn: &[u8], usage: KeyUsage) -> Result<Vec<u8>, &'static str> {
+    if initial_key.len() != 16 {
+        return Err("TDES initial key must be 16 bytes");
+    }
+    let register = ksn[2..].iter().fold(0u64, |acc, b| acc << 8 | u64::from(*b));
+    let counter = register & TDES_COUNTER_BITS;
+    let mut register = register & !TDES_COUNTER_BITS;
+    let mut key = initial_key.to_vec();
+    let mut bit = 1u64 << 20;
+    while bit > 0 {
+        if counter & bit != 0 {
+            register |= bit;
+            let bytes: Vec<u8> = (0..8).rev().map(|i| (register >> (i * 8)) as u8).collect();
+            key = tdes_next_key(&key, &bytes);
+        }
+        bit >>= 1;
+    }
+    xor(&mut key, &hex::decode(usage.variant()).unwrap());
+    match usage {
+        KeyUsage::DataRequest | KeyUsage::DataResponse => {
+            let mut data_key = tdes_encrypt(&key, &key[..8]);
+            data_key.extend(tdes_encrypt(&key, &key[8..]));
+            Ok(data_key)
+        }
+        _ => Ok(key),
+    }
+}
+
+/// AES derivation data, `counter` is `None` for the initial key
+fn aes_derivation_data(usage: u16, key_len: usize, ksn: &[u8], counter: Option<u32>) -> Vec<u8> {
+    let algorithm: u16 = match key_len {
+        16 => 2,
+        24 => 3,
+        _ => 4,
+    };
+    let bits = (key_len * 8) as u16;
+    let mut data = vec![0x01, 0x01];
+    data.extend_from_slice(&[(usage >> 8) as u8, usage as u8]);
+    data.extend_from_slice(&[(algorithm >> 8) as u8, algorithm as u8]);
+    data.extend_from_slice(&[(bits >> 8) as u8, bits as u8]);
+    match counter {
+        Some(c) => {
+            data.extend_from_slice(&ksn[4..8]);
+            data.extend_from_slice(&[(c >> 24) as u8, (c >> 16) as u8, (c >> 8) as u8, c as u8]);
+        }
+        None => data.extend_from_slice(&ksn[..8]),
+    }
+    data
+}
+
+fn aes_derive(key: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+    let cipher = AesKey::new(key)?;
+    let mut res = Vec::with_capacity(32);
+    let mut block_counter = 1u8;
+    while res.len() < key.len() {
+        data[1] = block_counter;
+        let mut block = data.clone();
+        cipher.encrypt_block(&mut block);
+        res.extend(block);
+        block_counter += 1;
+    }
+    res.truncate(key.len());
+    Ok(res)
+}
+
+fn aes_working_key(initial_key: &[u8], ksn: &[u8], usage: KeyUsage) -> Result<Vec<u8>, &'static str> {
+    let counter = ksn[8..].iter().fold(0u32, |acc, b| acc << 8 | u32::from(*b));
+    let mut key = initial_key.to_vec();
+    let mut working_counter = 0u32;
+    let mut bit = 1u32 << 31;
+    while bit > 0 {
+        if counter & bit != 0 {
+            working_counter |= bit;
+            let data = aes_derivation_data(AES_KEY_DERIVATION, key.len(), ksn, Some(working_counter));
+            key = aes_derive(&key, data)?;
+        }
+        bit >>= 1;
+    }
+    let data = aes_derivation_data(usage.indicator(), key.len(), ksn, Some(counter));
+    aes_derive(&key, data)
+}
+
+/// Initial key (IPEK) of a terminal, derived from the BDK and its KSN
+pub fn derive_initial_key(algorithm: DukptAlgorithm, bdk: &[u8], ksn: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if ksn.len() != algorithm.ksn_len() {
+        return Err("Invalid KSN length");
+    }
+    match algorithm {
+        DukptAlgorithm::Tdes => tdes_initial_key(bdk, ksn),
+        DukptAlgorithm::Aes => aes_derive(bdk, aes_derivation_data(AES_INITIAL_KEY, bdk.len(), ksn, None)),
+    }
+}
+
+/// Working key for the transaction counter in `ksn`
+pub fn derive_working_key(
+    algorithm: DukptAlgorithm,
+    initial_key: &[u8],
+    ksn: &[u8],
+    usage: KeyUsage,
+) -> Result<Vec<u8>, &'static str> {
+    if ksn.len() != algorithm.ksn_len() {
+        return Err("Invalid KSN length");
+    }
+    match algorithm {
+        DukptAlgorithm::Tdes => tdes_working_key(initial_key, ksn, usage),
+        DukptAlgorithm::Aes => aes_working_key(initial_key, ksn, usage),
+    }
+}
+
+/// Key material of one terminal, or of the host when created from the BDK
+pub struct Dukpt {
+    algorithm: DukptAlgorithm,
+    bdk: Option<Vec<u8>>,
+    initial_key: Vec<u8>,
+}
+
+impl Dukpt {
+    /// Terminal side, from its initial key
+    pub fn new(algorithm: DukptAlgorithm, initial_key: &[u8]) -> Dukpt {
+        Dukpt {
+            algorithm,
+            bdk: None,
+            initial_key: initial_key.to_vec(),
+        }
+    }
+
+    /// Host side, the initial key is derived for each KSN
+    pub fn from_bdk(algorithm: DukptAlgorithm, bdk: &[u8]) -> Dukpt {
+        Dukpt {
+            algorithm,
+            bdk: Some(bdk.to_vec()),
+            initial_key: Vec::new(),
+        }
+    }
+
+    pub fn get_algorithm(&self) -> DukptAlgorithm {
+        self.algorithm
+    }
+
+    pub fn derive_key(&self, ksn: &[u8], usage: KeyUsage) -> Result<Vec<u8>, &'static str> {
+        match self.bdk {
+            Some(ref bdk) => {
+                let initial_key = derive_initial_key(self.algorithm, bdk, ksn)?;
+                derive_working_key(self.algorithm, &initial_key, ksn, usage)
+            }
+            None => derive_working_key(self.algorithm, &self.initial_key, ksn, usage),
+        }
+    }
+}
+
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    fn ksn_field(&self, index: usize) -> Result<&IsoField, &'static str> {
+        self.get_spec().get_handle().get(index).ok_or("Spec has no KSN field")
+    }
+
+    /// 10 or 12 byte KSN in field `index`, hex decoded unless the field is binary
+    pub fn get_ksn(&self, index: usize) -> Result<Vec<u8>, &'static str> {
+        let binary = self.ksn_field(index)?.char_type == FieldCharType::Iso8583_b;
+        let value = self.get_field_value(index)?;
+        let ksn = if binary { value } else { hex::decode(&value).map_err(|_| "Invalid KSN")? };
+        if ksn.len() != 10 && ksn.len() != 12 {
+            return Err("Invalid KSN length");
+        }
+        Ok(ksn)
+    }
+
+    /// Set a 10 or 12 byte KSN in field `index`, hex encoded unless the field is binary
+    pub fn set_ksn(&mut self, index: usize, ksn: &[u8]) -> Result<(), &'static str> {
+        if ksn.len() != 10 && ksn.len() != 12 {
+            return Err("Invalid KSN length");
+        }
+        let field = self.ksn_field(index)?;
+        let value = if field.char_type == FieldCharType::Iso8583_b {
+            ksn.to_vec()
+        } else {
+            hex::encode_upper(ksn).into_bytes()
+        };
+        let fits = match field.size_type {
+            FieldSizeType::Fixed => value.len() == field.length,
+            _ => value.len() <= field.length,
+        };
+        if !fits {
+            return Err("KSN does not fit the field");
+        }
+        self.set_field(index, &value)
+    }
+
+    /// Set the KSN in field `ksn_index` and the PIN block encrypted under the DUKPT PIN key in DE 52
+    pub fn set_pin_dukpt(&mut self, dukpt: &Dukpt, ksn_index: usize, ksn: &[u8], pin: &str) -> Result<(), &'static str> {
+        let key = dukpt.derive_key(ksn, KeyUsage::Pin)?;
+        self.set_ksn(ksn_index, ksn)?;
+        self.set_pin(dukpt.get_algorithm().pin_block_format(), &key, pin)
+    }
+
+    pub fn get_pin_dukpt(&self, dukpt: &Dukpt, ksn_index: usize) -> Result<String, &'static str> {
+        let key = dukpt.derive_key(&self.get_ksn(ksn_index)?, KeyUsage::Pin)?;
+        self.get_pin(dukpt.get_algorithm().pin_block_format(), &key)
+    }
+
+    /// MAC the message with the request MAC key for the KSN in field `ksn_index`
+    pub fn set_mac_dukpt(&mut self, dukpt: &Dukpt, ksn_index: usize) -> Result<(), &'static str> {
+        let key = dukpt.derive_key(&self.get_ksn(ksn_index)?, KeyUsage::MacRequest)?;
+        self.set_mac(dukpt.get_algorithm().mac_algorithm(), &key)
+    }
+
+    pub fn verify_mac_dukpt(&self, dukpt: &Dukpt, ksn_index: usize) -> Result<(), &'static str> {
+        let key = dukpt.derive_key(&self.get_ksn(ksn_index)?, KeyUsage::MacRequest)?;
+        self.verify_mac(dukpt.get_algorithm().mac_algorithm(), &key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_specs::YamlSpec;
+
+    const TDES_BDK: &str = "0123456789ABCDEFFEDCBA9876543210";
+    const AES_BDK: &str = "FEDCBA9876543210F1F1F1F1F1F1F1F1";
+
+    fn key(algorithm: DukptAlgorithm, ik: &[u8], ksn: &str, usage: KeyUsage) -> String {
+        hex::encode_upper(derive_working_key(algorithm, ik, &hex::decode(ksn).unwrap(), usage).unwrap())
+    }
+
+    #[test]
+    fn tdes_dukpt_test() {
+        // ANSI X9.24-1 annex A
+        let bdk = hex::decode(TDES_BDK).unwrap();
+        let ksn = hex::decode("FFFF9876543210E00000").unwrap();
+        let ik = derive_initial_key(DukptAlgorithm::Tdes, &bdk, &ksn).unwrap();
+        assert_eq!(hex::encode_upper(&ik), "6AC292FAA1315B4D858AB3A3D7D5933A");
+
+        let t = DukptAlgorithm::Tdes;
+        assert_eq!(key(t, &ik, "FFFF9876543210E00001", KeyUsage::Pin), "042666B49184CF5C68DE9628D0397B36");
+        assert_eq!(key(t, &ik, "FFFF9876543210E00001", KeyUsage::MacRequest), "042666B4918430A368DE9628D03984C9");
+        assert_eq!(key(t, &ik, "FFFF9876543210E00001", KeyUsage::DataRequest), "448D3F076D8304036A55A3D7E0055A78");
+        assert_eq!(key(t, &ik, "FFFF9876543210E00002", KeyUsage::Pin), "C46551CEF9FD244FAA9AD834130D3B38");
+        assert_eq!(key(t, &ik, "FFFF9876543210E00012", KeyUsage::Pin), "9CF640F279C2AE1915F725EEEAC2CB50");
+
+        // host side derives the same key from the BDK
+        let host = Dukpt::from_bdk(t, &bdk);
+        let pin_key = host.derive_key(&hex::decode("FFFF9876543210E00001").unwrap(), KeyUsage::Pin).unwrap();
+        assert_eq!(hex::encode_upper(pin_key), "042666B49184CF5C68DE9628D0397B36");
+        assert!(derive_initial_key(t, &bdk, &ksn[..8]).is_err());
+    }
+
+    #[test]
+    fn aes_dukpt_test() {
+        // ANSI X9.24-3 AES-128 BDK
+        let bdk = hex::decode(AES_BDK).unwrap();
+        let ksn = hex::decode("123456789012345600000001").unwrap();
+        let ik = derive_initial_key(DukptAlgorithm::Aes, &bdk, &ksn).unwrap();
+        assert_eq!(hex::encode_upper(&ik), "1273671EA26AC29AFA4D1084127652A1");
+
+        let a = DukptAlgorithm::Aes;
+        assert_eq!(key(a, &ik, "123456789012345600000001", KeyUsage::Pin), "AF8CB133A78F8DC2D1359F18527593FB");
+        assert_eq!(key(a, &ik, "123456789012345600000001", KeyUsage::MacRequest), "A2DC23DE6FDE0824A2BC321E08E4B8B7");
+        assert_eq!(key(a, &ik, "123456789012345600000001", KeyUsage::DataRequest), "A35C412EFD41FDB98B69797C02DCD08F");
+        assert_eq!(key(a, &ik, "123456789012345600000002", KeyUsage::Pin), "D30BDC73EC9714B000BEC66BDB7B6D09");
+        assert_eq!(key(a, &ik, "123456789012345600000010", KeyUsage::Pin), "A09C63853B707708DEAB907BA778C191");
+    }
+
+    #[test]
+    fn msg_dukpt_test() {
+        // DE 52 long enough for the hex encoded AES PIN block
+        let s = String::from(include_str!("../spec1993.yml")).replace(
+            "Length: \"16\"\n  Label: Personal Id Number (PIN) Data\n  LengthType: fixed",
+            "Length: \"32\"\n  Label: Personal Id Number (PIN) Data\n  LengthType: llvar",
+        );
+        let spec = YamlSpec::new(&s).unwrap();
+        for &(algorithm, bdk, ksn) in &[
+            (DukptAlgorithm::Tdes, TDES_BDK, "FFFF9876543210E00001"),
+            (DukptAlgorithm::Aes, AES_BDK, "123456789012345600000001"),
+        ] {
+            let bdk = hex::decode(bdk).unwrap();
+            let ksn = hex::decode(ksn).unwrap();
+            let terminal = Dukpt::new(algorithm, &derive_initial_key(algorithm, &bdk, &ksn).unwrap());
+            let mut msg = IsoMsg::empty(&spec);
+            msg.set_field(0, b"0200").unwrap();
+            msg.set_field(2, b"4111111111111111").unwrap();
+            msg.set_pin_dukpt(&terminal, 48, &ksn, "1234").unwrap();
+            msg.set_mac_dukpt(&terminal, 48).unwrap();
+
+            let host = Dukpt::from_bdk(algorithm, &bdk);
+            assert_eq!(msg.get_ksn(48), Ok(ksn.clone()));
+            assert_eq!(msg.get_pin_dukpt(&host, 48), Ok("1234".to_string()));
+            msg.verify_mac_dukpt(&host, 48).unwrap();
+        }
+    }
+
+    #[test]
+    fn msg_ksn_test() {
+        let ksn = hex::decode("FFFF9876543210E00001").unwrap();
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut msg = IsoMsg::empty(&spec);
+        assert_eq!(msg.set_ksn(DE_KSN, &ksn), Err("KSN does not fit the field"));
+        assert_eq!(msg.set_ksn(48, &ksn[..8]), Err("Invalid KSN length"));
+
+        // a KSN of hex digits is still decoded from a character field
+        let hex_ksn = b"0123456789".to_vec();
+        msg.set_ksn(48, &hex_ksn).unwrap();
+        assert_eq!(msg.get_field_value(48), Ok(b"30313233343536373839".to_vec()));
+        assert_eq!(msg.get_ksn(48), Ok(hex_ksn));
+
+        let s = s.replace(
+            "Length: \"16\"\n  ContentType: ns",
+            "Length: \"12\"\n  ContentType: b",
+        ).replace(
+            "Label: Security Related Control Information\n  LengthType: fixed",
+            "Label: Security Related Control Information\n  LengthType: llvar",
+        );
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut msg = IsoMsg::empty(&spec);
+        msg.set_ksn(DE_KSN, &ksn).unwrap();
+        assert_eq!(msg.get_field_value(DE_KSN), Ok(ksn.clone()));
+        assert_eq!(msg.get_ksn(DE_KSN), Ok(ksn));
+    }
+}
@@ -6,6 +6,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use iso_msg::IsoSpecs;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum FieldCharType {
@@ -67,6 +68,9 @@ pub enum FieldSizeType {
     LlVar,
     LllVar,
     LlllVar,
+    LlllllVar,
+    /// LL var with a 2 digit length prefix
+    LlVar2,
     BitMap,
 }
 
@@ -78,6 +82,8 @@ impl FieldSizeType {
             "llvar" => Some(FieldSizeType::LlVar),
             "lllvar" => Some(FieldSizeType::LllVar),
             "llllvar" => Some(FieldSizeType::LlllVar),
+            "llllllvar" => Some(FieldSizeType::LlllllVar),
+            "llvar2" => Some(FieldSizeType::LlVar2),
             "bitmap" => Some(FieldSizeType::BitMap),
             _ => None,
         }
@@ -89,9 +95,104 @@ impl FieldSizeType {
             &FieldSizeType::LlVar => "llvar",
             &FieldSizeType::LllVar => "lllvar",
             &FieldSizeType::LlllVar => "llllvar",
+            &FieldSizeType::LlllllVar => "llllllvar",
+            &FieldSizeType::LlVar2 => "llvar2",
             &FieldSizeType::BitMap => "bitmap",
         }
     }
+
+    /// Digits of the length prefix on the wire. LL and LLL var fields both carry
+    /// 3 digits, as in the spec1993 payloads. LLLL var fields carry 4 digits
+    /// (3 up to 0.1.1). The same width applies to fields, subfields and nested fields
+    pub fn prefix_width(&self) -> usize {
+        match self {
+            &FieldSizeType::LlVar | &FieldSizeType::LllVar => 3,
+            &FieldSizeType::LlllVar => 4,
+            &FieldSizeType::LlllllVar => 6,
+            &FieldSizeType::LlVar2 => 2,
+            &FieldSizeType::Fixed | &FieldSizeType::BitMap => 0,
+        }
+    }
+}
+
+/// Length encoding of an ASCII TLV element
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum TlvLengthEncoding {
+    Decimal,
+    Hex,
+    Binary,
+}
+
+impl TlvLengthEncoding {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<TlvLengthEncoding> {
+        let s_lower = s.to_lowercase();
+        match s_lower.as_str() {
+            "decimal" => Some(TlvLengthEncoding::Decimal),
+            "hex" => Some(TlvLengthEncoding::Hex),
+            "binary" => Some(TlvLengthEncoding::Binary),
+            _ => None,
+        }
+    }
+
+    /// Widest length that can be decoded into a `usize` with this encoding
+    pub fn max_width(&self) -> usize {
+        match self {
+            TlvLengthEncoding::Decimal => 19,
+            TlvLengthEncoding::Hex => 16,
+            TlvLengthEncoding::Binary => 8,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlvLengthEncoding::Decimal => "decimal",
+            TlvLengthEncoding::Hex => "hex",
+            TlvLengthEncoding::Binary => "binary",
+        }
+    }
+}
+
+/// Tag-length-value layout of a private field e.g. 2 char tag followed by 3 digit length
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AsciiTlvFormat {
+    pub tag_width: usize,
+    pub length_width: usize,
+    pub length_encoding: TlvLengthEncoding,
+}
+
+impl AsciiTlvFormat {
+    pub fn new(tag_width: usize, length_width: usize, length_encoding: TlvLengthEncoding) -> AsciiTlvFormat {
+        AsciiTlvFormat {
+            tag_width,
+            length_width,
+            length_encoding,
+        }
+    }
+
+    /// Tag and length must each be at least 1 wide, the length no wider than its encoding allows
+    pub fn check(&self) -> Result<(), &'static str> {
+        if self.tag_width == 0 || self.length_width == 0 {
+            return Err("TLV tag and length width must be at least 1");
+        }
+        if self.length_width > self.length_encoding.max_width() {
+            return Err("TLV length width exceeds the length encoding range");
+        }
+        Ok(())
+    }
+}
+
+/// Spec of a sub-message with its own bitmap, carried in a composite field (DE 127 style).
+/// Index 0 is the bitmap, index `n - 1` is nested field `n` e.g. `127.22`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct NestedSpec {
+    pub handle: Vec<IsoField>,
+}
+
+impl IsoSpecs for NestedSpec {
+    fn get_handle(&self) -> &Vec<IsoField> {
+        &self.handle
+    }
 }
 
 /// `IsoField` defination
@@ -101,6 +202,12 @@ pub struct IsoField {
     pub char_type: FieldCharType,
     pub size_type: FieldSizeType,
     pub length: usize,
+    /// Subfields of a composite field, empty for a plain field
+    pub subfields: Vec<IsoField>,
+    /// Set if the field content is ASCII tag-length-value
+    pub tlv_format: Option<AsciiTlvFormat>,
+    /// Set if the field content is a sub-message
+    pub nested: Option<NestedSpec>,
 }
 
 /// `IsoField` implementation
@@ -116,12 +223,27 @@ impl IsoField {
             char_type: char_type,
             length: length,
             size_type: size_type,
+            subfields: Vec::new(),
+            tlv_format: None,
+            nested: None,
         }
     }
+
+    pub fn new_composite(
+        label: &str,
+        char_type: FieldCharType,
+        length: usize,
+        size_type: FieldSizeType,
+        subfields: Vec<IsoField>,
+    ) -> IsoField {
+        let mut iso_field = IsoField::new(label, char_type, length, size_type);
+        iso_field.subfields = subfields;
+        iso_field
+    }
 }
 
 /// Field Payload
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct FieldPayload {
     pub exist: bool,
     pub index: usize,
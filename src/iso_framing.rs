@@ -0,0 +1,309 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Wire framing of messages on a stream.
+//!
+//! Each message is sent as `[length header][TPDU][MTI ...]`. The length header
+//! is 2 or 4 byte big-endian binary or 4 ASCII digits, and may or may not count
+//! its own bytes. The TPDU (or network header) is optional and fixed length.
+
+use std::str;
+use iso_msg::IsoMsg;
+
+/// Default max frame length, header included
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8192;
+
+/// Encoding of the length header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LengthHeader {
+    Binary2,
+    Binary4,
+    Ascii4,
+}
+
+impl LengthHeader {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<LengthHeader> {
+        let s_lower = s.to_lowercase();
+        match s_lower.as_str() {
+            "binary2" => Some(LengthHeader::Binary2),
+            "binary4" => Some(LengthHeader::Binary4),
+            "ascii4" => Some(LengthHeader::Ascii4),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            LengthHeader::Binary2 => "binary2",
+            LengthHeader::Binary4 => "binary4",
+            LengthHeader::Ascii4 => "ascii4",
+        }
+    }
+
+    /// Number of bytes of the header
+    pub fn size(&self) -> usize {
+        match self {
+            &LengthHeader::Binary2 => 2,
+            &LengthHeader::Binary4 | &LengthHeader::Ascii4 => 4,
+        }
+    }
+
+    fn max_value(&self) -> usize {
+        match *self {
+            LengthHeader::Binary2 => 0xFFFF,
+            LengthHeader::Binary4 => 0xFFFF_FFFF,
+            LengthHeader::Ascii4 => 9999,
+        }
+    }
+}
+
+/// Framing used on a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameFormat {
+    pub header: LengthHeader,
+    /// true if the length value counts the header bytes
+    pub inclusive: bool,
+    /// Length of the TPDU / network header before the MTI, 0 if none
+    pub tpdu_length: usize,
+    /// Longest frame accepted or produced, header included
+    pub max_length: usize,
+}
+
+/// A message read from the wire
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub tpdu: Vec<u8>,
+    pub body: Vec<u8>,
+}
+
+impl FrameFormat {
+    pub fn new(header: LengthHeader, inclusive: bool, tpdu_length: usize) -> FrameFormat {
+        FrameFormat {
+            header,
+            inclusive,
+            tpdu_length,
+            max_length: DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+
+    pub fn with_max_length(mut self, max_length: usize) -> FrameFormat {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Total frame length (header included) if `data` holds a complete header
+    pub fn frame_length(&self, data: &[u8]) -> Result<Option<usize>, &'static str> {
+        let header_len = self.header.size();
+        if data.len() < header_len {
+            return Ok(None);
+        }
+        let value = match self.header {
+            LengthHeader::Binary2 | LengthHeader::Binary4 => {
+                let mut v = 0usize;
+                for b in data[..header_len].iter() {
+                    v = (v << 8) | *b as usize;
+                }
+                v
+            }
+            LengthHeader::Ascii4 => {
+                let digits = match str::from_utf8(&data[..header_len]) {
+                    Ok(d) => d,
+                    Err(_) => return Err("Invalid length header"),
+                };
+                if !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err("Invalid length header");
+                }
+                digits.parse::<usize>().unwrap()
+            }
+        };
+        let total = if self.inclusive {
+            if value < header_len {
+                return Err("Length header smaller than itself");
+            }
+            value
+        } else {
+            value + header_len
+        };
+        if total < header_len + self.tpdu_length {
+            return Err("Frame shorter than TPDU");
+        }
+        if total > self.max_length {
+            return Err("Frame exceeds max frame length");
+        }
+        Ok(Some(total))
+    }
+
+    /// Decode the first frame of `data`. Returns `None` until the frame is complete,
+    /// otherwise the frame and the number of bytes it used
+    pub fn decode(&self, data: &[u8]) -> Result<Option<(Frame, usize)>, &'static str> {
+        let total = match self.frame_length(data)? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        if data.len() < total {
+            return Ok(None);
+        }
+        let tpdu_start = self.header.size();
+        let body_start = tpdu_start + self.tpdu_length;
+        let frame = Frame {
+            tpdu: data[tpdu_start..body_start].to_vec(),
+            body: data[body_start..total].to_vec(),
+        };
+        Ok(Some((frame, total)))
+    }
+
+    /// Length header of a frame whose TPDU and body are `len` bytes
+    pub fn length_header(&self, len: usize) -> Result<Vec<u8>, &'static str> {
+        let header_len = self.header.size();
+        let value = if self.inclusive { len + header_len } else { len };
+        if value > self.header.max_value() || header_len + len > self.max_length {
+            return Err("Message exceeds max frame length");
+        }
+        let mut out = Vec::with_capacity(header_len);
+        match self.header {
+            LengthHeader::Binary2 | LengthHeader::Binary4 => {
+                for i in (0..header_len).rev() {
+                    out.push((value >> (8 * i)) as u8);
+                }
+            }
+            LengthHeader::Ascii4 => {
+                out.extend_from_slice(format!("{:04}", value).as_bytes());
+            }
+        }
+        Ok(out)
+    }
+
+    /// Prefix `body` with the length header and `tpdu`
+    pub fn encode(&self, tpdu: &[u8], body: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if tpdu.len() != self.tpdu_length {
+            return Err("TPDU does not match TPDU length");
+        }
+        let mut out = self.length_header(tpdu.len() + body.len())?;
+        out.reserve(tpdu.len() + body.len());
+        out.extend_from_slice(tpdu);
+        out.extend_from_slice(body);
+        Ok(out)
+    }
+}
+
+/// Split `data` into complete frames. Also returns the number of bytes used,
+/// the remainder is the start of an incomplete frame
+pub fn split_frames(format: &FrameFormat, data: &[u8]) -> Result<(Vec<Frame>, usize), &'static str> {
+    let mut frames = Vec::new();
+    let mut index = 0usize;
+    while let Some((frame, used)) = format.decode(&data[index..])? {
+        frames.push(frame);
+        index += used;
+    }
+    Ok((frames, index))
+}
+
+/// TPDU of a response: the destination and source addresses of the 5 byte
+/// `[id][destination][source]` request TPDU swapped. Other lengths are returned as is
+pub fn swap_tpdu(tpdu: &[u8]) -> Vec<u8> {
+    if tpdu.len() != 5 {
+        return tpdu.to_vec();
+    }
+    vec![tpdu[0], tpdu[3], tpdu[4], tpdu[1], tpdu[2]]
+}
+
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    /// `to_byte_array` output framed for the wire
+    pub fn to_frame(&self, format: &FrameFormat, tpdu: &[u8]) -> Result<Vec<u8>, &'static str> {
+        format.encode(tpdu, &self.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_specs::YamlSpec;
+
+    #[test]
+    fn frame_format_test() {
+        let format = FrameFormat::new(LengthHeader::Binary2, false, 0);
+        let data = format.encode(&[], b"0800ABC").unwrap();
+        assert_eq!(data, b"\x00\x070800ABC".to_vec());
+
+        let format = FrameFormat::new(LengthHeader::Binary4, true, 0);
+        assert_eq!(format.encode(&[], b"0800").unwrap(), b"\x00\x00\x00\x080800".to_vec());
+
+        let format = FrameFormat::new(LengthHeader::Ascii4, false, 5);
+        let data = format.encode(b"\x60\x00\x01\x00\x00", b"0800").unwrap();
+        assert_eq!(&data[..4], b"0009");
+        let (frame, used) = format.decode(&data).unwrap().unwrap();
+        assert_eq!(used, data.len());
+        assert_eq!(frame.tpdu, b"\x60\x00\x01\x00\x00".to_vec());
+        assert_eq!(frame.body, b"0800".to_vec());
+
+        assert!(format.encode(b"\x60", b"0800").is_err());
+        assert!(format.decode(b"00A1").is_err());
+        assert!(FrameFormat::new(LengthHeader::Binary2, true, 0).decode(b"\x00\x01").is_err());
+        assert!(FrameFormat::new(LengthHeader::Ascii4, false, 0).encode(&[], &[b'0'; 10000]).is_err());
+
+        let format = FrameFormat::new(LengthHeader::Binary4, false, 0);
+        assert_eq!(format.frame_length(b"\xFF\xFF\xFF\xF0"), Err("Frame exceeds max frame length"));
+        assert!(format.encode(&[], &[b'0'; DEFAULT_MAX_FRAME_LENGTH]).is_err());
+        let format = format.with_max_length(0x10_0000);
+        assert_eq!(format.frame_length(b"\x00\x01\x00\x00"), Ok(Some(0x1_0004)));
+        assert!(format.encode(&[], &[b'0'; DEFAULT_MAX_FRAME_LENGTH]).is_ok());
+        assert_eq!(LengthHeader::from_str("ASCII4"), Some(LengthHeader::Ascii4));
+        assert_eq!(swap_tpdu(b"\x60\x00\x01\x00\x02"), b"\x60\x00\x02\x00\x01".to_vec());
+    }
+
+    #[test]
+    fn split_frames_test() {
+        let format = FrameFormat::new(LengthHeader::Binary2, true, 0);
+        let mut data = format.encode(&[], b"0800A").unwrap();
+        data.extend(format.encode(&[], b"0810BC").unwrap());
+        data.extend_from_slice(b"\x00\x0A08");
+
+        let (frames, used) = split_frames(&format, &data).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].body, b"0800A".to_vec());
+        assert_eq!(frames[1].body, b"0810BC".to_vec());
+        assert_eq!(&data[used..], b"\x00\x0A08");
+
+        let (frames, used) = split_frames(&format, b"\x00").unwrap();
+        assert!(frames.is_empty());
+        assert_eq!(used, 0);
+    }
+
+    #[test]
+    fn iso_msg_to_frame_test() {
+        let s = String::from(
+            "
+            0:
+                ContentType: n
+                LengthType: fixed
+                Length: 4
+            1:
+                ContentType: bmps
+                LengthType: bitmap
+                Length: 16
+            2:
+                ContentType: n
+                LengthType: fixed
+                Length: 6
+            ",
+        );
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut iso_msg = IsoMsg::empty(&spec);
+        assert_eq!(iso_msg.set_field(0, b"0800"), Ok(()));
+        assert_eq!(iso_msg.set_field(2, b"123456"), Ok(()));
+
+        let format = FrameFormat::new(LengthHeader::Ascii4, false, 0);
+        let data = iso_msg.to_frame(&format, &[]).unwrap();
+        assert_eq!(data, b"002608004000000000000000123456".to_vec());
+
+        let (frame, _) = format.decode(&data).unwrap().unwrap();
+        let parsed = IsoMsg::new(&spec, &frame.body);
+        assert_eq!(parsed.get_field_value(2), Ok(b"123456".to_vec()));
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lazy decoding: a read-only view of a payload that indexes field offsets only up
+//! to the highest field requested, e.g. the MTI and DE 3 to route a message.
+//!
+//! Field values are borrowed from the payload. `LazyIsoMsg::to_msg` parses the full
+//! `IsoMsg` when the message has to be modified.
+
+use std::str;
+use iso_field::FieldSizeType;
+use iso_msg::{IsoMsg, IsoSpecs};
+
+/// Why indexing stopped
+pub(crate) enum IndexError {
+    /// The payload must be at least this long
+    Truncated(usize),
+    Invalid(&'static str),
+}
+
+pub struct LazyIsoMsg<'a, 'b> {
+    payload: &'a [u8],
+    iso_spec: &'b dyn IsoSpecs,
+    bitmap_field_index: Option<usize>,
+    /// Bits of the bitmap(s) once read, bit 0 is the secondary bitmap indicator
+    bits: Vec<bool>,
+    /// Offset and length in the payload of the fields indexed so far, `None` if absent
+    fields: Vec<Option<(usize, usize)>>,
+    /// Payload offset following the last field indexed
+    offset: usize,
+}
+
+impl<'a, 'b> LazyIsoMsg<'a, 'b> {
+    /// Nothing is read until a field is requested
+    pub fn new(iso_spec: &'b dyn IsoSpecs, payload: &'a [u8]) -> LazyIsoMsg<'a, 'b> {
+        LazyIsoMsg {
+            payload,
+            iso_spec,
+            bitmap_field_index: IsoMsg::get_bitmap_field_index(iso_spec),
+            bits: Vec::new(),
+            fields: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    pub fn get_spec(&self) -> &'b dyn IsoSpecs {
+        self.iso_spec
+    }
+
+    /// Number of fields indexed so far
+    pub fn indexed(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Payload offset following the last field indexed
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Index the fields up to and including `index`
+    pub(crate) fn index_to(&mut self, index: usize) -> Result<(), IndexError> {
+        let handle = self.iso_spec.get_handle();
+        while self.fields.len() <= index && self.fields.len() < handle.len() {
+            let i = self.fields.len();
+            if Some(i) == self.bitmap_field_index {
+                let start = self.offset;
+                self.read_bitmap(handle.len() - i - 1)?;
+                self.fields.push(Some((start, self.offset - start)));
+                continue;
+            }
+            if let Some(b) = self.bitmap_field_index {
+                if i > b {
+                    if i - b >= 128 {
+                        return Err(IndexError::Invalid("Spec has more fields than the bitmap"));
+                    }
+                    if !self.bits.get(i - b).cloned().unwrap_or(false) {
+                        self.fields.push(None);
+                        continue;
+                    }
+                }
+            }
+            let iso_field = &handle[i];
+            let len = match iso_field.size_type {
+                FieldSizeType::Fixed => iso_field.length,
+                FieldSizeType::LlVar |
+                FieldSizeType::LllVar |
+                FieldSizeType::LlllVar |
+                FieldSizeType::LlllllVar |
+                FieldSizeType::LlVar2 => {
+                    let width = iso_field.size_type.prefix_width();
+                    if self.offset + width > self.payload.len() {
+                        return Err(IndexError::Truncated(self.offset + width));
+                    }
+                    let digits = &self.payload[self.offset..self.offset + width];
+                    if !digits.iter().all(|b| b.is_ascii_digit()) {
+                        return Err(IndexError::Invalid("Invalid field length"));
+                    }
+                    let len = str::from_utf8(digits).unwrap().parse::<usize>().unwrap();
+                    if len > iso_field.length {
+                        return Err(IndexError::Invalid("Field length exceeds max length"));
+                    }
+                    len + width
+                }
+                FieldSizeType::BitMap => 0,
+            };
+            if self.offset + len > self.payload.len() {
+                return Err(IndexError::Truncated(self.offset + len));
+            }
+            self.fields.push(Some((self.offset, len)));
+            self.offset += len;
+        }
+        Ok(())
+    }
+
+    /// Read 64 bit hex bitmaps while the first bit is set, enough for `num_fields`
+    fn read_bitmap(&mut self, num_fields: usize) -> Result<(), IndexError> {
+        let max_bits = num_fields.div_ceil(128).max(1) * 128;
+        loop {
+            if self.offset + 16 > self.payload.len() {
+                return Err(IndexError::Truncated(self.offset + 16));
+            }
+            let hex = &self.payload[self.offset..self.offset + 16];
+            if !hex.iter().all(|b| b.is_ascii_hexdigit()) {
+                return Err(IndexError::Invalid("Invalid bitmap"));
+            }
+            let chunk = u64::from_str_radix(str::from_utf8(hex).unwrap(), 16).unwrap();
+            for x in (0..64).rev() {
+                self.bits.push(chunk & (1 << x) != 0);
+            }
+            self.offset += 16;
+            if chunk >> 63 == 0 || self.bits.len() >= max_bits {
+                return Ok(());
+            }
+        }
+    }
+
+    fn get_entry(&mut self, index: usize) -> Result<Option<(usize, usize)>, &'static str> {
+        if index >= self.iso_spec.get_handle().len() {
+            return Err("Invalid field index");
+        }
+        match self.index_to(index) {
+            Ok(()) => Ok(self.fields[index]),
+            Err(IndexError::Truncated(_)) => Err("Payload is truncated"),
+            Err(IndexError::Invalid(e)) => Err(e),
+        }
+    }
+
+    pub fn has_field(&mut self, index: usize) -> Result<bool, &'static str> {
+        Ok(self.get_entry(index)?.is_some())
+    }
+
+    /// Value of field `index` without its length prefix, borrowed from the payload
+    pub fn get_field(&mut self, index: usize) -> Result<&'a [u8], &'static str> {
+        let (offset, len) = match self.get_entry(index)? {
+            Some(e) => e,
+            None => return Err("Field not set"),
+        };
+        let prefix = self.iso_spec.get_handle()[index].size_type.prefix_width();
+        let payload = self.payload;
+        Ok(&payload[offset + prefix..offset + len])
+    }
+
+    /// Full message, after checking the payload
+    pub fn to_msg(&self) -> Result<IsoMsg<'a, 'b>, &'static str> {
+        IsoMsg::check_payload(self.iso_spec, self.payload)?;
+        Ok(IsoMsg::new(self.iso_spec, self.payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_specs::YamlSpec;
+
+    #[test]
+    fn lazy_msg_test() {
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut msg = IsoMsg::empty(&spec);
+        msg.set_field(0, b"0200").unwrap();
+        msg.set_field(2, b"4111111111111111").unwrap();
+        msg.set_field(3, b"000000").unwrap();
+        msg.set_field(41, b"TERM0001").unwrap();
+        msg.set_field(128, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let mut buffer = vec![0u8; 512];
+        let len = msg.to_byte_array(&mut buffer);
+        let payload = &buffer[..len];
+
+        let mut lazy = LazyIsoMsg::new(&spec, payload);
+        assert_eq!(lazy.indexed(), 0);
+        assert_eq!(lazy.get_field(0), Ok(&b"0200"[..]));
+        assert_eq!(lazy.indexed(), 1);
+        assert_eq!(lazy.get_field(3), Ok(&b"000000"[..]));
+        assert_eq!(lazy.indexed(), 4);
+        assert_eq!(lazy.get_field(2), Ok(&b"4111111111111111"[..]));
+        assert_eq!(lazy.has_field(4), Ok(false));
+        assert_eq!(lazy.get_field(4), Err("Field not set"));
+        assert_eq!(lazy.get_field(41), Ok(&b"TERM0001"[..]));
+        assert_eq!(lazy.get_field(128), Ok(&[1u8, 2, 3, 4, 5, 6, 7, 8][..]));
+        assert_eq!(lazy.get_field(129), Err("Invalid field index"));
+        assert_eq!(lazy.to_msg().unwrap().get_field_value(41), Ok(b"TERM0001".to_vec()));
+
+        let mut truncated = LazyIsoMsg::new(&spec, &payload[..60]);
+        assert_eq!(truncated.get_field(2), Ok(&b"4111111111111111"[..]));
+        assert_eq!(truncated.get_field(3), Err("Payload is truncated"));
+        assert!(truncated.to_msg().is_err());
+        let mut invalid = LazyIsoMsg::new(&spec, b"0200ZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZ");
+        assert_eq!(invalid.get_field(0), Ok(&b"0200"[..]));
+        assert_eq!(invalid.get_field(2), Err("Invalid bitmap"));
+    }
+}
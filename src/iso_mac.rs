@@ -0,0 +1,233 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Message Authentication Code in DE 64 or DE 128.
+//! The MAC covers the encoded message up to, but excluding, the MAC field itself.
+
+use iso_crypto::{aes_cmac, constant_time_eq, xor, DesKey};
+use iso_field::FieldSizeType;
+use iso_msg::IsoMsg;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MacAlgorithm {
+    /// ISO 9797-1 algorithm 1 (CBC-MAC) with DES or triple DES, padding method 1
+    Iso9797Alg1,
+    /// ISO 9797-1 algorithm 3 (retail MAC) with a double length DES key, padding method 1
+    Iso9797Alg3,
+    /// AES-CMAC with a 16, 24 or 32 byte key
+    AesCmac,
+}
+
+impl MacAlgorithm {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<MacAlgorithm> {
+        match s {
+            "alg1" => Some(MacAlgorithm::Iso9797Alg1),
+            "alg3" => Some(MacAlgorithm::Iso9797Alg3),
+            "aes-cmac" => Some(MacAlgorithm::AesCmac),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            MacAlgorithm::Iso9797Alg1 => "alg1",
+            MacAlgorithm::Iso9797Alg3 => "alg3",
+            MacAlgorithm::AesCmac => "aes-cmac",
+        }
+    }
+}
+
+/// CBC-MAC over `data` zero padded to a multiple of 8 bytes (a full block if empty)
+fn cbc_mac(key: &DesKey, data: &[u8]) -> [u8; 8] {
+    let mut mac = [0u8; 8];
+    let blocks = if data.is_empty() { 1 } else { data.len().div_ceil(8) };
+    for i in 0..blocks {
+        let end = if (i + 1) * 8 < data.len() { (i + 1) * 8 } else { data.len() };
+        xor(&mut mac, &data[i * 8..end]);
+        key.encrypt_block(&mut mac);
+    }
+    mac
+}
+
+/// Full MAC block over `data`, 8 bytes for the DES algorithms and 16 for AES-CMAC
+pub fn compute_mac(algorithm: MacAlgorithm, key: &[u8], data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    match algorithm {
+        MacAlgorithm::Iso9797Alg1 => Ok(cbc_mac(&DesKey::new(key)?, data).to_vec()),
+        MacAlgorithm::Iso9797Alg3 => {
+            if key.len() != 16 {
+                return Err("ISO 9797-1 algorithm 3 needs a 16 byte key");
+            }
+            let mut mac = cbc_mac(&DesKey::new(&key[..8])?, data);
+            DesKey::new(&key[8..])?.decrypt_block(&mut mac);
+            DesKey::new(&key[..8])?.encrypt_block(&mut mac);
+            Ok(mac.to_vec())
+        }
+        MacAlgorithm::AesCmac => aes_cmac(key, data),
+    }
+}
+
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    /// DE 128 if any secondary bitmap field is set, otherwise DE 64
+    pub fn get_mac_field_index(&self) -> Result<usize, &'static str> {
+        let bitmap = match IsoMsg::get_bitmap_field_index(&self.get_spec()) {
+            Some(b) => b,
+            None => return Err("Spec has no bitmap"),
+        };
+        let secondary = (bitmap + 64..bitmap + 127).any(|i| self.has_field(i));
+        let index = if secondary { bitmap + 127 } else { bitmap + 63 };
+        if index >= self.get_spec().get_handle().len() {
+            return Err("Spec has no MAC field");
+        }
+        Ok(index)
+    }
+
+    fn encode_mac_data(&self, mac_index: usize) -> Result<Vec<u8>, &'static str> {
+        if (mac_index + 1..self.get_spec().get_handle().len()).any(|i| self.has_field(i)) {
+            return Err("MAC field must be the last field");
+        }
+        let mac_len = self.get_field_value(mac_index)?.len();
+        let mut buffer = self.to_vec();
+        let len = buffer.len() - mac_len;
+        buffer.truncate(len);
+        Ok(buffer)
+    }
+
+    /// Compute the MAC of the message and set it in DE 64 or DE 128,
+    /// truncated to the field length
+    pub fn set_mac(&mut self, algorithm: MacAlgorithm, key: &[u8]) -> Result<(), &'static str> {
+        let bitmap = IsoMsg::get_bitmap_field_index(&self.get_spec()).unwrap_or(0);
+        for i in &[bitmap + 63, bitmap + 127] {
+            if self.has_field(*i) {
+                self.remove_field(*i)?;
+            }
+        }
+        let index = self.get_mac_field_index()?;
+        let mac_field = &self.get_spec().get_handle()[index];
+        if mac_field.size_type != FieldSizeType::Fixed {
+            return Err("MAC field must be fixed length");
+        }
+        let mac_len = mac_field.length;
+        self.set_field(index, &vec![0u8; mac_len])?;
+        let mac = compute_mac(algorithm, key, &self.encode_mac_data(index)?)?;
+        if mac_len > mac.len() {
+            return Err("MAC field is longer than the MAC");
+        }
+        self.set_field(index, &mac[..mac_len])
+    }
+
+    /// Check the MAC in DE 64 or DE 128 of a received message,
+    /// the received MAC must fill the field
+    pub fn verify_mac(&self, algorithm: MacAlgorithm, key: &[u8]) -> Result<(), &'static str> {
+        let index = self.get_mac_field_index()?;
+        if !self.has_field(index) {
+            return Err("Message has no MAC");
+        }
+        let mac_field = &self.get_spec().get_handle()[index];
+        if mac_field.size_type != FieldSizeType::Fixed {
+            return Err("MAC field must be fixed length");
+        }
+        let mac_len = mac_field.length;
+        let received = self.get_field_value(index)?;
+        let mac = compute_mac(algorithm, key, &self.encode_mac_data(index)?)?;
+        if mac_len > mac.len() {
+            return Err("MAC field is longer than the MAC");
+        }
+        if received.len() != mac_len || !constant_time_eq(&received, &mac[..mac_len]) {
+            return Err("MAC mismatch");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex;
+    use yaml_specs::YamlSpec;
+
+    const DES_KEY: &str = "0123456789ABCDEF";
+    const TDES_KEY: &str = "0123456789ABCDEFFEDCBA9876543210";
+
+    #[test]
+    fn compute_mac_test() {
+        let data = b"7654321 Now is the time for ";
+        let des = hex::decode(DES_KEY).unwrap();
+        let tdes = hex::decode(TDES_KEY).unwrap();
+        // ANSI X9.9 example
+        let mac = compute_mac(MacAlgorithm::Iso9797Alg1, &des, data).unwrap();
+        assert_eq!(hex::encode(mac), "f1d30f6849312ca4");
+        let mac = compute_mac(MacAlgorithm::Iso9797Alg1, &tdes, data).unwrap();
+        assert_eq!(hex::encode(mac), "e5e7a413c3e3f4b5");
+        let mac = compute_mac(MacAlgorithm::Iso9797Alg3, &tdes, data).unwrap();
+        assert_eq!(hex::encode(mac), "ae4b45b1b527642f");
+        assert!(compute_mac(MacAlgorithm::Iso9797Alg3, &des, data).is_err());
+
+        let key = hex::decode("2b7e151628aed2a6abf7158809cf4f3c").unwrap();
+        let mac = compute_mac(MacAlgorithm::AesCmac, &key, &[]).unwrap();
+        assert_eq!(hex::encode(mac), "bb1d6929e95937287fa37d129b756746");
+
+        assert_eq!(MacAlgorithm::from_str("alg3"), Some(MacAlgorithm::Iso9797Alg3));
+        assert_eq!(MacAlgorithm::AesCmac.as_str(), "aes-cmac");
+    }
+
+    #[test]
+    fn set_verify_mac_test() {
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let key = hex::decode(TDES_KEY).unwrap();
+        let mut msg = IsoMsg::empty(&spec);
+        msg.set_field(0, b"0200").unwrap();
+        msg.set_field(4, b"000000001000").unwrap();
+        msg.set_field(11, b"000001").unwrap();
+
+        msg.set_mac(MacAlgorithm::Iso9797Alg3, &key).unwrap();
+        assert!(msg.has_field(64));
+        assert!(!msg.has_field(128));
+        msg.verify_mac(MacAlgorithm::Iso9797Alg3, &key).unwrap();
+
+        let buffer = msg.to_vec();
+        let expected = compute_mac(MacAlgorithm::Iso9797Alg3, &key, &buffer[..buffer.len() - 8]).unwrap();
+        assert_eq!(msg.get_field_value(64), Ok(expected));
+
+        let received = IsoMsg::new(&spec, &buffer);
+        received.verify_mac(MacAlgorithm::Iso9797Alg3, &key).unwrap();
+        assert_eq!(received.verify_mac(MacAlgorithm::Iso9797Alg1, &key), Err("MAC mismatch"));
+
+        msg.set_field(4, b"000000009999").unwrap();
+        assert_eq!(msg.verify_mac(MacAlgorithm::Iso9797Alg3, &key), Err("MAC mismatch"));
+
+        // a secondary bitmap field moves the MAC to DE 128
+        msg.set_field(70, b"001").unwrap();
+        let aes_key = hex::decode("2b7e151628aed2a6abf7158809cf4f3c").unwrap();
+        msg.set_mac(MacAlgorithm::AesCmac, &aes_key).unwrap();
+        assert!(!msg.has_field(64));
+        assert_eq!(msg.get_field_value(128).unwrap().len(), 8);
+        msg.verify_mac(MacAlgorithm::AesCmac, &aes_key).unwrap();
+
+        msg.remove_field(128).unwrap();
+        assert_eq!(msg.verify_mac(MacAlgorithm::AesCmac, &aes_key), Err("Message has no MAC"));
+    }
+
+    #[test]
+    fn verify_variable_mac_field_test() {
+        let s = String::from(include_str!("../spec1993.yml")).replace(
+            "Length: \"8\"\n  Label: Message Authentication Code Field\n  LengthType: fixed",
+            "Length: \"8\"\n  Label: Message Authentication Code Field\n  LengthType: llvar",
+        );
+        let spec = YamlSpec::new(&s).unwrap();
+        let key = hex::decode(TDES_KEY).unwrap();
+        let mut msg = IsoMsg::empty(&spec);
+        msg.set_field(0, b"0200").unwrap();
+        msg.set_field(4, b"000000001000").unwrap();
+        assert_eq!(msg.set_mac(MacAlgorithm::Iso9797Alg3, &key), Err("MAC field must be fixed length"));
+
+        msg.set_field(64, b"\0\0\0\0\0\0\0\0").unwrap();
+        assert_eq!(msg.verify_mac(MacAlgorithm::Iso9797Alg3, &key), Err("MAC field must be fixed length"));
+    }
+}
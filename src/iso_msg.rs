@@ -11,10 +11,17 @@ use std::str;
 use bit_array::BitArray;
 use typenum::U128;
 use std::borrow::Cow;
+use std::io;
+#[cfg(feature = "codec")]
+use bytes::BytesMut;
+use std::ops::Range;
 use iso_field::FieldCharType;
 use iso_field::FieldPayload;
 use iso_field::IsoField;
 use iso_field::FieldSizeType;
+use iso_subfield;
+use iso_rules::MessageRules;
+use iso_stream::ParseStatus;
 
 
 
@@ -22,20 +29,64 @@ use iso_field::FieldSizeType;
 /// This defines the Iso8583 message format
 pub trait IsoSpecs {
     fn get_handle(&self) -> &Vec<IsoField>;
+
+    /// Field presence rules per MTI, if the spec declares any
+    fn get_rules(&self) -> Option<&MessageRules> {
+        None
+    }
+}
+
+/// Field definitions and rules of the spec a message is built with. Only plain data is
+/// borrowed, so messages can be sent across threads whatever the `IsoSpecs` type
+#[derive(Clone, Copy)]
+pub struct SpecRef<'b> {
+    handle: &'b Vec<IsoField>,
+    rules: Option<&'b MessageRules>,
+}
+
+impl<'b> SpecRef<'b> {
+    pub fn get_handle(&self) -> &'b Vec<IsoField> {
+        self.handle
+    }
+
+    pub fn get_rules(&self) -> Option<&'b MessageRules> {
+        self.rules
+    }
+}
+
+impl<'b> IsoSpecs for SpecRef<'b> {
+    fn get_handle(&self) -> &Vec<IsoField> {
+        self.handle
+    }
+
+    fn get_rules(&self) -> Option<&MessageRules> {
+        self.rules
+    }
+}
+
+impl<'b, T: IsoSpecs + ?Sized> From<&'b T> for SpecRef<'b> {
+    fn from(iso_spec: &'b T) -> SpecRef<'b> {
+        SpecRef {
+            handle: iso_spec.get_handle(),
+            rules: iso_spec.get_rules(),
+        }
+    }
 }
 
 /// `IsoMsg`
+#[derive(Clone)]
 pub struct IsoMsg<'a, 'b> {
     payload: Cow<'a, [u8]>,
-    iso_spec: &'b IsoSpecs,
+    iso_spec: SpecRef<'b>,
     fields: Vec<FieldPayload>,
 }
 
 impl<'a, 'b> IsoMsg<'a, 'b> {
-    pub fn new(iso_spec: &'b IsoSpecs, payload: &'a [u8]) -> IsoMsg<'a, 'b> {
+    pub fn new<S: Into<SpecRef<'b>>>(iso_spec: S, payload: &'a [u8]) -> IsoMsg<'a, 'b> {
+        let iso_spec = iso_spec.into();
         let mut fields = Vec::with_capacity(iso_spec.get_handle().len());
 
-        IsoMsg::from_byte_array(iso_spec, &mut fields, payload);
+        IsoMsg::from_byte_array(&iso_spec, &mut fields, payload);
 
         IsoMsg {
             iso_spec: iso_spec,
@@ -46,7 +97,66 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
         }
     }
 
-    pub fn remove_field(&mut self, index: usize) -> Result<(), &str> {
+    /// Same as `new` but the message owns `payload`
+    pub fn new_owned<S: Into<SpecRef<'b>>>(iso_spec: S, payload: Vec<u8>) -> IsoMsg<'static, 'b> {
+        let iso_spec = iso_spec.into();
+        let mut fields = Vec::with_capacity(iso_spec.get_handle().len());
+
+        IsoMsg::from_byte_array(&iso_spec, &mut fields, &payload);
+
+        IsoMsg {
+            iso_spec,
+            payload: Cow::Owned(payload),
+            fields,
+        }
+    }
+
+    /// Check that `payload` can be parsed against `iso_spec`.
+    /// `new` expects a well formed payload, untrusted input should be checked first
+    pub fn check_payload(iso_spec: &dyn IsoSpecs, payload: &[u8]) -> Result<(), &'static str> {
+        match IsoMsg::measure(iso_spec, payload)? {
+            ParseStatus::Complete(_) => Ok(()),
+            ParseStatus::Incomplete(_) => Err("Payload is truncated"),
+        }
+    }
+
+    /// Create a message with no fields set, to be populated with `set_field`.
+    /// The bitmap field is reserved here and computed by `to_byte_array`
+    pub fn empty<S: Into<SpecRef<'b>>>(iso_spec: S) -> IsoMsg<'a, 'b> {
+        let iso_spec = iso_spec.into();
+        let handle = iso_spec.get_handle();
+        let mut fields = Vec::with_capacity(handle.len());
+        let mut bitmap_found = false;
+        for (index, iso_field) in handle.iter().enumerate() {
+            let mut field = FieldPayload::default();
+            if !bitmap_found &&
+                (iso_field.char_type == FieldCharType::Iso8583_bmp ||
+                     iso_field.char_type == FieldCharType::Iso8583_bmps)
+            {
+                bitmap_found = true;
+                let bitmap_len = if handle.len() - index > 64 { 32 } else { 16 };
+                field.new_payload = Some(vec![b'0'; bitmap_len]);
+                field.exist = true;
+            }
+            fields.push(field);
+        }
+
+        IsoMsg {
+            iso_spec,
+            payload: Cow::Borrowed(&[]),
+            fields,
+        }
+    }
+
+    pub fn get_spec(&self) -> SpecRef<'b> {
+        self.iso_spec
+    }
+
+    pub fn has_field(&self, index: usize) -> bool {
+        index < self.fields.len() && self.fields[index].exist
+    }
+
+    pub fn remove_field(&mut self, index: usize) -> Result<(), &'static str> {
         assert!(index < self.fields.len());
         assert!(index < self.iso_spec.get_handle().len());
         self.fields[index].exist = false;
@@ -54,11 +164,11 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
     }
 
 
-    pub fn set_field(&mut self, index: usize, buffer: &[u8]) -> Result<(), &str> {
+    pub fn set_field(&mut self, index: usize, buffer: &[u8]) -> Result<(), &'static str> {
         trace!(
             "set_field: index:{}, buffer:{}",
             index,
-            str::from_utf8(&buffer).unwrap()
+            String::from_utf8_lossy(buffer)
         );
         assert!(index < self.fields.len());
         assert!(index < self.iso_spec.get_handle().len());
@@ -81,7 +191,7 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
         trace!(
             "index:{}, set_extend_from_slice : v {}",
             index,
-            str::from_utf8(&v).unwrap()
+            String::from_utf8_lossy(&v)
         );
         trace!("set_field: v.len:{}", v.len());
         self.fields[index].new_payload = Some(v);
@@ -90,13 +200,10 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
     }
 
     pub fn get_field_length_prefix(&self, index: usize) -> usize {
-        match self.iso_spec.get_handle()[index].size_type {
-            FieldSizeType::LlVar | FieldSizeType::LllVar | FieldSizeType::LlllVar => 3,
-            _ => 0,
-        }
+        self.iso_spec.get_handle()[index].size_type.prefix_width()
     }
 
-    pub fn get_field(&self, index: usize, buffer: &mut [u8]) -> Result<usize, &str> {
+    pub fn get_field(&self, index: usize, buffer: &mut [u8]) -> Result<usize, &'static str> {
 
         let res = self.get_field_raw(index, buffer);
         if res.is_err() {
@@ -111,7 +218,194 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
         Ok(len - field_len_prefix)
     }
 
-    fn get_field_raw(&self, index: usize, buffer: &mut [u8]) -> Result<(usize, usize), &str> {
+    /// Same as `get_field` but returns the field value in a new `Vec<u8>`
+    pub fn get_field_value(&self, index: usize) -> Result<Vec<u8>, &'static str> {
+        if index >= self.fields.len() {
+            return Err("Invalid field index");
+        }
+        let raw_len = match self.fields[index].new_payload {
+            Some(ref m) => m.len(),
+            None => self.fields[index].len,
+        };
+        let mut buffer = vec![0u8; raw_len];
+        let len = self.get_field(index, &mut buffer)?;
+        buffer.truncate(len);
+        Ok(buffer)
+    }
+
+    /// Get subfield `sub` (numbered from 1) of composite field `index`
+    pub fn get_subfield(&self, index: usize, sub: usize, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let subfields = &self.iso_spec.get_handle()[index].subfields;
+        if sub == 0 || sub > subfields.len() {
+            return Err("Subfield not defined");
+        }
+        let value = self.get_field_value(index)?;
+        let offsets = iso_subfield::parse_subfields(subfields, &value)?;
+        match offsets[sub - 1] {
+            Some((offset, len)) => {
+                if buffer.len() < len {
+                    return Err("Input buffer is smaller than field value");
+                }
+                buffer[..len].copy_from_slice(&value[offset..offset + len]);
+                Ok(len)
+            }
+            None => Err("Field not set"),
+        }
+    }
+
+    /// Set subfield `sub` (numbered from 1) of composite field `index`.
+    /// The parent field is rebuilt including its length prefix
+    pub fn set_subfield(&mut self, index: usize, sub: usize, buffer: &[u8]) -> Result<(), &'static str> {
+        self.update_subfield(index, sub, Some(buffer))
+    }
+
+    pub fn remove_subfield(&mut self, index: usize, sub: usize) -> Result<(), &'static str> {
+        self.update_subfield(index, sub, None)
+    }
+
+    fn update_subfield(&mut self, index: usize, sub: usize, buffer: Option<&[u8]>) -> Result<(), &'static str> {
+        let iso_spec = self.iso_spec;
+        let iso_field = &iso_spec.get_handle()[index];
+        if sub == 0 || sub > iso_field.subfields.len() {
+            return Err("Subfield not defined");
+        }
+        let mut values: Vec<Option<Vec<u8>>> = vec![None; iso_field.subfields.len()];
+        if self.has_field(index) {
+            let value = self.get_field_value(index)?;
+            let offsets = iso_subfield::parse_subfields(&iso_field.subfields, &value)?;
+            for (i, offset) in offsets.iter().enumerate() {
+                if let Some((offset, len)) = *offset {
+                    values[i] = Some(value[offset..offset + len].to_vec());
+                }
+            }
+        }
+        values[sub - 1] = buffer.map(|b| b.to_vec());
+
+        let value = iso_subfield::build_subfields(&iso_field.subfields, &values)?;
+        if value.is_empty() {
+            return self.remove_field(index);
+        }
+        if value.len() > iso_field.length {
+            return Err("Composite field exceeds max length");
+        }
+        self.set_field(index, &value)
+    }
+
+    /// Get a field by dotted path e.g. `127.22` for a field of a nested message
+    /// or `43.2` for a subfield
+    pub fn get_field_path(&self, path: &str, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let path = IsoMsg::parse_path(path)?;
+        self.get_field_by_path(&path, buffer)
+    }
+
+    /// Value of a field by dotted path, sized from the top level field
+    pub fn get_field_path_value(&self, path: &str) -> Result<Vec<u8>, &'static str> {
+        let path = IsoMsg::parse_path(path)?;
+        if path[0] >= self.fields.len() {
+            return Err("Invalid field index");
+        }
+        let raw_len = match self.fields[path[0]].new_payload {
+            Some(ref m) => m.len(),
+            None => self.fields[path[0]].len,
+        };
+        let mut buffer = vec![0u8; raw_len];
+        let len = self.get_field_by_path(&path, &mut buffer)?;
+        buffer.truncate(len);
+        Ok(buffer)
+    }
+
+    /// Set a field by dotted path, parent fields are rebuilt
+    pub fn set_field_path(&mut self, path: &str, buffer: &[u8]) -> Result<(), &'static str> {
+        let path = IsoMsg::parse_path(path)?;
+        self.update_field_by_path(&path, Some(buffer))
+    }
+
+    pub fn remove_field_path(&mut self, path: &str) -> Result<(), &'static str> {
+        let path = IsoMsg::parse_path(path)?;
+        self.update_field_by_path(&path, None)
+    }
+
+    fn parse_path(path: &str) -> Result<Vec<usize>, &'static str> {
+        let mut res = Vec::new();
+        for (i, p) in path.split('.').enumerate() {
+            match p.parse::<usize>() {
+                Ok(n) if i == 0 || n > 0 => res.push(n),
+                _ => return Err("Invalid field path"),
+            }
+        }
+        Ok(res)
+    }
+
+    /// `path[0]` is the index in this message, following entries are numbered from 1
+    fn get_field_by_path(&self, path: &[usize], buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let index = path[0];
+        if index >= self.fields.len() {
+            return Err("Invalid field index");
+        }
+        if path.len() == 1 {
+            return self.get_field(index, buffer);
+        }
+        let iso_field = &self.iso_spec.get_handle()[index];
+        if let Some(ref nested) = iso_field.nested {
+            let value = self.get_field_value(index)?;
+            IsoMsg::check_payload(nested, &value)?;
+            let nested_msg = IsoMsg::new(nested, &value);
+            let mut nested_path = path[1..].to_vec();
+            nested_path[0] -= 1;
+            return nested_msg.get_field_by_path(&nested_path, buffer);
+        }
+        if path.len() == 2 {
+            return self.get_subfield(index, path[1], buffer);
+        }
+        Err("Invalid field path")
+    }
+
+    fn update_field_by_path(&mut self, path: &[usize], buffer: Option<&[u8]>) -> Result<(), &'static str> {
+        let index = path[0];
+        if index >= self.fields.len() {
+            return Err("Invalid field index");
+        }
+        let iso_spec = self.iso_spec;
+        let iso_field = &iso_spec.get_handle()[index];
+        if path.len() == 1 {
+            return match buffer {
+                Some(b) if b.len() > iso_field.length => Err("Field value exceeds max length"),
+                Some(b) => self.set_field(index, b),
+                None => self.remove_field(index),
+            };
+        }
+        if let Some(ref nested) = iso_field.nested {
+            let value = if self.has_field(index) {
+                self.get_field_value(index)?
+            } else {
+                Vec::new()
+            };
+            let mut nested_msg = if value.is_empty() {
+                IsoMsg::empty(nested)
+            } else {
+                IsoMsg::check_payload(nested, &value)?;
+                IsoMsg::new(nested, &value)
+            };
+            let mut nested_path = path[1..].to_vec();
+            nested_path[0] -= 1;
+            nested_msg.update_field_by_path(&nested_path, buffer)?;
+
+            if !(1..nested_msg.fields.len()).any(|i| nested_msg.is_field_set(i)) {
+                return self.remove_field(index);
+            }
+            let out = nested_msg.to_vec();
+            if out.len() > iso_field.length {
+                return Err("Composite field exceeds max length");
+            }
+            return self.set_field(index, &out);
+        }
+        if path.len() == 2 {
+            return self.update_subfield(index, path[1], buffer);
+        }
+        Err("Invalid field path")
+    }
+
+    fn get_field_raw(&self, index: usize, buffer: &mut [u8]) -> Result<(usize, usize), &'static str> {
         assert!(index < self.fields.len());
         let field = &self.fields[index];
         if !field.exist {
@@ -160,10 +454,11 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
         bitmap_bytes: &[u8],
     ) -> (Vec<BitArray<u64, U128>>, usize) {
 
-        let bitmap_length = iso_spec.get_handle()[index].length;
-        let bitmap_size = bitmap_length * 8;
-        let num_iteration: usize = (iso_spec.get_handle().len() - index + 63) / bitmap_size;
-        let i = 0;
+        let handle = iso_spec.get_handle();
+        let bitmap_field_index = IsoMsg::get_bitmap_field_index(iso_spec).unwrap_or(0);
+        let bitmap_size = 128;
+        let num_iteration: usize = (handle.len() - bitmap_field_index - 1).div_ceil(bitmap_size);
+        let num_iteration = if num_iteration == 0 { 1 } else { num_iteration };
 
         let mut bit_map_index = index;
         let num_bits = 32; //note: we are using rev() of range e.g (0..num_bits).rev() so 32 would start with 31
@@ -172,20 +467,22 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
             bit_arrays.push(BitArray::<u64, U128>::from_elem(false));
         }
 
-        let mut bit_arrays_index = 0;
-
         //let bitmap = unsafe { str::from_utf8_unchecked(bitmap_bytes) };
-        let bitmap = str::from_utf8(bitmap_bytes).unwrap();
+        // only the hex bitmap has to be text, binary fields may follow it
+        let bitmap = match str::from_utf8(bitmap_bytes) {
+            Ok(s) => s,
+            Err(e) => str::from_utf8(&bitmap_bytes[..e.valid_up_to()]).unwrap(),
+        };
         trace!("index:{}, bitmap:{}", index, bitmap);
         let mut field_index = 0; //current field index
-        while i < num_iteration {
-
+        loop {
+            let first = field_index == 0;
             //move to the next field
-            if i == 0 {
-                field_index += 1; // skip field index 0. This is to check if bit 31 is set for 2ndory bitmap
+            if first {
+                field_index += 1; // skip bit 1. This is to check if bit 31 is set for 2ndory bitmap
             }
             let mut ms_num_bit = num_bits;
-            if i == 0 {
+            if first {
                 ms_num_bit -= 1;
             }
             let ms = u32::from_str_radix(&bitmap[bit_map_index..bit_map_index + 8], 16).unwrap();
@@ -194,7 +491,7 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
             for x in (0..ms_num_bit).rev() {
                 let bit_set = IsoMsg::is_bit_set(ms, x as u8);
                 trace!("ms: bit {} field_index:{} is {}", x, field_index, bit_set);
-                bit_arrays[bit_arrays_index].set(field_index, bit_set);
+                bit_arrays[field_index / bitmap_size].set(field_index % bitmap_size, bit_set);
                 field_index += 1;
             }
             trace!("ms: {}", ms);
@@ -204,26 +501,31 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
             for x in (0..num_bits).rev() {
                 let bit_set = IsoMsg::is_bit_set(ls, x as u8);
                 trace!("ms: bit {} field_index:{} is {}", x, field_index, bit_set);
-                bit_arrays[bit_arrays_index].set(field_index, bit_set);
+                bit_arrays[field_index / bitmap_size].set(field_index % bitmap_size, bit_set);
                 field_index += 1;
             }
             bit_map_index += 8;
             trace!("ls: {}", ls);
 
-
-            bit_arrays_index = field_index / bitmap_size;
-            if i == 0 && !IsoMsg::is_bit_set(ms, 31u8) {
-                // if 2ndory bitmap doesn't exist, comeout
-                trace!("2ndory bitmap doesn't exis");
+            if !IsoMsg::is_bit_set(ms, 31u8) || field_index >= num_iteration * bitmap_size {
+                // if next bitmap doesn't exist, comeout
+                trace!("next bitmap doesn't exist");
                 break;
             }
         }
 
 
-        (bit_arrays, bit_map_index + index - 8)
+        (bit_arrays, bit_map_index - index)
 
     }
 
+    /// Index of the (first) bitmap field in the spec
+    pub fn get_bitmap_field_index(iso_spec: &dyn IsoSpecs) -> Option<usize> {
+        iso_spec.get_handle().iter().position(|f| {
+            f.char_type == FieldCharType::Iso8583_bmp || f.char_type == FieldCharType::Iso8583_bmps
+        })
+    }
+
     pub fn convert_u32_be(array: &[u8]) -> u32 {
         assert_eq!(array.len(), 4);
         (u32::from(array[0]) << 24) + (u32::from(array[1]) << 16) + (u32::from(array[2]) << 8) +
@@ -236,80 +538,216 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
             (u32::from(array[3]) << 24)
     }
 
+    /// Write the message to `buffer` and return its length.
+    /// Panics if `buffer` is shorter than `encoded_len`, see `try_to_byte_array`
     pub fn to_byte_array(&self, buffer: &mut [u8]) -> usize {
+        assert!(buffer.len() >= self.encoded_len(), "Buffer is smaller than the message");
         let mut buffer_index = 0usize;
-        let num_iteration: usize = (self.iso_spec.get_handle().len() - 1 + 63) / 128;
-        let mut bit_arrays = Vec::<BitArray<u64, U128>>::with_capacity(num_iteration);
-        for _ in 0..num_iteration {
-            bit_arrays.push(BitArray::<u64, U128>::from_elem(false));
+        let bitmap_field_index = IsoMsg::get_bitmap_field_index(&self.iso_spec);
+        let bitmap = match bitmap_field_index {
+            Some(b) => self.build_bitmap(b),
+            None => String::new(),
+        };
+
+        for index in 0..self.fields.len() {
+            if Some(index) == bitmap_field_index {
+                buffer[buffer_index..buffer_index + bitmap.len()].copy_from_slice(bitmap.as_bytes());
+                buffer_index += bitmap.len();
+            } else {
+                let res = self.get_field_raw(index, &mut buffer[buffer_index..]);
+                if let Ok((field_total_len, _)) = res {
+                    trace!(
+                        "index:{}, field:{}",
+                        index,
+                        String::from_utf8_lossy(&buffer[buffer_index..buffer_index + field_total_len])
+                    );
+                    buffer_index += field_total_len;
+                }
+            }
         }
-        let mut bit_array_index = 0;
-        let mut bit_index = 0;
-        let mut bitmap_field_index = 0;
+        buffer_index
+    }
 
-        let mut bitmap_found = false;
+    /// Same as `to_byte_array` but fails if `buffer` is too small
+    pub fn try_to_byte_array(&self, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        if buffer.len() < self.encoded_len() {
+            return Err("Buffer is smaller than the message");
+        }
+        Ok(self.to_byte_array(buffer))
+    }
+
+    /// Message in a new `Vec<u8>` of `encoded_len` bytes
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to_vec(&mut out);
+        out
+    }
+
+    /// Append the message to `out` and return its length
+    pub fn write_to_vec(&self, out: &mut Vec<u8>) -> usize {
+        let start = out.len();
+        out.resize(start + self.encoded_len(), 0);
+        let len = self.to_byte_array(&mut out[start..]);
+        out.truncate(start + len);
+        len
+    }
 
+    /// Append the message to `dst` and return its length
+    #[cfg(feature = "codec")]
+    pub fn write_to_bytes(&self, dst: &mut BytesMut) -> usize {
+        let start = dst.len();
+        dst.resize(start + self.encoded_len(), 0);
+        let len = self.to_byte_array(&mut dst[start..]);
+        dst.truncate(start + len);
+        len
+    }
+
+    /// Write the message field by field to `writer` and return its length
+    pub fn write_to<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+        let bitmap_field_index = IsoMsg::get_bitmap_field_index(&self.iso_spec);
+        let mut len = 0;
         for index in 0..self.fields.len() {
-            bit_array_index = index / 128;
+            if Some(index) == bitmap_field_index {
+                let bitmap = self.build_bitmap(index);
+                writer.write_all(bitmap.as_bytes())?;
+                len += bitmap.len();
+            } else if let Some(raw) = self.raw_field(index) {
+                writer.write_all(raw)?;
+                len += raw.len();
+            }
+        }
+        Ok(len)
+    }
 
-            if !bitmap_found &&
-                (self.iso_spec.get_handle()[index].char_type == FieldCharType::Iso8583_bmp ||
-                     self.iso_spec.get_handle()[index].char_type == FieldCharType::Iso8583_bmps)
-            {
-                bitmap_field_index = index;
-                bitmap_found = true;
-                bit_index = buffer_index;
-                let res = self.get_field_raw(index, &mut buffer[buffer_index..]);
-                if res.is_ok() {
-                    let (field_total_len, _) = res.unwrap();
-                    buffer_index += field_total_len;
+    /// Encoded field with its length prefix, as written by `to_byte_array`
+    fn raw_field(&self, index: usize) -> Option<&[u8]> {
+        let field = &self.fields[index];
+        if !self.is_field_set(index) {
+            return None;
+        }
+        match field.new_payload {
+            Some(ref m) => Some(&m[..]),
+            None => self.payload.get(field.index..field.index + field.len),
+        }
+    }
+
+    /// Same as `to_byte_array_patched` but fails if `buffer` is too small
+    pub fn try_to_byte_array_patched(&self, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        if buffer.len() < self.encoded_len() {
+            return Err("Buffer is smaller than the message");
+        }
+        Ok(self.to_byte_array_patched(buffer))
+    }
+
+    /// Same output as `to_byte_array`, but the unchanged ranges of the parsed payload are
+    /// copied in bulk and only modified fields and a changed bitmap are written.
+    /// Panics if `buffer` is shorter than `encoded_len`, see `try_to_byte_array_patched`
+    pub fn to_byte_array_patched(&self, buffer: &mut [u8]) -> usize {
+        let bitmap_field_index = IsoMsg::get_bitmap_field_index(&self.iso_spec);
+        let mut buffer_index = 0usize;
+        // payload range to copy as is, grown while unchanged fields follow each other
+        let mut run = 0..0;
+        for index in 0..self.fields.len() {
+            let field = &self.fields[index];
+            let bitmap;
+            let modified: Option<&[u8]> = if Some(index) == bitmap_field_index {
+                bitmap = self.build_bitmap(index);
+                if self.payload.get(field.index..field.index + field.len) == Some(bitmap.as_bytes()) {
+                    None
+                } else {
+                    Some(bitmap.as_bytes())
                 }
+            } else if self.is_field_set(index) {
+                field.new_payload.as_ref().map(|m| &m[..])
             } else {
-
-                let res = self.get_field_raw(index, &mut buffer[buffer_index..]);
-                if res.is_ok() {
-                    if bitmap_found {
-                        bit_arrays[bit_array_index].set(index - bitmap_field_index, true);
-                        trace!(
-                            "index:{}, buffer[buffer_index..]:{}",
-                            index,
-                            str::from_utf8(&buffer[buffer_index..]).unwrap()
-                        );
+                continue;
+            };
+            match modified {
+                None => {
+                    if run.end != field.index {
+                        self.copy_payload(run, buffer, &mut buffer_index);
+                        run = field.index..field.index;
                     }
-                    let (field_total_len, _) = res.unwrap();
-                    buffer_index += field_total_len;
+                    run.end += field.len;
+                }
+                Some(bytes) => {
+                    self.copy_payload(run, buffer, &mut buffer_index);
+                    run = 0..0;
+                    buffer[buffer_index..buffer_index + bytes.len()].copy_from_slice(bytes);
+                    buffer_index += bytes.len();
                 }
             }
-
         }
-        //override bitmap
-        let mut bitmap = String::with_capacity(bit_array_index * 16);
-        for (i, bit_array_item) in bit_arrays.iter_mut().enumerate().take(bit_array_index) {
-            //for i in 0..bit_array_index {
-            if i == 0 && bit_array_item.len() > 64 {
-                bit_array_item.set(0, true);
+        self.copy_payload(run, buffer, &mut buffer_index);
+        buffer_index
+    }
+
+    fn copy_payload(&self, range: Range<usize>, buffer: &mut [u8], buffer_index: &mut usize) {
+        let len = range.end - range.start;
+        buffer[*buffer_index..*buffer_index + len].copy_from_slice(&self.payload[range]);
+        *buffer_index += len;
+    }
+
+    /// Hex bitmap of the fields following `bitmap_field_index`.
+    /// The secondary bitmap is included if the spec defines more than 64 such fields
+    fn build_bitmap(&self, bitmap_field_index: usize) -> String {
+        let mut bit_array = BitArray::<u64, U128>::from_elem(false);
+        for index in bitmap_field_index + 1..self.fields.len() {
+            if self.is_field_set(index) && index - bitmap_field_index < 128 {
+                bit_array.set(index - bitmap_field_index, true);
             }
-            let bytes = bit_array_item.to_bytes();
-            let mut byte_index = 0;
+        }
+        let secondary = self.fields.len() - bitmap_field_index > 64;
+        if secondary {
+            bit_array.set(0, true);
+        }
 
-            while byte_index < bytes.len() {
-                let ms_str = IsoMsg::convert_u32_be(&bytes[byte_index..byte_index + 4]);
-                byte_index += 4;
-                bitmap.push_str(&format!("{:08X}", ms_str));
+        let bytes = bit_array.to_bytes();
+        let num_bytes = if secondary { 16 } else { 8 };
+        let mut bitmap = String::with_capacity(num_bytes * 2);
+        let mut byte_index = 0;
+        while byte_index < num_bytes {
+            let ms_str = IsoMsg::convert_u32_be(&bytes[byte_index..byte_index + 4]);
+            byte_index += 4;
+            bitmap.push_str(&format!("{:08X}", ms_str));
+        }
+        bitmap
+    }
+
+    /// true if the field will be written by `to_byte_array`
+    fn is_field_set(&self, index: usize) -> bool {
+        let field = &self.fields[index];
+        field.exist && (field.new_payload.is_some() || field.len > 0)
+    }
 
+    /// Number of bytes written by `to_byte_array`
+    pub fn encoded_len(&self) -> usize {
+        let bitmap_field_index = IsoMsg::get_bitmap_field_index(&self.iso_spec);
+        let mut len = 0;
+        for index in 0..self.fields.len() {
+            if Some(index) == bitmap_field_index {
+                len += self.build_bitmap(index).len();
+            } else if self.is_field_set(index) {
+                len += match self.fields[index].new_payload {
+                    Some(ref m) => m.len(),
+                    None => self.fields[index].len,
+                };
             }
         }
-        buffer[bit_index..bitmap.len() + bit_index]
-            .copy_from_slice(&bitmap.as_bytes()[0..bitmap.len()]);
-        buffer_index
+        len
     }
 
     pub fn get_field_length(iso_field: &IsoField, input_buffer: &[u8]) -> usize {
         match iso_field.size_type {
             FieldSizeType::Fixed => iso_field.length,
-            FieldSizeType::LlVar | FieldSizeType::LllVar | FieldSizeType::LlllVar => {
-                let str_digits = unsafe { str::from_utf8_unchecked(&input_buffer[0..3]) };
-                usize::from_str_radix(str_digits, 10).unwrap() + 3
+            FieldSizeType::LlVar |
+                FieldSizeType::LllVar |
+                FieldSizeType::LlllVar |
+                FieldSizeType::LlllllVar |
+                FieldSizeType::LlVar2 => {
+                let width = iso_field.size_type.prefix_width();
+                let str_digits = unsafe { str::from_utf8_unchecked(&input_buffer[0..width]) };
+                str_digits.parse::<usize>().unwrap() + width
             }
             _ => 0,
         }
@@ -347,7 +785,7 @@ impl<'a, 'b> IsoMsg<'a, 'b> {
                     iso_field.length,
                     field.index,
                     payload_index,
-                    str::from_utf8(&input_buffer[field.index..len + field.index]).unwrap()
+                    String::from_utf8_lossy(&input_buffer[field.index..len + field.index])
                 );
 
                 trace!("bit_arrays:{}", bit_arrays.len());
@@ -395,8 +833,10 @@ mod tests {
     use iso_field::FieldPayload;
     use iso_field::FieldSizeType;
     use iso_field::IsoField;
+    use iso_field::NestedSpec;
 
     use yaml_specs::YamlSpec;
+    use iso_lazy::LazyIsoMsg;
 
     /// Auth spec defines the format of Iso8583 message
     pub struct AuthSpecs {
@@ -728,7 +1168,104 @@ IsoField::new("Message Authentication Code Field",FieldCharType::Iso8583_b  ,  8
         assert_eq!(str::from_utf8(&buffer[0..total_size]).unwrap(), payload);
     }
 
+    #[test]
+    fn iso_encode_test() {
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let handle = AuthSpecs::new();
+        let mut iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+        assert_eq!(iso_msg.encoded_len(), payload.len());
+        assert_eq!(iso_msg.to_vec(), payload.as_bytes());
+
+        iso_msg.set_field(100, b"654321").unwrap();
+        let len = iso_msg.encoded_len();
+        assert_eq!(len, payload.len() + 9);
+        let mut small = vec![0u8; len - 1];
+        assert_eq!(iso_msg.try_to_byte_array(&mut small), Err("Buffer is smaller than the message"));
+        let mut exact = vec![0u8; len];
+        assert_eq!(iso_msg.try_to_byte_array(&mut exact), Ok(len));
+        assert_eq!(iso_msg.to_vec(), exact);
+
+        let mut out = b"head".to_vec();
+        assert_eq!(iso_msg.write_to_vec(&mut out), len);
+        assert_eq!(&out[4..], &exact[..]);
+
+        let mut writer = io::Cursor::new(Vec::new());
+        assert_eq!(iso_msg.write_to(&mut writer).unwrap(), len);
+        assert_eq!(writer.into_inner(), exact);
+        let mut short = [0u8; 16];
+        assert!(iso_msg.write_to(&mut &mut short[..]).is_err());
+    }
+
+    /// A spec that is not `Sync`
+    struct CellSpecs {
+        handle: Vec<IsoField>,
+        _reads: ::std::cell::Cell<u32>,
+    }
+
+    impl IsoSpecs for CellSpecs {
+        fn get_handle(&self) -> &Vec<IsoField> {
+            &self.handle
+        }
+    }
+
+    #[test]
+    fn iso_send_test() {
+        fn assert_send<T: Send>(_: &T) {}
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let handle = CellSpecs { handle: Util::define_auth_specs(), _reads: ::std::cell::Cell::new(0) };
+        let iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+        assert_send(&iso_msg);
+        let copy = ::std::thread::scope(|scope| scope.spawn(move || iso_msg.to_vec()).join().unwrap());
+        assert_eq!(copy, payload.as_bytes());
+    }
 
+    #[test]
+    #[should_panic(expected = "Buffer is smaller than the message")]
+    fn iso_encode_short_buffer_test() {
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut iso_msg = IsoMsg::empty(&spec);
+        iso_msg.set_field(0, b"0100").unwrap();
+        iso_msg.set_field(2, b"4111111111111111").unwrap();
+        iso_msg.set_field(3, b"000000").unwrap();
+        let mut buffer = vec![0u8; iso_msg.encoded_len() - 1];
+        iso_msg.to_byte_array(&mut buffer);
+    }
+
+    #[test]
+    fn iso_to_byte_array_patched_test() {
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let handle = AuthSpecs::new();
+        let mut iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+        let mut buffer = [0u8; 1024];
+        let mut expected = [0u8; 1024];
+        let total_size = iso_msg.to_byte_array_patched(&mut buffer);
+        assert_eq!(str::from_utf8(&buffer[0..total_size]).unwrap(), payload);
+
+        // same bitmap
+        iso_msg.set_field(4, b"000000002000").unwrap();
+        let total_size = iso_msg.to_byte_array_patched(&mut buffer);
+        let expected_size = iso_msg.to_byte_array(&mut expected);
+        assert_eq!(&buffer[..total_size], &expected[..expected_size]);
+
+        // new bitmap, field added to and removed from the secondary bitmap
+        iso_msg.set_field(32, b"12345").unwrap();
+        iso_msg.set_field(100, b"654321").unwrap();
+        iso_msg.remove_field(2).unwrap();
+        let total_size = iso_msg.to_byte_array_patched(&mut buffer);
+        let expected_size = iso_msg.to_byte_array(&mut expected);
+        assert_eq!(&buffer[..total_size], &expected[..expected_size]);
+
+        let mut new_msg = IsoMsg::empty(&handle);
+        new_msg.set_field(0, b"0800").unwrap();
+        new_msg.set_field(11, b"000001").unwrap();
+        let total_size = new_msg.to_byte_array_patched(&mut buffer);
+        let expected_size = new_msg.to_byte_array(&mut expected);
+        assert_eq!(&buffer[..total_size], &expected[..expected_size]);
+        let mut small = [0u8; 8];
+        assert_eq!(new_msg.try_to_byte_array_patched(&mut small), Err("Buffer is smaller than the message"));
+        assert_eq!(new_msg.try_to_byte_array_patched(&mut buffer), Ok(expected_size));
+    }
 
     #[test]
     fn iso_auth_req_test() {
@@ -819,6 +1356,149 @@ IsoField::new("Message Authentication Code Field",FieldCharType::Iso8583_b  ,  8
         );
     }
 
+    #[test]
+    fn iso_subfield_test() {
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let mut h = Util::define_auth_specs();
+        h[43] = IsoField::new_composite("Card Acceptor Name/Location", FieldCharType::Iso8583_ans, 40, FieldSizeType::Fixed, vec![
+            IsoField::new("Card Acceptor Name", FieldCharType::Iso8583_ans, 22, FieldSizeType::Fixed),
+            IsoField::new("Card Acceptor City", FieldCharType::Iso8583_ans, 13, FieldSizeType::Fixed),
+            IsoField::new("Card Acceptor Region", FieldCharType::Iso8583_ans, 2, FieldSizeType::Fixed),
+            IsoField::new("Card Acceptor Country", FieldCharType::ISO8583_a, 3, FieldSizeType::Fixed),
+        ]);
+        h[48] = IsoField::new_composite("Additional Data - Private", FieldCharType::Iso8583_ans, 999, FieldSizeType::LllVar, vec![
+            IsoField::new("Bitmap", FieldCharType::Iso8583_bmps, 2, FieldSizeType::BitMap),
+            IsoField::new("Tag 2", FieldCharType::Iso8583_ans, 99, FieldSizeType::LlVar2),
+            IsoField::new("Tag 3", FieldCharType::Iso8583_ns, 4, FieldSizeType::Fixed),
+        ]);
+        let handle = AuthSpecs { handle: h };
+        let mut iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+        let mut buffer = [0u8; 1024];
+
+        let res = iso_msg.get_subfield(43, 2, &mut buffer);
+        assert_eq!(res, Ok(13));
+        assert_eq!(&buffer[..13], "Richmond1    ".as_bytes());
+        assert_eq!(iso_msg.get_subfield(43, 5, &mut buffer), Err("Subfield not defined"));
+
+        assert_eq!(iso_msg.set_subfield(43, 1, b"New Merchant"), Ok(()));
+        let res = iso_msg.get_field(43, &mut buffer);
+        assert_eq!(res, Ok(40));
+        assert_eq!(&buffer[..40], "New Merchant          Richmond1    51USA".as_bytes());
+
+        assert_eq!(iso_msg.set_subfield(48, 3, b"42"), Ok(()));
+        assert_eq!(iso_msg.set_subfield(48, 2, b"HELLO"), Ok(()));
+        let res = iso_msg.get_field_value(48).unwrap();
+        assert_eq!(str::from_utf8(&res).unwrap(), "6005HELLO0042");
+        let res = iso_msg.get_subfield(48, 3, &mut buffer);
+        assert_eq!(res, Ok(4));
+        assert_eq!(&buffer[..4], "0042".as_bytes());
+
+        let total_size = iso_msg.to_byte_array(&mut buffer);
+        let out = str::from_utf8(&buffer[..total_size]).unwrap();
+        assert!(out.contains("New Merchant          Richmond1    51USA"));
+        assert!(out.contains("0136005HELLO0042"));
+
+        assert_eq!(iso_msg.remove_subfield(48, 2), Ok(()));
+        assert_eq!(iso_msg.remove_subfield(48, 3), Ok(()));
+        assert!(!iso_msg.has_field(48));
+    }
+
+    #[test]
+    fn iso_nested_field_test() {
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let mut h = Util::define_auth_specs();
+        let mut nested_handle = vec![
+            IsoField::new("Bitmap", FieldCharType::Iso8583_bmps, 16, FieldSizeType::BitMap),
+        ];
+        for i in 2..40 {
+            nested_handle.push(IsoField::new(&format!("Field 127.{}", i), FieldCharType::Iso8583_ans, 32, FieldSizeType::LlVar));
+        }
+        h[127] = IsoField::new("Reserved for Private use", FieldCharType::Iso8583_ans, 999, FieldSizeType::LllVar);
+        h[127].nested = Some(NestedSpec { handle: nested_handle });
+        let handle = AuthSpecs { handle: h };
+        let mut iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+        let mut buffer = [0u8; 1024];
+
+        assert_eq!(iso_msg.get_field_path("127.22", &mut buffer), Err("Field not set"));
+        assert_eq!(iso_msg.set_field_path("127.22", b"ROUTE-A"), Ok(()));
+        assert_eq!(iso_msg.set_field_path("127.3", b"XY"), Ok(()));
+        assert_eq!(iso_msg.get_field_value(127), Ok(b"2000040000000000002XY007ROUTE-A".to_vec()));
+        assert_eq!(iso_msg.get_field_path("127.22", &mut buffer), Ok(7));
+        assert_eq!(&buffer[..7], b"ROUTE-A");
+        assert_eq!(iso_msg.get_field_path("2", &mut buffer), Ok(16));
+        assert_eq!(iso_msg.get_field_path("127.0", &mut buffer), Err("Invalid field path"));
+        assert_eq!(iso_msg.get_field_path("127.x", &mut buffer), Err("Invalid field path"));
+
+        let total_size = iso_msg.to_byte_array(&mut buffer);
+        let out = buffer[..total_size].to_vec();
+        let parsed = IsoMsg::new(&handle, &out);
+        assert_eq!(parsed.get_field_path("127.3", &mut buffer), Ok(2));
+        assert_eq!(&buffer[..2], b"XY");
+        assert_eq!(parsed.get_field_path_value("127.3"), Ok(b"XY".to_vec()));
+        assert_eq!(parsed.get_field_path_value("127.22"), Ok(b"ROUTE-A".to_vec()));
+        assert_eq!(parsed.get_field_path_value("999"), Err("Invalid field index"));
+
+        assert_eq!(iso_msg.remove_field_path("127.3"), Ok(()));
+        assert_eq!(iso_msg.get_field_value(127), Ok(b"0000040000000000007ROUTE-A".to_vec()));
+        assert_eq!(iso_msg.remove_field_path("127.22"), Ok(()));
+        assert!(!iso_msg.has_field(127));
+
+        // malformed nested content is an error
+        iso_msg.set_field(127, b"20000400000000000099XY").unwrap();
+        assert_eq!(iso_msg.get_field_path("127.3", &mut buffer), Err("Payload is truncated"));
+        assert_eq!(iso_msg.set_field_path("127.3", b"XY"), Err("Payload is truncated"));
+        iso_msg.set_field(127, b"20ZZZZZZZZZZZZZZ").unwrap();
+        assert_eq!(iso_msg.get_field_path("127.3", &mut buffer), Err("Invalid bitmap"));
+    }
+
+    #[test]
+    fn iso_llllvar_field_test() {
+        let mut h = Util::define_auth_specs();
+        h[48] = IsoField::new("Additional Data - Private", FieldCharType::Iso8583_ans, 9999, FieldSizeType::LlllVar);
+        let handle = AuthSpecs { handle: h };
+        let payload = b"010000000000000100000005HELLO";
+        assert_eq!(IsoMsg::check_payload(&handle, payload), Ok(()));
+        let iso_msg = IsoMsg::new(&handle, payload);
+        assert_eq!(iso_msg.get_field_value(48), Ok(b"HELLO".to_vec()));
+        let out = iso_msg.to_vec();
+        assert!(out.ends_with(b"0005HELLO"));
+        assert_eq!(IsoMsg::new(&handle, &out).get_field_value(48), Ok(b"HELLO".to_vec()));
+        // the 3 digit prefix of earlier releases is no longer read
+        assert!(IsoMsg::check_payload(&handle, b"01000000000000010000005HELLO").is_err());
+    }
+
+    #[test]
+    fn iso_llllllvar_nested_field_test() {
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let mut h = Util::define_auth_specs();
+        let mut nested_handle = vec![
+            IsoField::new("Bitmap", FieldCharType::Iso8583_bmps, 16, FieldSizeType::BitMap),
+        ];
+        for i in 2..40 {
+            nested_handle.push(IsoField::new(&format!("Field 127.{}", i), FieldCharType::Iso8583_ans, 9999, FieldSizeType::LlllVar));
+        }
+        h[127] = IsoField::new("Reserved for Private use", FieldCharType::Iso8583_ans, 999999, FieldSizeType::LlllllVar);
+        h[127].nested = Some(NestedSpec { handle: nested_handle });
+        let handle = AuthSpecs { handle: h };
+        let mut iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+
+        let long = vec![b'X'; 1500];
+        assert_eq!(iso_msg.set_field_path("127.22", &long), Ok(()));
+        assert_eq!(iso_msg.set_field_path("127.3", b"XY"), Ok(()));
+        let nested = iso_msg.get_field_value(127).unwrap();
+        assert_eq!(nested.len(), 16 + 4 + 2 + 4 + 1500);
+        assert_eq!(&nested[16..22], b"0002XY");
+
+        let out = iso_msg.to_vec();
+        let tail = format!("{:06}", nested.len());
+        assert!(out.windows(6).any(|w| w == tail.as_bytes()));
+        assert_eq!(IsoMsg::check_payload(&handle, &out), Ok(()));
+        let parsed = IsoMsg::new(&handle, &out);
+        let mut buffer = vec![0u8; 2048];
+        assert_eq!(parsed.get_field_path("127.22", &mut buffer), Ok(1500));
+        assert_eq!(parsed.get_field_path("2", &mut buffer), Ok(16));
+    }
+
     extern crate test;
     use self::test::Bencher;
 
@@ -831,6 +1511,54 @@ IsoField::new("Message Authentication Code Field",FieldCharType::Iso8583_b  ,  8
         });
 
     }
+
+    /// DE 32 and DE 100 modified by a switch, message rebuilt field by field
+    #[bench]
+    fn bench_iso_msg_modify_to_bytearray(b: &mut Bencher) {
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let handle = AuthSpecs::new();
+        let mut iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+        iso_msg.set_field(32, b"12345").unwrap();
+        iso_msg.set_field(100, b"654321").unwrap();
+        let mut buffer = [0u8; 1024];
+        b.iter(|| iso_msg.to_byte_array(&mut buffer));
+    }
+
+    /// DE 32 and DE 100 modified by a switch, unchanged ranges copied in bulk
+    #[bench]
+    fn bench_iso_msg_modify_to_bytearray_patched(b: &mut Bencher) {
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let handle = AuthSpecs::new();
+        let mut iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+        iso_msg.set_field(32, b"12345").unwrap();
+        iso_msg.set_field(100, b"654321").unwrap();
+        let mut buffer = [0u8; 1024];
+        b.iter(|| iso_msg.to_byte_array_patched(&mut buffer));
+    }
+
+    /// MTI and DE 3 for routing, parsing the whole message
+    #[bench]
+    fn bench_iso_msg_from_bytearray_routing(b: &mut Bencher) {
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let handle = AuthSpecs::new();
+        let mut mti = [0u8; 4];
+        let mut processing_code = [0u8; 6];
+        b.iter(|| {
+            let iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+            (iso_msg.get_field(0, &mut mti).unwrap(), iso_msg.get_field(3, &mut processing_code).unwrap())
+        });
+    }
+
+    /// MTI and DE 3 for routing, indexing only up to DE 3
+    #[bench]
+    fn bench_iso_msg_lazy_from_bytearray_routing(b: &mut Bencher) {
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let handle = AuthSpecs::new();
+        b.iter(|| {
+            let mut lazy = LazyIsoMsg::new(&handle, payload.as_bytes());
+            (lazy.get_field(0).unwrap(), lazy.get_field(3).unwrap())
+        });
+    }
     #[bench]
     fn bench_iso_msg_to_bytearray(b: &mut Bencher) {
         let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
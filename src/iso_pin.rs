@@ -0,0 +1,388 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! ISO 9564-1 PIN blocks and DE 52.
+//! Formats 0, 1 and 3 are 8 byte blocks encrypted with DES/3DES, format 4 is a
+//! 16 byte block encrypted with AES.
+//! DE 52 holds the encrypted block as is if its content type is `b`, otherwise as upper case
+//! hex, so an `ans 16` field carries formats 0, 1 and 3 and format 4 needs 32 characters.
+
+use getrandom;
+use hex;
+
+use iso_crypto::{xor, AesKey, DesKey};
+use iso_field::{FieldCharType, FieldSizeType, IsoField};
+use iso_msg::IsoMsg;
+
+pub const DE_PAN: usize = 2;
+pub const DE_PIN_BLOCK: usize = 52;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PinBlockFormat {
+    /// PIN xor PAN, padded with `F`
+    Format0,
+    /// PIN padded with random digits, no PAN
+    Format1,
+    /// PIN xor PAN, padded with random `A`-`F`
+    Format3,
+    /// AES, PIN and PAN fields enciphered in two passes
+    Format4,
+}
+
+impl PinBlockFormat {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<PinBlockFormat> {
+        match s {
+            "0" => Some(PinBlockFormat::Format0),
+            "1" => Some(PinBlockFormat::Format1),
+            "3" => Some(PinBlockFormat::Format3),
+            "4" => Some(PinBlockFormat::Format4),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PinBlockFormat::Format0 => "0",
+            PinBlockFormat::Format1 => "1",
+            PinBlockFormat::Format3 => "3",
+            PinBlockFormat::Format4 => "4",
+        }
+    }
+
+    /// Block length in bytes
+    pub fn block_size(&self) -> usize {
+        match *self {
+            PinBlockFormat::Format4 => 16,
+            _ => 8,
+        }
+    }
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, &'static str> {
+    let mut buf = vec![0u8; len];
+    getrandom::getrandom(&mut buf).map_err(|_| "Random number generator failed")?;
+    Ok(buf)
+}
+
+/// `len` characters from `base` to `base + range - 1`, rejecting bytes that would bias the result
+fn random_chars(len: usize, base: u8, range: u8) -> Result<String, &'static str> {
+    let limit = 256 - 256 % range as usize;
+    let mut chars = String::with_capacity(len);
+    while chars.len() < len {
+        for b in random_bytes(len)? {
+            if (b as usize) < limit && chars.len() < len {
+                chars.push((base + b % range) as char);
+            }
+        }
+    }
+    Ok(chars)
+}
+
+fn is_digits(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn check_pin(pin: &str) -> Result<(), &'static str> {
+    if pin.len() < 4 || pin.len() > 12 || !is_digits(pin) {
+        return Err("PIN must be 4 to 12 digits");
+    }
+    Ok(())
+}
+
+/// Account field of formats 0 and 3: the 12 rightmost PAN digits excluding the check digit
+fn pan_field(pan: &str) -> Result<Vec<u8>, &'static str> {
+    if pan.len() < 2 || !is_digits(pan) {
+        return Err("Invalid PAN");
+    }
+    let digits = &pan[..pan.len() - 1];
+    let digits = if digits.len() > 12 { &digits[digits.len() - 12..] } else { digits };
+    Ok(hex::decode(format!("0000{:0>12}", digits)).unwrap())
+}
+
+/// Account field of format 4: PAN length - 12, then the PAN padded to 12 digits with `0`
+fn pan_field4(pan: &str) -> Result<Vec<u8>, &'static str> {
+    if pan.is_empty() || pan.len() > 19 || !is_digits(pan) {
+        return Err("Invalid PAN");
+    }
+    let m = if pan.len() > 12 { pan.len() - 12 } else { 0 };
+    Ok(hex::decode(format!("{:0<32}", format!("{}{:0<12}", m, pan))).unwrap())
+}
+
+/// Clear PIN block, formats 1, 3 and 4 use random padding
+pub fn build_pin_block(format: PinBlockFormat, pin: &str, pan: &str) -> Result<Vec<u8>, &'static str> {
+    check_pin(pin)?;
+    let head = format!("{}{:X}{}", format.as_str(), pin.len(), pin);
+    match format {
+        PinBlockFormat::Format0 => {
+            let mut block = hex::decode(format!("{:F<16}", head)).unwrap();
+            xor(&mut block, &pan_field(pan)?);
+            Ok(block)
+        }
+        PinBlockFormat::Format1 => {
+            let digits = random_chars(16 - head.len(), b'0', 10)?;
+            Ok(hex::decode(head + &digits).unwrap())
+        }
+        PinBlockFormat::Format3 => {
+            let letters = random_chars(16 - head.len(), b'A', 6)?;
+            let mut block = hex::decode(head + &letters).unwrap();
+            xor(&mut block, &pan_field(pan)?);
+            Ok(block)
+        }
+        PinBlockFormat::Format4 => {
+            let mut block = hex::decode(format!("{:A<16}", head)).unwrap();
+            block.extend_from_slice(&random_bytes(8)?);
+            Ok(block)
+        }
+    }
+}
+
+/// PIN from a clear PIN block
+pub fn parse_pin_block(format: PinBlockFormat, block: &[u8], pan: &str) -> Result<String, &'static str> {
+    if block.len() != format.block_size() {
+        return Err("Invalid PIN block length");
+    }
+    let mut block = block.to_vec();
+    match format {
+        PinBlockFormat::Format0 | PinBlockFormat::Format3 => xor(&mut block, &pan_field(pan)?),
+        _ => {}
+    }
+    let field = hex::encode_upper(&block[..8]);
+    if &field[..1] != format.as_str() {
+        return Err("Invalid PIN block format");
+    }
+    let len = usize::from_str_radix(&field[1..2], 16).unwrap();
+    if !(4..=12).contains(&len) || !is_digits(&field[2..2 + len]) {
+        return Err("Invalid PIN block");
+    }
+    let valid_fill = match format {
+        PinBlockFormat::Format0 => field[2 + len..].bytes().all(|b| b == b'F'),
+        PinBlockFormat::Format3 => field[2 + len..].bytes().all(|b| b >= b'A'),
+        PinBlockFormat::Format4 => field[2 + len..].bytes().all(|b| b == b'A'),
+        PinBlockFormat::Format1 => true,
+    };
+    if !valid_fill {
+        return Err("Invalid PIN block");
+    }
+    Ok(field[2..2 + len].to_string())
+}
+
+/// PIN block encrypted under `key`, a DES/3DES key for formats 0, 1 and 3 and an AES key for format 4
+pub fn encrypt_pin_block(format: PinBlockFormat, key: &[u8], pin: &str, pan: &str) -> Result<Vec<u8>, &'static str> {
+    let mut block = build_pin_block(format, pin, pan)?;
+    if format == PinBlockFormat::Format4 {
+        let key = AesKey::new(key)?;
+        key.encrypt_block(&mut block);
+        xor(&mut block, &pan_field4(pan)?);
+        key.encrypt_block(&mut block);
+    } else {
+        DesKey::new(key)?.encrypt_block(&mut block);
+    }
+    Ok(block)
+}
+
+/// PIN from a PIN block encrypted under `key`
+pub fn decrypt_pin_block(format: PinBlockFormat, key: &[u8], block: &[u8], pan: &str) -> Result<String, &'static str> {
+    if block.len() != format.block_size() {
+        return Err("Invalid PIN block length");
+    }
+    let mut block = block.to_vec();
+    if format == PinBlockFormat::Format4 {
+        let key = AesKey::new(key)?;
+        key.decrypt_block(&mut block);
+        xor(&mut block, &pan_field4(pan)?);
+        key.decrypt_block(&mut block);
+    } else {
+        DesKey::new(key)?.decrypt_block(&mut block);
+    }
+    parse_pin_block(format, &block, pan)
+}
+
+/// Re-encrypt a PIN block under another key and format
+pub fn translate_pin_block(
+    from_format: PinBlockFormat,
+    from_key: &[u8],
+    to_format: PinBlockFormat,
+    to_key: &[u8],
+    block: &[u8],
+    pan: &str,
+) -> Result<Vec<u8>, &'static str> {
+    let pin = decrypt_pin_block(from_format, from_key, block, pan)?;
+    encrypt_pin_block(to_format, to_key, &pin, pan)
+}
+
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    fn get_pan(&self) -> Result<String, &'static str> {
+        if !self.has_field(DE_PAN) {
+            return Ok(String::new());
+        }
+        String::from_utf8(self.get_field_value(DE_PAN)?).map_err(|_| "Invalid PAN")
+    }
+
+    fn pin_field(&self) -> Result<&IsoField, &'static str> {
+        self.get_spec().get_handle().get(DE_PIN_BLOCK).ok_or("Spec has no PIN field")
+    }
+
+    /// Encrypted PIN block in DE 52, hex decoded unless the field is binary
+    pub fn get_pin_block(&self, format: PinBlockFormat) -> Result<Vec<u8>, &'static str> {
+        let binary = self.pin_field()?.char_type == FieldCharType::Iso8583_b;
+        let value = self.get_field_value(DE_PIN_BLOCK)?;
+        let block = if binary { value } else { hex::decode(&value).map_err(|_| "Invalid PIN block")? };
+        if block.len() != format.block_size() {
+            return Err("Invalid PIN block length");
+        }
+        Ok(block)
+    }
+
+    /// Set an encrypted PIN block in DE 52, hex encoded unless the field is binary
+    pub fn set_pin_block(&mut self, block: &[u8]) -> Result<(), &'static str> {
+        let field = self.pin_field()?;
+        let value = if field.char_type == FieldCharType::Iso8583_b {
+            block.to_vec()
+        } else {
+            hex::encode_upper(block).into_bytes()
+        };
+        let fits = match field.size_type {
+            FieldSizeType::Fixed => value.len() == field.length,
+            _ => value.len() <= field.length,
+        };
+        if !fits {
+            return Err("PIN block does not fit DE 52");
+        }
+        self.set_field(DE_PIN_BLOCK, &value)
+    }
+
+    /// Encrypt `pin` with the PAN in DE 2 and set it in DE 52
+    pub fn set_pin(&mut self, format: PinBlockFormat, key: &[u8], pin: &str) -> Result<(), &'static str> {
+        let block = encrypt_pin_block(format, key, pin, &self.get_pan()?)?;
+        self.set_pin_block(&block)
+    }
+
+    /// Decrypt the PIN in DE 52 with the PAN in DE 2
+    pub fn get_pin(&self, format: PinBlockFormat, key: &[u8]) -> Result<String, &'static str> {
+        decrypt_pin_block(format, key, &self.get_pin_block(format)?, &self.get_pan()?)
+    }
+
+    /// Translate the PIN block in DE 52 e.g. from the acquirer to the issuer key
+    pub fn translate_pin(
+        &mut self,
+        from_format: PinBlockFormat,
+        from_key: &[u8],
+        to_format: PinBlockFormat,
+        to_key: &[u8],
+    ) -> Result<(), &'static str> {
+        let pan = self.get_pan()?;
+        let block = translate_pin_block(from_format, from_key, to_format, to_key, &self.get_pin_block(from_format)?, &pan)?;
+        self.set_pin_block(&block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_specs::YamlSpec;
+
+    const PAN: &str = "4111111111111111";
+    const TDES_KEY: &str = "0123456789ABCDEFFEDCBA9876543210";
+    const AES_KEY: &str = "000102030405060708090a0b0c0d0e0f";
+
+    #[test]
+    fn clear_pin_block_test() {
+        let block = build_pin_block(PinBlockFormat::Format0, "1234", PAN).unwrap();
+        assert_eq!(hex::encode_upper(&block), "041225EEEEEEEEEE");
+        assert_eq!(parse_pin_block(PinBlockFormat::Format0, &block, PAN), Ok("1234".to_string()));
+        let block = hex::decode("341225BADCFEBADC").unwrap();
+        assert_eq!(parse_pin_block(PinBlockFormat::Format3, &block, PAN), Ok("1234".to_string()));
+        assert!(parse_pin_block(PinBlockFormat::Format0, &block, PAN).is_err());
+
+        for format in &[PinBlockFormat::Format1, PinBlockFormat::Format3, PinBlockFormat::Format4] {
+            let block = build_pin_block(*format, "123456789012", PAN).unwrap();
+            assert_eq!(block.len(), format.block_size());
+            assert_eq!(parse_pin_block(*format, &block, PAN), Ok("123456789012".to_string()));
+        }
+        assert!(build_pin_block(PinBlockFormat::Format0, "123", PAN).is_err());
+        assert!(build_pin_block(PinBlockFormat::Format0, "12A4", PAN).is_err());
+    }
+
+    #[test]
+    fn encrypted_pin_block_test() {
+        let tdes = hex::decode(TDES_KEY).unwrap();
+        let aes = hex::decode(AES_KEY).unwrap();
+        let block = encrypt_pin_block(PinBlockFormat::Format0, &tdes, "1234", PAN).unwrap();
+        assert_eq!(hex::encode_upper(&block), "2A3D408A1977DDE9");
+        let block = hex::decode("96ADA6201DA72E29").unwrap();
+        assert_eq!(decrypt_pin_block(PinBlockFormat::Format3, &tdes, &block, PAN), Ok("1234".to_string()));
+
+        let block = hex::decode("70487881E82D3F1EF3A87678147EDAA7").unwrap();
+        assert_eq!(decrypt_pin_block(PinBlockFormat::Format4, &aes, &block, PAN), Ok("1234".to_string()));
+        let block = hex::decode("E092E73914B32BB8C40F506D77189570").unwrap();
+        assert_eq!(decrypt_pin_block(PinBlockFormat::Format4, &aes, &block, "123456789"), Ok("1234".to_string()));
+        assert!(decrypt_pin_block(PinBlockFormat::Format4, &aes, &block, PAN).is_err());
+
+        let block = encrypt_pin_block(PinBlockFormat::Format0, &tdes, "9876", PAN).unwrap();
+        let block = translate_pin_block(PinBlockFormat::Format0, &tdes, PinBlockFormat::Format4, &aes, &block, PAN)
+            .unwrap();
+        assert_eq!(decrypt_pin_block(PinBlockFormat::Format4, &aes, &block, PAN), Ok("9876".to_string()));
+    }
+
+    /// spec1993 with a DE 52 long enough for a hex encoded format 4 block
+    fn spec_pin32() -> YamlSpec {
+        let s = String::from(include_str!("../spec1993.yml")).replace(
+            "Length: \"16\"\n  Label: Personal Id Number (PIN) Data\n  LengthType: fixed",
+            "Length: \"32\"\n  Label: Personal Id Number (PIN) Data\n  LengthType: llvar",
+        );
+        YamlSpec::new(&s).unwrap()
+    }
+
+    #[test]
+    fn random_fill_test() {
+        for _ in 0..20 {
+            let block = build_pin_block(PinBlockFormat::Format1, "1234", PAN).unwrap();
+            assert!(is_digits(&hex::encode(&block)));
+            let block = build_pin_block(PinBlockFormat::Format3, "1234", "0000000000000").unwrap();
+            assert!(hex::encode_upper(&block)[6..].bytes().all(|b| (b'A'..=b'F').contains(&b)));
+        }
+    }
+
+    #[test]
+    fn msg_pin_test() {
+        let spec = spec_pin32();
+        let tdes = hex::decode(TDES_KEY).unwrap();
+        let aes = hex::decode(AES_KEY).unwrap();
+        let mut msg = IsoMsg::empty(&spec);
+        msg.set_field(0, b"0200").unwrap();
+        msg.set_field(DE_PAN, PAN.as_bytes()).unwrap();
+
+        msg.set_pin(PinBlockFormat::Format0, &tdes, "1234").unwrap();
+        assert_eq!(msg.get_field_value(DE_PIN_BLOCK), Ok(b"2A3D408A1977DDE9".to_vec()));
+        assert_eq!(msg.get_pin(PinBlockFormat::Format0, &tdes), Ok("1234".to_string()));
+
+        msg.translate_pin(PinBlockFormat::Format0, &tdes, PinBlockFormat::Format4, &aes).unwrap();
+        assert_eq!(msg.get_field_value(DE_PIN_BLOCK).unwrap().len(), 32);
+        assert_eq!(msg.get_pin(PinBlockFormat::Format4, &aes), Ok("1234".to_string()));
+
+        // the fixed ans 16 DE 52 of spec1993 has no room for a format 4 block
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut msg = IsoMsg::empty(&spec);
+        msg.set_field(DE_PAN, PAN.as_bytes()).unwrap();
+        assert_eq!(msg.set_pin(PinBlockFormat::Format4, &aes, "1234"), Err("PIN block does not fit DE 52"));
+
+        // a binary DE 52 holds the block as is
+        let s = String::from(include_str!("../spec1993.yml")).replace(
+            "ContentType: ans\n  Length: \"16\"\n  Label: Personal Id Number (PIN) Data",
+            "ContentType: b\n  Length: \"8\"\n  Label: Personal Id Number (PIN) Data",
+        );
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut msg = IsoMsg::empty(&spec);
+        msg.set_field(DE_PAN, PAN.as_bytes()).unwrap();
+        msg.set_pin(PinBlockFormat::Format0, &tdes, "1234").unwrap();
+        assert_eq!(msg.get_field_value(DE_PIN_BLOCK), Ok(hex::decode("2A3D408A1977DDE9").unwrap()));
+        assert_eq!(msg.get_pin(PinBlockFormat::Format0, &tdes), Ok("1234".to_string()));
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reversals (`0400`/`0420`) of timed out requests and their repeats (`0401`/`0421`).
+//!
+//! Outstanding reversals are kept in a `ReversalStore` as encoded messages until
+//! the host acknowledges them, `RetryPolicy` decides when to give up.
+
+use std::collections::BTreeMap;
+use std::str;
+use std::time::Duration;
+use iso_msg::IsoMsg;
+
+const DE_MTI: usize = 0;
+const DE_TRANSMISSION_DATE_TIME: usize = 7;
+const DE_STAN: usize = 11;
+const DE_LOCAL_DATE_TIME: usize = 12;
+const DE_ACQUIRER_ID: usize = 32;
+const DE_FORWARDER_ID: usize = 33;
+const DE_TERMINAL_ID: usize = 41;
+
+/// Fields not copied from the original by `build_reversal`: track data, PIN block and MACs
+pub const DEFAULT_EXCLUDED_FIELDS: [usize; 5] = [35, 45, 52, 64, 128];
+
+/// Field carrying the original data elements
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OriginalDataFormat {
+    /// DE 90 n 42: MTI, STAN, DE 7, acquirer and forwarder ids zero padded to 11
+    De90,
+    /// DE 56 (1993) LLVAR: MTI, STAN, DE 12, acquirer id with a 2 digit length
+    De56,
+}
+
+impl OriginalDataFormat {
+    pub fn get_field_index(&self) -> usize {
+        match *self {
+            OriginalDataFormat::De90 => 90,
+            OriginalDataFormat::De56 => 56,
+        }
+    }
+}
+
+fn check_mti(mti: &[u8]) -> Result<(), &'static str> {
+    if mti.len() != 4 || !mti.iter().all(|b| b.is_ascii_digit()) {
+        return Err("Invalid MTI");
+    }
+    Ok(())
+}
+
+/// `0400` (or `0420` advice) reversing a request with MTI `original_mti`, version digit kept
+pub fn reversal_mti(original_mti: &[u8], advice: bool) -> Result<Vec<u8>, &'static str> {
+    check_mti(original_mti)?;
+    let mut mti = original_mti.to_vec();
+    mti[1] = b'4';
+    mti[2] = if advice { b'2' } else { b'0' };
+    mti[3] = b'0';
+    Ok(mti)
+}
+
+/// Repeat MTI e.g. `0420` -> `0421`
+pub fn repeat_mti(mti: &[u8]) -> Result<Vec<u8>, &'static str> {
+    check_mti(mti)?;
+    let mut res = mti.to_vec();
+    if res[3] == b'0' || res[3] == b'2' {
+        res[3] += 1;
+    }
+    Ok(res)
+}
+
+fn get_optional(msg: &IsoMsg, index: usize) -> Result<Vec<u8>, &'static str> {
+    if index < msg.get_spec().get_handle().len() && msg.has_field(index) {
+        msg.get_field_value(index)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+fn zero_pad(value: &[u8], width: usize) -> Result<Vec<u8>, &'static str> {
+    if value.len() > width {
+        return Err("Original data element exceeds its width");
+    }
+    let mut res = vec![b'0'; width - value.len()];
+    res.extend_from_slice(value);
+    Ok(res)
+}
+
+/// Original data elements value identifying `original`
+pub fn build_original_data(original: &IsoMsg, format: OriginalDataFormat) -> Result<Vec<u8>, &'static str> {
+    let mut res = original.get_field_value(DE_MTI)?;
+    check_mti(&res)?;
+    res.extend(zero_pad(&original.get_field_value(DE_STAN)?, 6)?);
+    match format {
+        OriginalDataFormat::De90 => {
+            res.extend(zero_pad(&get_optional(original, DE_TRANSMISSION_DATE_TIME)?, 10)?);
+            res.extend(zero_pad(&get_optional(original, DE_ACQUIRER_ID)?, 11)?);
+            res.extend(zero_pad(&get_optional(original, DE_FORWARDER_ID)?, 11)?);
+        }
+        OriginalDataFormat::De56 => {
+            res.extend(get_optional(original, DE_LOCAL_DATE_TIME)?);
+            let acquirer = get_optional(original, DE_ACQUIRER_ID)?;
+            if acquirer.len() > 99 {
+                return Err("Original data element exceeds its width");
+            }
+            res.extend_from_slice(format!("{:02}", acquirer.len()).as_bytes());
+            res.extend(acquirer);
+        }
+    }
+    Ok(res)
+}
+
+/// Reversal of `original` with MTI `mti`, copying its fields except `DEFAULT_EXCLUDED_FIELDS`,
+/// and setting the original data elements
+pub fn build_reversal<'b>(
+    original: &IsoMsg<'_, 'b>,
+    mti: &[u8],
+    format: OriginalDataFormat,
+) -> Result<IsoMsg<'static, 'b>, &'static str> {
+    build_reversal_excluding(original, mti, format, &DEFAULT_EXCLUDED_FIELDS)
+}
+
+/// Same as `build_reversal`, copying the fields of `original` except `excluded`
+pub fn build_reversal_excluding<'b>(
+    original: &IsoMsg<'_, 'b>,
+    mti: &[u8],
+    format: OriginalDataFormat,
+    excluded: &[usize],
+) -> Result<IsoMsg<'static, 'b>, &'static str> {
+    check_mti(mti)?;
+    let spec = original.get_spec();
+    let handle = spec.get_handle();
+    let original_data_index = format.get_field_index();
+    if original_data_index >= handle.len() {
+        return Err("Spec has no original data elements field");
+    }
+    let bitmap_field_index = IsoMsg::get_bitmap_field_index(&spec);
+    let mut reversal = IsoMsg::empty(spec);
+    reversal.set_field(DE_MTI, mti)?;
+    for index in 1..handle.len() {
+        if Some(index) == bitmap_field_index || excluded.contains(&index) || !original.has_field(index) {
+            continue;
+        }
+        reversal.set_field(index, &original.get_field_value(index)?)?;
+    }
+    let original_data = build_original_data(original, format)?;
+    if original_data.len() > handle[original_data_index].length {
+        return Err("Original data element exceeds its width");
+    }
+    reversal.set_field(original_data_index, &original_data)?;
+    Ok(reversal)
+}
+
+/// Store key of a reversal or its response: STAN, terminal id and DE 7
+pub fn reversal_key(msg: &IsoMsg) -> Result<String, &'static str> {
+    let mut key = String::from_utf8_lossy(&msg.get_field_value(DE_STAN)?).into_owned();
+    for index in [DE_TERMINAL_ID, DE_TRANSMISSION_DATE_TIME].iter() {
+        key.push(':');
+        key.push_str(&String::from_utf8_lossy(&get_optional(msg, *index)?));
+    }
+    Ok(key)
+}
+
+/// Reversal waiting for acknowledgement, `payload` is the `to_byte_array` output
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingReversal {
+    pub key: String,
+    pub payload: Vec<u8>,
+    /// Number of times sent
+    pub attempts: u32,
+}
+
+impl PendingReversal {
+    pub fn new(reversal: &IsoMsg) -> Result<PendingReversal, &'static str> {
+        let key = reversal_key(reversal)?;
+        let payload = reversal.to_vec();
+        Ok(PendingReversal {
+            key,
+            payload,
+            attempts: 0,
+        })
+    }
+}
+
+/// Outstanding reversals, kept until acknowledged
+pub trait ReversalStore {
+    fn insert(&mut self, reversal: PendingReversal) -> Result<(), String>;
+    fn get(&self, key: &str) -> Option<PendingReversal>;
+    /// Count a send of `key`, returns the updated reversal
+    fn record_attempt(&mut self, key: &str) -> Result<PendingReversal, String>;
+    /// Remove `key` once the host responded
+    fn acknowledge(&mut self, key: &str) -> Option<PendingReversal>;
+    fn outstanding(&self) -> Vec<PendingReversal>;
+}
+
+#[derive(Default)]
+pub struct InMemoryReversalStore {
+    reversals: BTreeMap<String, PendingReversal>,
+}
+
+impl InMemoryReversalStore {
+    pub fn new() -> InMemoryReversalStore {
+        InMemoryReversalStore { reversals: BTreeMap::new() }
+    }
+}
+
+impl ReversalStore for InMemoryReversalStore {
+    fn insert(&mut self, reversal: PendingReversal) -> Result<(), String> {
+        self.reversals.insert(reversal.key.clone(), reversal);
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Option<PendingReversal> {
+        self.reversals.get(key).cloned()
+    }
+
+    fn record_attempt(&mut self, key: &str) -> Result<PendingReversal, String> {
+        match self.reversals.get_mut(key) {
+            Some(r) => {
+                r.attempts += 1;
+                Ok(r.clone())
+            }
+            None => Err(format!("No reversal {}", key)),
+        }
+    }
+
+    fn acknowledge(&mut self, key: &str) -> Option<PendingReversal> {
+        self.reversals.remove(key)
+    }
+
+    fn outstanding(&self) -> Vec<PendingReversal> {
+        self.reversals.values().cloned().collect()
+    }
+}
+
+/// How often an unacknowledged reversal is sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Wait between attempts
+    pub interval: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, interval: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            interval,
+        }
+    }
+
+    pub fn should_retry(&self, reversal: &PendingReversal) -> bool {
+        reversal.attempts < self.max_attempts
+    }
+
+    /// Message to send for the next attempt: the reversal first, then its repeat.
+    /// `None` once `max_attempts` is reached
+    pub fn next_payload(&self, reversal: &PendingReversal) -> Option<Vec<u8>> {
+        if !self.should_retry(reversal) || reversal.payload.len() < 4 {
+            return None;
+        }
+        let mut payload = reversal.payload.clone();
+        if reversal.attempts > 0 {
+            let mti = repeat_mti(&payload[..4]).ok()?;
+            payload[..4].copy_from_slice(&mti);
+        }
+        Some(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use yaml_specs::YamlSpec;
+
+    fn spec() -> YamlSpec {
+        let mut contents = String::new();
+        File::open("spec1993.yml").unwrap().read_to_string(&mut contents).unwrap();
+        YamlSpec::new(&contents).unwrap()
+    }
+
+    #[test]
+    fn reversal_mti_test() {
+        assert_eq!(reversal_mti(b"0100", false), Ok(b"0400".to_vec()));
+        assert_eq!(reversal_mti(b"1200", true), Ok(b"1420".to_vec()));
+        assert_eq!(repeat_mti(b"0420"), Ok(b"0421".to_vec()));
+        assert_eq!(repeat_mti(b"0421"), Ok(b"0421".to_vec()));
+        assert!(reversal_mti(b"01", false).is_err());
+    }
+
+    #[test]
+    fn build_reversal_test() {
+        let spec = spec();
+        let mut original = IsoMsg::empty(&spec);
+        original.set_field(0, b"0200").unwrap();
+        original.set_field(4, b"000000001000").unwrap();
+        original.set_field(7, b"1019123456").unwrap();
+        original.set_field(11, b"000123").unwrap();
+        original.set_field(12, b"123456").unwrap();
+        original.set_field(32, b"12345").unwrap();
+        original.set_field(41, b"TERM0001").unwrap();
+        original.set_field(52, b"0123456789ABCDEF").unwrap();
+
+        let reversal = build_reversal(&original, b"0420", OriginalDataFormat::De90).unwrap();
+        assert_eq!(reversal.get_field_value(0), Ok(b"0420".to_vec()));
+        assert_eq!(reversal.get_field_value(4), Ok(b"000000001000".to_vec()));
+        assert!(!reversal.has_field(52));
+        assert_eq!(
+            reversal.get_field_value(90),
+            Ok(b"020000012310191234560000001234500000000000".to_vec())
+        );
+
+        let reversal = build_reversal(&original, b"0400", OriginalDataFormat::De56).unwrap();
+        assert_eq!(reversal.get_field_value(56), Ok(b"02000001231234560512345".to_vec()));
+        assert_eq!(reversal_key(&reversal), Ok(String::from("000123:TERM0001:1019123456")));
+
+        let reversal = build_reversal_excluding(&original, b"0400", OriginalDataFormat::De90, &[4]).unwrap();
+        assert!(!reversal.has_field(4));
+        assert_eq!(reversal.get_field_value(52), Ok(b"0123456789ABCDEF".to_vec()));
+
+        original.remove_field(11).unwrap();
+        assert!(build_reversal(&original, b"0400", OriginalDataFormat::De90).is_err());
+    }
+
+    #[test]
+    fn reversal_store_test() {
+        let spec = spec();
+        let mut original = IsoMsg::empty(&spec);
+        original.set_field(0, b"0100").unwrap();
+        original.set_field(11, b"000001").unwrap();
+        let reversal = build_reversal(&original, b"0420", OriginalDataFormat::De90).unwrap();
+        let pending = PendingReversal::new(&reversal).unwrap();
+        assert_eq!(&pending.payload[..4], b"0420");
+
+        let mut store = InMemoryReversalStore::new();
+        store.insert(pending.clone()).unwrap();
+        let policy = RetryPolicy::new(3, Duration::from_secs(30));
+        assert_eq!(&policy.next_payload(&pending).unwrap()[..4], b"0420");
+
+        let r = store.record_attempt(&pending.key).unwrap();
+        assert_eq!(r.attempts, 1);
+        let repeat = policy.next_payload(&r).unwrap();
+        assert_eq!(&repeat[..4], b"0421");
+        assert_eq!(&repeat[4..], &pending.payload[4..]);
+
+        store.record_attempt(&pending.key).unwrap();
+        let r = store.record_attempt(&pending.key).unwrap();
+        assert!(policy.next_payload(&r).is_none());
+        assert_eq!(store.outstanding().len(), 1);
+        assert_eq!(store.acknowledge(&pending.key), Some(r));
+        assert!(store.outstanding().is_empty());
+        assert!(store.record_attempt(&pending.key).is_err());
+    }
+}
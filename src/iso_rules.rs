@@ -0,0 +1,271 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::str;
+use serde_yaml;
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use iso_field::FieldCharType;
+use iso_msg::IsoMsg;
+
+/// Presence of a data element in a message
+#[derive(Debug, PartialEq)]
+pub enum FieldPresence {
+    Mandatory,
+    Conditional,
+    Optional,
+    /// Mandatory if the given field is present (`true`) or absent (`false`),
+    /// otherwise conditional
+    MandatoryIf(usize, bool),
+}
+
+impl FieldPresence {
+    /// Parse `M`, `C`, `O`, `M if <de> present` or `M if <de> absent`
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<FieldPresence> {
+        let tokens: Vec<&str> = s.split_whitespace().collect();
+        match tokens.as_slice() {
+            &["M"] | &["m"] => Some(FieldPresence::Mandatory),
+            &["C"] | &["c"] => Some(FieldPresence::Conditional),
+            &["O"] | &["o"] => Some(FieldPresence::Optional),
+            &["M", "if", de, cond] | &["m", "if", de, cond] => {
+                let de = match de.parse() {
+                    Ok(de) => de,
+                    Err(_) => return None,
+                };
+                match cond {
+                    "present" => Some(FieldPresence::MandatoryIf(de, true)),
+                    "absent" => Some(FieldPresence::MandatoryIf(de, false)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Result of `MessageRules::validate`
+#[derive(Debug, Default, PartialEq)]
+pub struct ValidationReport {
+    /// mandatory fields which are not present
+    pub missing: Vec<usize>,
+    /// present fields which are not defined for the MTI
+    pub unexpected: Vec<usize>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+/// Field presence rules per MTI, declared in the `MessageRules` section of the spec e.g.
+///
+/// ```text
+/// MessageRules:
+///   "0100":
+///     2: M
+///     3: M
+///     14: M if 35 absent
+///     35: C
+///     43: O
+/// ```
+/// MTI and bitmap fields are always allowed
+pub struct MessageRules {
+    rules: BTreeMap<String, BTreeMap<usize, FieldPresence>>,
+}
+
+impl MessageRules {
+    pub fn new(yaml_string: &str) -> Result<MessageRules, String> {
+        let rules = MessageRules::from_string(yaml_string)?;
+        Ok(MessageRules { rules })
+    }
+
+    /// Rules from the `MessageRules` section of a spec
+    pub fn from_value(value: Value) -> Result<MessageRules, String> {
+        let mtis: BTreeMap<String, BTreeMap<usize, String>> = match serde_yaml::from_value(value) {
+            Err(e) => {
+                return Err(format!("Invalid MessageRules. Err: {} ", e));
+            }
+            Ok(bt) => bt,
+        };
+        let rules = MessageRules::from_mtis(&mtis)?;
+        Ok(MessageRules { rules })
+    }
+
+    fn from_string(yaml_string: &str) -> Result<BTreeMap<String, BTreeMap<usize, FieldPresence>>, String> {
+        let mtis: BTreeMap<String, BTreeMap<usize, String>> = match serde_yaml::from_str(yaml_string) {
+            Err(e) => {
+                return Err(format!("Failed to parse rules yaml. Err: {} ", e));
+            }
+            Ok(bt) => bt,
+        };
+        MessageRules::from_mtis(&mtis)
+    }
+
+    fn from_mtis(
+        mtis: &BTreeMap<String, BTreeMap<usize, String>>,
+    ) -> Result<BTreeMap<String, BTreeMap<usize, FieldPresence>>, String> {
+        let mut rules = BTreeMap::new();
+        for (mti, fields) in mtis.iter() {
+            let mut mti_rules = BTreeMap::new();
+            for (index, rule) in fields.iter() {
+                match FieldPresence::from_str(rule) {
+                    Some(p) => {
+                        mti_rules.insert(*index, p);
+                    }
+                    None => {
+                        return Err(format!(
+                            "Invalid presence rule {} for MTI {} Index {}",
+                            rule,
+                            mti,
+                            index
+                        ));
+                    }
+                }
+            }
+            rules.insert(mti.clone(), mti_rules);
+        }
+        Ok(rules)
+    }
+
+    pub fn get_rules(&self, mti: &str) -> Option<&BTreeMap<usize, FieldPresence>> {
+        self.rules.get(mti)
+    }
+
+    /// Check `iso_msg` against the rules of its MTI
+    pub fn validate(&self, iso_msg: &IsoMsg) -> Result<ValidationReport, String> {
+        let mti = match iso_msg.get_field_value(0) {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Failed to get MTI. Err: {}", e)),
+        };
+        let mti = match str::from_utf8(&mti) {
+            Ok(s) => s,
+            Err(_) => return Err(String::from("MTI is not valid utf8")),
+        };
+        let rules = match self.rules.get(mti) {
+            Some(r) => r,
+            None => return Err(format!("No presence rules for MTI {}", mti)),
+        };
+
+        let mut report = ValidationReport::default();
+        for (index, presence) in rules.iter() {
+            let required = match *presence {
+                FieldPresence::Mandatory => true,
+                FieldPresence::MandatoryIf(de, present) => iso_msg.has_field(de) == present,
+                _ => false,
+            };
+            if required && !iso_msg.has_field(*index) {
+                report.missing.push(*index);
+            }
+        }
+
+        let handle = iso_msg.get_spec().get_handle();
+        for (index, iso_field) in handle.iter().enumerate().skip(1) {
+            if iso_field.char_type == FieldCharType::Iso8583_bmp ||
+                iso_field.char_type == FieldCharType::Iso8583_bmps
+            {
+                continue;
+            }
+            if iso_msg.has_field(index) && !rules.contains_key(&index) {
+                report.unexpected.push(index);
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    /// Check the message against the presence rules declared in its spec
+    pub fn validate(&self) -> Result<ValidationReport, String> {
+        match self.get_spec().get_rules() {
+            Some(rules) => rules.validate(self),
+            None => Err(String::from("Spec has no MessageRules")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use yaml_specs::YamlSpec;
+
+    const RULES: &str = "
+    \"0100\":
+        2: M
+        3: M
+        4: M
+        7: M
+        11: M
+        14: M if 35 absent
+        18: O
+        19: O
+        22: M
+        25: M
+        32: M
+        35: C
+        37: M
+        42: M
+        43: O
+        44: O
+        49: M
+        53: O
+        59: O
+        60: O
+        62: O
+        63: O
+    ";
+
+    #[test]
+    fn field_presence_test() {
+        assert_eq!(FieldPresence::from_str("M"), Some(FieldPresence::Mandatory));
+        assert_eq!(FieldPresence::from_str("o"), Some(FieldPresence::Optional));
+        assert_eq!(
+            FieldPresence::from_str("M if 35 absent"),
+            Some(FieldPresence::MandatoryIf(35, false))
+        );
+        assert_eq!(FieldPresence::from_str("M if 35"), None);
+        assert_eq!(FieldPresence::from_str("X"), None);
+        assert!(MessageRules::new("\"0100\":\n  2: Q\n").is_err());
+    }
+
+    #[test]
+    fn validate_test() {
+        let mut file = File::open("spec1993.yml").unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        let plain = YamlSpec::new(&contents).unwrap();
+        contents.push_str("\nMessageRules:");
+        contents.push_str(RULES);
+        let handle = YamlSpec::new(&contents).unwrap();
+        let rules = MessageRules::new(RULES).unwrap();
+
+        let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+        let mut iso_msg = IsoMsg::new(&handle, payload.as_bytes());
+
+        let report = iso_msg.validate().unwrap();
+        assert_eq!(report, rules.validate(&iso_msg).unwrap());
+        assert_eq!(report.missing, Vec::<usize>::new());
+        assert_eq!(report.unexpected, vec![126]);
+        assert!(!report.is_valid());
+
+        iso_msg.remove_field(126).unwrap();
+        iso_msg.remove_field(14).unwrap();
+        let report = iso_msg.validate().unwrap();
+        assert_eq!(report.missing, vec![14]);
+
+        iso_msg.set_field(35, b"1234567179299851=2210").unwrap();
+        assert!(iso_msg.validate().unwrap().is_valid());
+
+        iso_msg.set_field(0, b"0200").unwrap();
+        assert!(iso_msg.validate().is_err());
+        assert!(IsoMsg::new(&plain, payload.as_bytes()).validate().is_err());
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Incremental parsing of messages arriving in pieces, e.g. from a non-blocking socket.
+//!
+//! The length of a message follows from its bitmap and field length prefixes, so
+//! `IsoMsg::measure` can tell from a partial buffer how many more bytes are needed
+//! before any field is parsed.
+
+use iso_framing::DEFAULT_MAX_FRAME_LENGTH;
+use iso_lazy::{IndexError, LazyIsoMsg};
+use iso_msg::{IsoMsg, IsoSpecs};
+
+/// Outcome of `IsoMsg::measure`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseStatus {
+    /// At least this many more bytes are needed
+    Incomplete(usize),
+    /// A message of this many bytes starts the buffer
+    Complete(usize),
+}
+
+impl<'a, 'b> IsoMsg<'a, 'b> {
+    /// Length of the message at the start of `buffer`, or the number of bytes missing.
+    /// Bytes after the message are ignored
+    pub fn measure(iso_spec: &dyn IsoSpecs, buffer: &[u8]) -> Result<ParseStatus, &'static str> {
+        let mut lazy = LazyIsoMsg::new(iso_spec, buffer);
+        match lazy.index_to(iso_spec.get_handle().len()) {
+            Ok(()) => Ok(ParseStatus::Complete(lazy.offset())),
+            Err(IndexError::Truncated(len)) => Ok(ParseStatus::Incomplete(len - buffer.len())),
+            Err(IndexError::Invalid(e)) => Err(e),
+        }
+    }
+}
+
+/// Outcome of `IsoStreamParser::next_message`
+pub enum Parsed<'b> {
+    /// At least this many more bytes are needed
+    Incomplete(usize),
+    Message(IsoMsg<'static, 'b>),
+}
+
+/// Buffers bytes as they arrive and splits them into messages
+pub struct IsoStreamParser<'b> {
+    iso_spec: &'b dyn IsoSpecs,
+    buffer: Vec<u8>,
+    max_length: usize,
+}
+
+impl<'b> IsoStreamParser<'b> {
+    /// Messages and buffered bytes are limited to `DEFAULT_MAX_FRAME_LENGTH`
+    pub fn new(iso_spec: &'b dyn IsoSpecs) -> IsoStreamParser<'b> {
+        IsoStreamParser {
+            iso_spec,
+            buffer: Vec::new(),
+            max_length: DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+
+    pub fn with_max_length(mut self, max_length: usize) -> IsoStreamParser<'b> {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Buffer `data`, rejected if the buffer would exceed the max length.
+    /// Take the complete messages with `next_message` before feeding more
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        if self.buffer.len() + data.len() > self.max_length {
+            return Err("Buffer exceeds max message length");
+        }
+        self.buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Bytes received and not yet returned as a message
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Drop the buffered bytes, e.g. to resynchronize after an error
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Next complete message, removed from the buffer. A message known to be longer
+    /// than the max length is an error.
+    /// On error the buffer is left as is, the stream can't be parsed further
+    pub fn next_message(&mut self) -> Result<Parsed<'b>, &'static str> {
+        match IsoMsg::measure(self.iso_spec, &self.buffer)? {
+            ParseStatus::Incomplete(n) if self.buffer.len() + n > self.max_length => {
+                Err("Message exceeds max message length")
+            }
+            ParseStatus::Incomplete(n) => Ok(Parsed::Incomplete(n)),
+            ParseStatus::Complete(len) if len > self.max_length => Err("Message exceeds max message length"),
+            ParseStatus::Complete(len) => {
+                let rest = self.buffer.split_off(len);
+                let payload = ::std::mem::replace(&mut self.buffer, rest);
+                Ok(Parsed::Message(IsoMsg::new_owned(self.iso_spec, payload)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_specs::YamlSpec;
+
+    fn wire(spec: &YamlSpec, stan: &[u8]) -> Vec<u8> {
+        let mut msg = IsoMsg::empty(spec);
+        msg.set_field(0, b"0100").unwrap();
+        msg.set_field(2, b"4111111111111111").unwrap();
+        msg.set_field(4, b"000000001000").unwrap();
+        msg.set_field(11, stan).unwrap();
+        msg.set_field(48, b"free form data").unwrap();
+        msg.set_field(64, &[0, 1, 2, 0xFF, 4, 5, 6, 7]).unwrap();
+        let mut buffer = vec![0u8; 512];
+        let len = msg.to_byte_array(&mut buffer);
+        buffer.truncate(len);
+        buffer
+    }
+
+    #[test]
+    fn measure_test() {
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let data = wire(&spec, b"000001");
+        assert_eq!(IsoMsg::measure(&spec, &data), Ok(ParseStatus::Complete(data.len())));
+        assert_eq!(IsoMsg::measure(&spec, &[]), Ok(ParseStatus::Incomplete(4)));
+        assert_eq!(IsoMsg::measure(&spec, &data[..4]), Ok(ParseStatus::Incomplete(16)));
+        // DE 2 length prefix, then its 16 digits
+        assert_eq!(IsoMsg::measure(&spec, &data[..36]), Ok(ParseStatus::Incomplete(3)));
+        assert_eq!(IsoMsg::measure(&spec, &data[..39]), Ok(ParseStatus::Incomplete(16)));
+        assert_eq!(IsoMsg::measure(&spec, &data[..data.len() - 1]), Ok(ParseStatus::Incomplete(1)));
+
+        let mut extra = data.clone();
+        extra.extend_from_slice(b"0200");
+        assert_eq!(IsoMsg::measure(&spec, &extra), Ok(ParseStatus::Complete(data.len())));
+        assert_eq!(IsoMsg::measure(&spec, b"0100ZZZZZZZZZZZZZZZZ"), Err("Invalid bitmap"));
+        assert_eq!(IsoMsg::check_payload(&spec, &data[..20]), Err("Payload is truncated"));
+    }
+
+    #[test]
+    fn stream_parser_test() {
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let mut data = wire(&spec, b"000001");
+        data.extend(wire(&spec, b"000002"));
+
+        // feed exactly what is asked for
+        let mut parser = IsoStreamParser::new(&spec);
+        let mut offset = 0;
+        let mut stans = Vec::new();
+        while offset < data.len() {
+            match parser.next_message().unwrap() {
+                Parsed::Incomplete(n) => {
+                    parser.feed(&data[offset..offset + n]).unwrap();
+                    offset += n;
+                }
+                Parsed::Message(msg) => stans.push(msg.get_field_value(11).unwrap()),
+            }
+        }
+        match parser.next_message().unwrap() {
+            Parsed::Message(msg) => {
+                stans.push(msg.get_field_value(11).unwrap());
+                assert_eq!(msg.get_field_value(64), Ok(vec![0, 1, 2, 0xFF, 4, 5, 6, 7]));
+            }
+            Parsed::Incomplete(_) => panic!("expected a message"),
+        }
+        assert_eq!(stans, vec![b"000001".to_vec(), b"000002".to_vec()]);
+        assert!(parser.buffered().is_empty());
+
+        // one byte at a time
+        let mut count = 0;
+        for b in &data {
+            parser.feed(&[*b]).unwrap();
+            if let Parsed::Message(_) = parser.next_message().unwrap() {
+                count += 1;
+            }
+        }
+        assert_eq!(count, 2);
+
+        parser.feed(b"0100ZZZZZZZZZZZZZZZZ").unwrap();
+        assert!(parser.next_message().is_err());
+        parser.clear();
+        assert!(parser.buffered().is_empty());
+    }
+
+    #[test]
+    fn max_length_test() {
+        let s = String::from(include_str!("../spec1993.yml"));
+        let spec = YamlSpec::new(&s).unwrap();
+        let data = wire(&spec, b"000001");
+
+        let mut parser = IsoStreamParser::new(&spec).with_max_length(data.len() - 1);
+        assert_eq!(parser.feed(&data), Err("Buffer exceeds max message length"));
+        assert!(parser.buffered().is_empty());
+        // the missing DE 64 is known to take the message past the max length
+        parser.feed(&data[..data.len() - 8]).unwrap();
+        match parser.next_message() {
+            Err(e) => assert_eq!(e, "Message exceeds max message length"),
+            Ok(_) => panic!("expected an error"),
+        }
+
+        let mut parser = IsoStreamParser::new(&spec).with_max_length(data.len());
+        parser.feed(&data).unwrap();
+        assert!(parser.next_message().is_ok());
+
+        // a length prefix beyond the field length is rejected, not waited for
+        let mut parser = IsoStreamParser::new(&spec);
+        parser.feed(b"01004000000000000000999").unwrap();
+        match parser.next_message() {
+            Err(e) => assert_eq!(e, "Field length exceeds max length"),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::str;
+use iso_field::FieldSizeType;
+use iso_field::IsoField;
+use iso_msg::IsoMsg;
+use iso_msg::IsoSpecs;
+
+/// `Iso8583` Interface
+/// Conversion between a Rust struct and `IsoMsg`.
+/// Usually implemented with `#[derive(Iso8583)]` (requires the `derive` feature)
+pub trait Iso8583: Sized {
+    fn to_iso_msg<'b>(&self, iso_spec: &'b dyn IsoSpecs) -> Result<IsoMsg<'static, 'b>, String>;
+    fn from_iso_msg(iso_msg: &IsoMsg) -> Result<Self, String>;
+}
+
+/// `IsoValue` Interface
+/// A struct member type which can be stored in a single data element
+pub trait IsoValue: Sized {
+    fn to_field(&self, iso_field: &IsoField) -> Vec<u8>;
+    fn from_field(buffer: &[u8]) -> Result<Self, String>;
+}
+
+impl IsoValue for String {
+    fn to_field(&self, _iso_field: &IsoField) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_field(buffer: &[u8]) -> Result<String, String> {
+        match str::from_utf8(buffer) {
+            Ok(s) => Ok(String::from(s)),
+            Err(e) => Err(format!("Field is not valid utf8. Err: {}", e)),
+        }
+    }
+}
+
+impl IsoValue for u64 {
+    /// Fixed length fields are left padded with zeros
+    fn to_field(&self, iso_field: &IsoField) -> Vec<u8> {
+        match iso_field.size_type {
+            FieldSizeType::Fixed => format!("{:0w$}", self, w = iso_field.length).into_bytes(),
+            _ => self.to_string().into_bytes(),
+        }
+    }
+
+    fn from_field(buffer: &[u8]) -> Result<u64, String> {
+        let s = String::from_field(buffer)?;
+        match s.trim().parse() {
+            Ok(n) => Ok(n),
+            Err(_) => Err(format!("Field value {} is not numeric", s)),
+        }
+    }
+}
+
+impl IsoValue for Vec<u8> {
+    fn to_field(&self, _iso_field: &IsoField) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn from_field(buffer: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(buffer.to_vec())
+    }
+}
+
+/// Set data element `index` of `iso_msg` from `value`
+pub fn set_value<T: IsoValue>(iso_msg: &mut IsoMsg, index: usize, value: &T) -> Result<(), String> {
+    let buffer = {
+        let handle = iso_msg.get_spec().get_handle();
+        if index >= handle.len() {
+            return Err(format!("Field {} is not defined in the spec", index));
+        }
+        let buffer = value.to_field(&handle[index]);
+        if buffer.len() > handle[index].length {
+            return Err(format!(
+                "Field {} length {} exceeds max length {}",
+                index,
+                buffer.len(),
+                handle[index].length
+            ));
+        }
+        buffer
+    };
+    match iso_msg.set_field(index, &buffer) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to set field {}. Err: {}", index, e)),
+    }
+}
+
+/// Get data element `index` of `iso_msg`, `None` if the field is not present.
+/// A field that is present but can't be read is an error
+pub fn get_value<T: IsoValue>(iso_msg: &IsoMsg, index: usize) -> Result<Option<T>, String> {
+    if !iso_msg.has_field(index) {
+        return Ok(None);
+    }
+    let buffer = match iso_msg.get_field_value(index) {
+        Ok(buffer) => buffer,
+        Err(e) => return Err(format!("Failed to get field {}. Err: {}", index, e)),
+    };
+    match T::from_field(&buffer) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => Err(format!("Invalid field {}. Err: {}", index, e)),
+    }
+}
+
+/// Error returned by `from_iso_msg` when a mandatory field is absent
+pub fn missing_field(index: usize, name: &str) -> String {
+    format!("Mandatory field {} ({}) is missing", index, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iso_field::FieldCharType;
+
+    #[test]
+    fn u64_value_test() {
+        let fixed = IsoField::new("Amount, Txn", FieldCharType::Iso8583_ns, 12, FieldSizeType::Fixed);
+        assert_eq!(131u64.to_field(&fixed), b"000000000131".to_vec());
+        let var = IsoField::new("Acquirer Inst Id Code", FieldCharType::Iso8583_ns, 11, FieldSizeType::LlVar);
+        assert_eq!(59u64.to_field(&var), b"59".to_vec());
+        assert_eq!(u64::from_field(b"000000000131"), Ok(131));
+        assert!(u64::from_field(b"12A").is_err());
+    }
+}
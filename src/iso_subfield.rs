@@ -0,0 +1,212 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Composite field support.
+//!
+//! Subfields are numbered from 1 and are either fixed positional or length
+//! prefixed, with the same prefix width as a field of their `LengthType`. If the
+//! first subfield is a bitmap (`bmp`/`bmps`, hex encoded), bit `n` of it indicates
+//! subfield `n` is present.
+
+use std::str;
+use iso_field::FieldCharType;
+use iso_field::IsoField;
+
+pub fn is_bitmap_driven(subfields: &[IsoField]) -> bool {
+    !subfields.is_empty() &&
+        (subfields[0].char_type == FieldCharType::Iso8583_bmp ||
+             subfields[0].char_type == FieldCharType::Iso8583_bmps)
+}
+
+/// Split the composite field `value` into its subfields.
+/// Returns `(offset, len)` of each subfield value within `value`, `None` if absent
+pub fn parse_subfields(
+    subfields: &[IsoField],
+    value: &[u8],
+) -> Result<Vec<Option<(usize, usize)>>, &'static str> {
+    let mut res = Vec::with_capacity(subfields.len());
+    let bitmap_driven = is_bitmap_driven(subfields);
+    let mut bitmap: &[u8] = &[];
+    let mut index = 0usize;
+
+    for (i, sub) in subfields.iter().enumerate() {
+        if bitmap_driven && i > 0 && !is_subfield_bit_set(bitmap, i + 1) {
+            res.push(None);
+            continue;
+        }
+        if index == value.len() {
+            if bitmap_driven {
+                return Err("Subfield missing from composite field");
+            }
+            // trailing positional subfields may be omitted
+            res.push(None);
+            continue;
+        }
+
+        let len_prefix = sub.size_type.prefix_width();
+        let len = if len_prefix > 0 {
+            if index + len_prefix > value.len() {
+                return Err("Subfield length prefix is truncated");
+            }
+            let digits = match str::from_utf8(&value[index..index + len_prefix]) {
+                Ok(s) => s,
+                Err(_) => return Err("Invalid subfield length prefix"),
+            };
+            match digits.parse::<usize>() {
+                Ok(l) => l,
+                Err(_) => return Err("Invalid subfield length prefix"),
+            }
+        } else {
+            sub.length
+        };
+
+        let start = index + len_prefix;
+        if start + len > value.len() {
+            return Err("Subfield exceeds composite field length");
+        }
+        if bitmap_driven && i == 0 {
+            bitmap = &value[start..start + len];
+        }
+        res.push(Some((start, len)));
+        index = start + len;
+    }
+
+    if index != value.len() {
+        return Err("Composite field has trailing data");
+    }
+    Ok(res)
+}
+
+/// Build the composite field value from subfield values (without length prefix).
+/// Fixed numeric subfields are left padded with zeros, other fixed subfields
+/// are right padded with spaces. The bitmap subfield is computed.
+pub fn build_subfields(
+    subfields: &[IsoField],
+    values: &[Option<Vec<u8>>],
+) -> Result<Vec<u8>, &'static str> {
+    let bitmap_driven = is_bitmap_driven(subfields);
+    let last = match values.iter().rposition(|v| v.is_some()) {
+        Some(l) => l,
+        None => return Ok(Vec::new()),
+    };
+    if bitmap_driven && last == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut v = Vec::new();
+    for (i, sub) in subfields.iter().enumerate().take(last + 1) {
+        if bitmap_driven && i == 0 {
+            v.extend_from_slice(&build_subfield_bitmap(sub.length, values));
+            continue;
+        }
+        let empty = Vec::new();
+        let value = match values.get(i) {
+            Some(Some(value)) => value,
+            _ if bitmap_driven => continue,
+            _ => &empty,
+        };
+        if value.len() > sub.length {
+            return Err("Subfield value exceeds max length");
+        }
+
+        let len_prefix = sub.size_type.prefix_width();
+        if len_prefix > 0 {
+            v.extend_from_slice(format!("{:0w$}", value.len(), w = len_prefix).as_bytes());
+            v.extend_from_slice(value);
+        } else {
+            let pad = sub.length - value.len();
+            match sub.char_type {
+                FieldCharType::Iso8583_n | FieldCharType::Iso8583_ns => {
+                    v.extend(::std::iter::repeat_n(b'0', pad));
+                    v.extend_from_slice(value);
+                }
+                _ => {
+                    v.extend_from_slice(value);
+                    v.extend(::std::iter::repeat_n(b' ', pad));
+                }
+            }
+        }
+    }
+    Ok(v)
+}
+
+/// Bit `n` (1 based, msb first) of a hex encoded bitmap
+fn is_subfield_bit_set(bitmap: &[u8], n: usize) -> bool {
+    let digit = (n - 1) / 4;
+    if digit >= bitmap.len() {
+        return false;
+    }
+    let nibble = match (bitmap[digit] as char).to_digit(16) {
+        Some(d) => d,
+        None => return false,
+    };
+    nibble & (0x8 >> ((n - 1) % 4)) != 0
+}
+
+fn build_subfield_bitmap(hex_len: usize, values: &[Option<Vec<u8>>]) -> Vec<u8> {
+    let mut nibbles = vec![0u32; hex_len];
+    for (i, value) in values.iter().enumerate().skip(1) {
+        let digit = i / 4;
+        if value.is_some() && digit < hex_len {
+            nibbles[digit] |= 0x8 >> (i % 4);
+        }
+    }
+    nibbles
+        .iter()
+        .map(|n| ::std::char::from_digit(*n, 16).unwrap().to_ascii_uppercase() as u8)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iso_field::FieldSizeType;
+
+    fn name_location() -> Vec<IsoField> {
+        vec![
+            IsoField::new("Name", FieldCharType::Iso8583_ans, 22, FieldSizeType::Fixed),
+            IsoField::new("City", FieldCharType::Iso8583_ans, 13, FieldSizeType::Fixed),
+            IsoField::new("Region", FieldCharType::Iso8583_ans, 2, FieldSizeType::Fixed),
+            IsoField::new("Country", FieldCharType::ISO8583_a, 3, FieldSizeType::Fixed),
+        ]
+    }
+
+    #[test]
+    fn positional_subfields_test() {
+        let subfields = name_location();
+        let value = b"Test Merchant         Richmond1    51USA";
+        let res = parse_subfields(&subfields, value).unwrap();
+        assert_eq!(res, vec![Some((0, 22)), Some((22, 13)), Some((35, 2)), Some((37, 3))]);
+
+        let values = vec![
+            Some(b"Test Merchant".to_vec()),
+            Some(b"Richmond1".to_vec()),
+            Some(b"51".to_vec()),
+            Some(b"USA".to_vec()),
+        ];
+        assert_eq!(build_subfields(&subfields, &values).unwrap(), value.to_vec());
+        assert!(parse_subfields(&subfields, b"Test Merchant").is_err());
+    }
+
+    #[test]
+    fn bitmap_subfields_test() {
+        let subfields = vec![
+            IsoField::new("Bitmap", FieldCharType::Iso8583_bmps, 4, FieldSizeType::BitMap),
+            IsoField::new("Switch Key", FieldCharType::Iso8583_ans, 32, FieldSizeType::LlVar2),
+            IsoField::new("Routing Info", FieldCharType::Iso8583_ans, 48, FieldSizeType::Fixed),
+            IsoField::new("Batch Number", FieldCharType::Iso8583_ns, 3, FieldSizeType::Fixed),
+            IsoField::new("Extended Data", FieldCharType::Iso8583_ans, 999, FieldSizeType::LllVar),
+        ];
+        let values = vec![None, Some(b"ABC".to_vec()), None, Some(b"7".to_vec()), Some(b"XY".to_vec())];
+        let value = build_subfields(&subfields, &values).unwrap();
+        assert_eq!(str::from_utf8(&value).unwrap(), "580003ABC007002XY");
+
+        let res = parse_subfields(&subfields, &value).unwrap();
+        assert_eq!(res, vec![Some((0, 4)), Some((6, 3)), None, Some((9, 3)), Some((15, 2))]);
+    }
+}
@@ -14,11 +14,50 @@ extern crate yaml_rust;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_yaml;
+extern crate hex;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "derive")]
+extern crate iso8583_derive;
+#[cfg(feature = "codec")]
+extern crate bytes;
+#[cfg(feature = "codec")]
+extern crate tokio_util;
+#[cfg(feature = "crypto")]
+extern crate des;
+#[cfg(feature = "crypto")]
+extern crate aes;
+#[cfg(feature = "crypto")]
+extern crate cmac;
+#[cfg(feature = "crypto")]
+extern crate getrandom;
 
 pub mod iso_msg;
 pub mod iso_field;
 pub mod yaml_specs;
+pub mod iso_struct;
+pub mod iso_rules;
+pub mod iso_subfield;
+pub mod emv_tlv;
+pub mod ascii_tlv;
+pub mod iso_framing;
+pub mod iso_reversal;
+pub mod iso_card;
+pub mod iso_diff;
+pub mod iso_stream;
+pub mod iso_lazy;
+#[cfg(feature = "codec")]
+pub mod iso_codec;
+#[cfg(feature = "crypto")]
+pub mod iso_crypto;
+#[cfg(feature = "crypto")]
+pub mod iso_mac;
+#[cfg(feature = "crypto")]
+pub mod iso_pin;
+#[cfg(feature = "crypto")]
+pub mod iso_dukpt;
+
+#[cfg(feature = "derive")]
+pub use iso8583_derive::Iso8583;
 
 
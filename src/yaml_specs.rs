@@ -8,31 +8,52 @@
 
 use yaml_rust::yaml;
 use serde_yaml;
+use serde_yaml::Value;
 use iso_field::FieldCharType;
 use iso_field::FieldPayload;
 use iso_field::IsoField;
 use iso_field::FieldSizeType;
+use iso_field::AsciiTlvFormat;
+use iso_field::TlvLengthEncoding;
+use iso_field::NestedSpec;
 use std::collections::HashMap;
 use std::collections::BTreeMap;
 use iso_msg::IsoSpecs;
+use iso_rules::MessageRules;
 
 /// Auth spec defines the format of Iso8583 message
 pub struct YamlSpec {
     handle: Vec<IsoField>,
+    rules: Option<MessageRules>,
 }
 ///  It implements the trait defined by IsoSpecs
 impl IsoSpecs for YamlSpec {
     fn get_handle(&self) -> &Vec<IsoField> {
         &self.handle
     }
+
+    fn get_rules(&self) -> Option<&MessageRules> {
+        self.rules.as_ref()
+    }
 }
 
 impl YamlSpec {
+    /// Fields by index, plus optional `MessageRules` per MTI
     pub fn new(yaml_string: &String) -> Result<YamlSpec, String> {
-        let handle = try!(YamlSpec::from_string(yaml_string));
+        let mut doc = YamlSpec::parse(yaml_string)?;
+        let rules = match doc {
+            Value::Mapping(ref mut m) => m.remove(&Value::String(String::from("MessageRules"))),
+            _ => None,
+        };
+        let rules = match rules {
+            Some(r) => Some(MessageRules::from_value(r)?),
+            None => None,
+        };
+        let handle = YamlSpec::from_value(doc)?;
         Ok(
             YamlSpec { 
-                handle: handle 
+                handle,
+                rules,
             }
         )
     }
@@ -56,8 +77,24 @@ impl YamlSpec {
         return serde_yaml::to_string(&btmap).unwrap();
     }
     
+    fn parse(yaml_string: &str) -> Result<Value, String> {
+        match serde_yaml::from_str(yaml_string) {
+            Err(e) => {
+                Err(format!(
+                                "Failed to parse yaml file. Err: {} ",e)
+                )
+            },
+            Ok(doc) => { Ok(doc) }
+        }
+    }
+
+    #[cfg(test)]
     fn from_string(yaml_string: &str) -> Result<Vec<IsoField>, String> {
-        let fields: BTreeMap<usize, HashMap<String,String>> = match serde_yaml::from_str(&yaml_string) {
+        YamlSpec::from_value(YamlSpec::parse(yaml_string)?)
+    }
+
+    fn from_value(doc: Value) -> Result<Vec<IsoField>, String> {
+        let fields: BTreeMap<usize, HashMap<String,Value>> = match serde_yaml::from_value(doc) {
             Err(e) => {
                 return Err(format!(
                                 "Failed to parse yaml file. Err: {} ",e.to_string())
@@ -65,20 +102,76 @@ impl YamlSpec {
             },
             Ok(bt) => { bt}
         };
+        YamlSpec::from_fields(&fields, 0)
+    }
+
+    /// Build the field list from `fields`, keys must be contiguous from `first_index`.
+    /// Subfields are numbered from 1
+    fn from_fields(fields: &BTreeMap<usize, HashMap<String,Value>>, first_index: usize) -> Result<Vec<IsoField>, String> {
         trace!("fields.length(): {}", fields.len());
         let mut handle = Vec::<IsoField>::with_capacity(fields.len());
 
         for (index, val) in fields.iter() {
+            if *index != first_index + handle.len() {
+                return Err(format!("Invalid Index {}, expected {}", index, first_index + handle.len()));
+            }
             let mut char_type = FieldCharType::Iso8583_ans;
             let mut length_type = FieldSizeType::Fixed;
             let mut field_length = 0;
             let mut label = String::from("");
-            for (a, b) in val.iter() {
+            let mut subfields = Vec::<IsoField>::new();
+            let mut nested = None;
+            let mut tlv_tag_width = None;
+            let mut tlv_length_width = None;
+            let mut tlv_length_encoding = TlvLengthEncoding::Decimal;
+            for (a, v) in val.iter() {
+                if a == "SubFields" {
+                    let sub: BTreeMap<usize, HashMap<String,Value>> = match serde_yaml::from_value(v.clone()) {
+                        Err(e) => {
+                            return Err(format!(
+                                "Invalid SubFields for Index {}. Err: {}",
+                                index,
+                                e
+                            ));
+                        },
+                        Ok(bt) => { bt }
+                    };
+                    subfields = YamlSpec::from_fields(&sub, 1)?;
+                    continue;
+                }
+                if a == "NestedFields" {
+                    let sub: BTreeMap<usize, HashMap<String,Value>> = match serde_yaml::from_value(v.clone()) {
+                        Err(e) => {
+                            return Err(format!(
+                                "Invalid NestedFields for Index {}. Err: {}",
+                                index,
+                                e
+                            ));
+                        },
+                        Ok(bt) => { bt }
+                    };
+                    let nested_handle = YamlSpec::from_fields(&sub, 1)?;
+                    if nested_handle.is_empty() ||
+                        (nested_handle[0].char_type != FieldCharType::Iso8583_bmp &&
+                             nested_handle[0].char_type != FieldCharType::Iso8583_bmps)
+                    {
+                        return Err(format!("NestedFields for Index {} must start with a bitmap", index));
+                    }
+                    nested = Some(NestedSpec { handle: nested_handle });
+                    continue;
+                }
+                let b = match *v {
+                    Value::String(ref s) => s.clone(),
+                    Value::Number(ref n) => n.to_string(),
+                    _ => {
+                        return Err(format!("Invalid value for {} for Index {}", a, index));
+                    }
+                };
                 trace!("index:{}, a:{}, b:{}", index, a, b);
                 if a == "Label" {
                      label = b.to_string();
                 }else if a == "ContentType" {
-                    let c = FieldCharType::from_str(b);
+                    let c = FieldCharType::from_str(&b);
                     if c.is_none() {
                         return Err(format!(
                                 "Invalid ContentType {} for Index {}",
@@ -92,7 +185,7 @@ impl YamlSpec {
                       
                 }else if a == "LengthType" || a == "LenType" {
 
-                    let lt = FieldSizeType::from_str(b);
+                    let lt = FieldSizeType::from_str(&b);
                     if lt.is_none() {
                         return Err(format!(
                             "Invalid LengthType {} for Index {}",
@@ -104,6 +197,34 @@ impl YamlSpec {
                         length_type = lt.unwrap();
                     }
                     
+                }else if a == "TlvTagWidth" || a == "TlvLengthWidth" {
+                    let w = b.parse::<usize>();
+                    if w.is_err() {
+                        return Err(format!(
+                            "Invalid {} {} for Index {}",
+                            a,
+                            b,
+                            index
+                        ));
+                    }
+                    if a == "TlvTagWidth" {
+                        tlv_tag_width = w.ok();
+                    } else {
+                        tlv_length_width = w.ok();
+                    }
+
+                }else if a == "TlvLengthEncoding" {
+                    tlv_length_encoding = match TlvLengthEncoding::from_str(&b) {
+                        Some(e) => e,
+                        None => {
+                            return Err(format!(
+                                "Invalid TlvLengthEncoding {} for Index {}",
+                                b,
+                                index
+                            ));
+                        }
+                    };
+
                 }else if a == "Length" || a == "MaxLen" {
                     let l  = b.parse();
                     if l.is_err() {
@@ -119,8 +240,25 @@ impl YamlSpec {
                 }
                
             }
-             let iso_field = IsoField::new(label.as_str(), char_type, field_length as usize, length_type);
-              handle.insert(*index, iso_field);
+             let mut iso_field = IsoField::new_composite(label.as_str(), char_type, field_length as usize, length_type, subfields);
+             iso_field.nested = nested;
+             match (tlv_tag_width, tlv_length_width) {
+                 (Some(tag_width), Some(length_width)) => {
+                     let format = AsciiTlvFormat::new(tag_width, length_width, tlv_length_encoding);
+                     if let Err(e) = format.check() {
+                         return Err(format!("{} for Index {}", e, index));
+                     }
+                     iso_field.tlv_format = Some(format);
+                 },
+                 (None, None) => {},
+                 _ => {
+                     return Err(format!(
+                         "TlvTagWidth and TlvLengthWidth are both required for Index {}",
+                         index
+                     ));
+                 }
+             }
+              handle.push(iso_field);
         }
         Ok(handle)
     }
@@ -192,7 +330,78 @@ mod tests {
         let fields = YamlSpec::from_string(s);
         assert_eq!(fields.is_ok(), true);
         assert_eq!(fields.unwrap().len(),2 );
-       
+
+        }
+
+        #[test]
+        fn test_yml_subfields_spec() {
+            let s =
+            "
+            0:
+                ContentType: n
+                LengthType: fixed
+                Length: 4
+            1:
+                ContentType: ans
+                LengthType: lllvar
+                Length: 999
+                SubFields:
+                    1:
+                        ContentType: an
+                        LengthType: fixed
+                        Length: 2
+                    2:
+                        ContentType: ans
+                        LengthType: llvar2
+                        Length: 20
+            ";
+            let fields = YamlSpec::from_string(s).unwrap();
+            assert_eq!(fields[0].subfields.len(), 0);
+            assert_eq!(fields[1].subfields.len(), 2);
+            assert_eq!(fields[1].subfields[1].size_type, FieldSizeType::LlVar2);
+            assert_eq!(fields[1].subfields[1].length, 20);
+
+            let missing = "
+            0:
+                ContentType: n
+                LengthType: fixed
+                Length: 4
+            2:
+                ContentType: n
+                LengthType: fixed
+                Length: 4
+            ";
+            assert_eq!(YamlSpec::from_string(missing), Err(String::from("Invalid Index 2, expected 1")));
+        }
+
+        #[test]
+        fn test_yml_nested_spec() {
+            let s =
+            "
+            0:
+                ContentType: n
+                LengthType: fixed
+                Length: 4
+            1:
+                ContentType: ans
+                LengthType: lllvar
+                Length: 999
+                NestedFields:
+                    1:
+                        ContentType: bmps
+                        LengthType: bitmap
+                        Length: 16
+                    2:
+                        ContentType: ans
+                        LengthType: llvar
+                        Length: 32
+            ";
+            let fields = YamlSpec::from_string(s).unwrap();
+            assert!(fields[0].nested.is_none());
+            assert_eq!(fields[1].nested.as_ref().unwrap().handle.len(), 2);
+
+            let no_bitmap = s.replace("bmps", "ans");
+            assert!(YamlSpec::from_string(&no_bitmap).is_err());
         }
 
         #[test]
@@ -0,0 +1,94 @@
+// Copyright 2017 Rohit Joshi <rohit.c.joshi@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+#[macro_use]
+extern crate iso8583;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::str;
+use iso8583::iso_msg::IsoMsg;
+use iso8583::iso_struct::Iso8583;
+use iso8583::yaml_specs::YamlSpec;
+
+#[derive(Iso8583, Debug, PartialEq)]
+struct AuthRequest {
+    #[iso(de = 0)]
+    mti: String,
+    #[iso(de = 2)]
+    pan: String,
+    #[iso(de = 3)]
+    processing_code: Option<String>,
+    #[iso(de = 4)]
+    amount: u64,
+    #[iso(de = 14)]
+    expiry: Option<String>,
+    #[iso(de = 52)]
+    pin_block: Option<Vec<u8>>,
+    note: String,
+}
+
+fn load_spec() -> YamlSpec {
+    let mut file = File::open("spec1993.yml").unwrap();
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    YamlSpec::new(&contents).unwrap()
+}
+
+#[test]
+fn derive_from_iso_msg_test() {
+    let spec = load_spec();
+    let payload = "0100F2246481087088360000000000000004016123456717929985100300000000000013112042128251178162210581284001059006419310712815007743555555555555888Test Merchant         Richmond1    51USA011          N8402001010000000000014510002329467890120100  00054002140000000000012312340001080000000020120040001N 989";
+    let iso_msg = IsoMsg::new(&spec, payload.as_bytes());
+    let req = AuthRequest::from_iso_msg(&iso_msg).unwrap();
+    assert_eq!(req.mti, "0100");
+    assert_eq!(req.pan, "1234567179299851");
+    assert_eq!(req.processing_code, Some(String::from("003000")));
+    assert_eq!(req.amount, 131);
+    assert_eq!(req.expiry, Some(String::from("2210")));
+    assert_eq!(req.pin_block, None);
+    assert_eq!(req.note, "");
+}
+
+#[test]
+fn derive_round_trip_test() {
+    let spec = load_spec();
+    let req = AuthRequest {
+        mti: String::from("0100"),
+        pan: String::from("1234567229741725"),
+        processing_code: Some(String::from("003000")),
+        amount: 2500,
+        expiry: None,
+        pin_block: None,
+        note: String::from("ignored"),
+    };
+    let iso_msg = req.to_iso_msg(&spec).unwrap();
+    let mut buffer = [0u8; 1024];
+    let len = iso_msg.to_byte_array(&mut buffer);
+    assert_eq!(
+        str::from_utf8(&buffer[..len]).unwrap(),
+        "0100F00000000000000000000000000000000161234567229741725003000000000002500"
+    );
+
+    let parsed = IsoMsg::new(&spec, &buffer[..len]);
+    let decoded = AuthRequest::from_iso_msg(&parsed).unwrap();
+    assert_eq!(decoded.pan, req.pan);
+    assert_eq!(decoded.processing_code, req.processing_code);
+    assert_eq!(decoded.amount, 2500);
+    assert_eq!(decoded.expiry, None);
+    assert_eq!(decoded.note, "");
+}
+
+#[test]
+fn derive_missing_mandatory_test() {
+    let spec = load_spec();
+    let mut iso_msg = IsoMsg::empty(&spec);
+    iso_msg.set_field(0, b"0100").unwrap();
+    iso_msg.set_field(2, b"1234567229741725").unwrap();
+    let res = AuthRequest::from_iso_msg(&iso_msg);
+    assert_eq!(res, Err(String::from("Mandatory field 4 (amount) is missing")));
+}